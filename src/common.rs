@@ -1,6 +1,6 @@
 use failure::Fail;
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::HashMap;
 
 use crate::peg;
 
@@ -10,29 +10,19 @@ pub enum Expression {
     String(String),
     Floating(f64),
     Variable(String),
-    Object(String), // currently just functions
     ArrayRef {
         name: Box<Expression>,
         index: Box<Expression>,
     },
-    Array {
-        numeric: BTreeMap<usize, Box<Expression>>,
-        strings: BTreeMap<String, Box<Expression>>,
-    },
     Modifier(Box<Expression>),
     True,
     False,
     Call(String, Vec<Expression>),
-    Nothing,
     Null,
     Mysterious,
     Pronoun,
     Not(Box<Expression>),
 
-    // needed by loops
-    Break,
-    Continue,
-
     // binary operators
     Is(Box<Expression>, Box<Expression>),
     Aint(Box<Expression>, Box<Expression>),
@@ -49,6 +39,26 @@ pub enum Expression {
     LessThan(Box<Expression>, Box<Expression>),
 }
 
+impl Expression {
+    /// The variable a condition tests, which the pronoun refers to afterwards
+    pub fn subject(&self) -> Option<&str> {
+        match self {
+            Expression::Variable(name) => Some(name),
+            Expression::Not(inner) => inner.subject(),
+            Expression::Is(first, _)
+            | Expression::Aint(first, _)
+            | Expression::And(first, _)
+            | Expression::Or(first, _)
+            | Expression::Nor(first, _)
+            | Expression::GreaterThanOrEqual(first, _)
+            | Expression::GreaterThan(first, _)
+            | Expression::LessThanOrEqual(first, _)
+            | Expression::LessThan(first, _) => first.subject(),
+            _ => None,
+        }
+    }
+}
+
 impl PartialOrd for Expression {
     fn partial_cmp(&self, other: &Expression) -> Option<Ordering> {
         match self {
@@ -233,6 +243,34 @@ pub enum MaidenError {
     Incomplete { line: usize },
     #[fail(display = "Bad string. Expected length at least 2 and got {}", length)]
     BadString { length: usize, line: usize },
+    #[fail(display = "Bad escape sequence '{}' in string", sequence)]
+    BadEscape { sequence: String, line: usize },
+    #[fail(display = "Expected a {} value, got a {}", expected, got)]
+    WrongType {
+        expected: String,
+        got: String,
+        line: usize,
+    },
+    #[fail(display = "Error in native function '{}': {}", name, description)]
+    Native {
+        name: String,
+        description: String,
+        line: usize,
+    },
+    #[fail(display = "Can't read included file '{}'", file)]
+    MissingInclude { file: String, line: usize },
+    #[fail(display = "Include of '{}' from a file it includes", file)]
+    IncludeCycle { file: String, line: usize },
+    #[fail(
+        display = "Include of '{}' inside a block, rather than at the top level",
+        file
+    )]
+    NestedInclude { file: String, line: usize },
+    #[fail(display = "In {}: {}", file, error)]
+    Included {
+        file: String,
+        error: Box<MaidenError>,
+    },
     #[fail(display = "Expected an expression, got: {}", other)]
     NotAnExpression { other: String, line: usize },
     #[fail(display = "Expected an symbol, got: {}", other)]
@@ -243,6 +281,39 @@ pub enum MaidenError {
     NotABlock { other: String, line: usize },
 }
 
+impl MaidenError {
+    /// Line the error happened on, or 0 if it's not known
+    pub fn line(&self) -> usize {
+        match self {
+            MaidenError::MissingVariable { ref line, .. }
+            | MaidenError::MissingFunction { ref line, .. }
+            | MaidenError::WrongArgCount { ref line, .. }
+            | MaidenError::ParseNumberError { ref line, .. }
+            | MaidenError::NoEndOfIf { ref line }
+            | MaidenError::BadBooleanResolve { ref line, .. }
+            | MaidenError::Unimplemented { ref line, .. }
+            | MaidenError::StackOverflow { ref line, .. }
+            | MaidenError::InstructionLimit { ref line }
+            | MaidenError::UndefinedPronoun { ref line }
+            | MaidenError::Infinity { ref line, .. }
+            | MaidenError::Incomplete { ref line, .. }
+            | MaidenError::NotAnExpression { ref line, .. }
+            | MaidenError::NotASymbol { ref line, .. }
+            | MaidenError::NotACommand { ref line, .. }
+            | MaidenError::NotABlock { ref line, .. }
+            | MaidenError::BadString { ref line, .. }
+            | MaidenError::BadEscape { ref line, .. }
+            | MaidenError::WrongType { ref line, .. }
+            | MaidenError::Native { ref line, .. }
+            | MaidenError::MissingInclude { ref line, .. }
+            | MaidenError::IncludeCycle { ref line, .. }
+            | MaidenError::NestedInclude { ref line, .. } => *line,
+            // Lines in an included file aren't lines of the program as a whole
+            MaidenError::Pest { .. } | MaidenError::Io { .. } | MaidenError::Included { .. } => 0,
+        }
+    }
+}
+
 pub type Result<T> = ::core::result::Result<T, MaidenError>;
 
 impl From<std::io::Error> for MaidenError {
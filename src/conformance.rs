@@ -0,0 +1,164 @@
+use crate::golden::{Outcome, Status};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Tally {
+    pub pass: usize,
+    pub fail: usize,
+    pub skip: usize,
+}
+
+impl Tally {
+    fn add(&mut self, status: &Status) {
+        match status {
+            Status::Pass | Status::Fixed(_) => self.pass += 1,
+            Status::Fail(_) => self.fail += 1,
+            Status::Skip(_) => self.skip += 1,
+        }
+    }
+}
+
+/// How many fixtures pass, fail and are skipped in each area
+pub fn tally(outcomes: &[Outcome]) -> BTreeMap<&str, Tally> {
+    let mut areas: BTreeMap<&str, Tally> = BTreeMap::new();
+    for outcome in outcomes {
+        areas
+            .entry(outcome.area.as_str())
+            .or_default()
+            .add(&outcome.status);
+    }
+    areas
+}
+
+// Failure messages can have whole program outputs in them
+fn first_line(message: &str) -> String {
+    let line = message.lines().next().unwrap_or("");
+    if line.chars().count() > 100 {
+        format!("{}...", line.chars().take(100).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
+
+// Lists the outcomes `why` says something about, if there are any
+fn section(outcomes: &[Outcome], title: &str, why: impl Fn(&Status) -> Option<String>) -> String {
+    let lines: Vec<String> = outcomes
+        .iter()
+        .filter_map(|outcome| {
+            why(&outcome.status).map(|why| format!("  {}: {}\n", outcome.name, why))
+        })
+        .collect();
+    if lines.is_empty() {
+        String::new()
+    } else {
+        format!("\n{}:\n{}", title, lines.concat())
+    }
+}
+
+/// Summary table of `outcomes` by area, followed by why each fixture that
+/// didn't pass failed or was skipped
+pub fn report(outcomes: &[Outcome]) -> String {
+    let areas = tally(outcomes);
+    let width = areas
+        .keys()
+        .map(|area| area.len())
+        .chain(Some("total".len()))
+        .max()
+        .unwrap_or(0);
+    let mut report = format!(
+        "{:width$} {:>5} {:>5} {:>5}\n",
+        "area",
+        "pass",
+        "fail",
+        "skip",
+        width = width
+    );
+    let mut total = Tally::default();
+    for (area, tally) in &areas {
+        report += &format!(
+            "{:width$} {:>5} {:>5} {:>5}\n",
+            area,
+            tally.pass,
+            tally.fail,
+            tally.skip,
+            width = width
+        );
+        total.pass += tally.pass;
+        total.fail += tally.fail;
+        total.skip += tally.skip;
+    }
+    report += &format!(
+        "{:width$} {:>5} {:>5} {:>5}\n",
+        "total",
+        total.pass,
+        total.fail,
+        total.skip,
+        width = width
+    );
+
+    report += &section(outcomes, "Failing", |status| match status {
+        Status::Fail(message) => Some(first_line(message)),
+        _ => None,
+    });
+    report += &section(outcomes, "Skipped", |status| match status {
+        Status::Skip(reason) => Some(reason.clone()),
+        _ => None,
+    });
+    report += &section(
+        outcomes,
+        "Passing, but still skipped",
+        |status| match status {
+            Status::Fixed(reason) => Some(reason.clone()),
+            _ => None,
+        },
+    );
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::report;
+    use crate::golden::{Outcome, Status};
+    use pretty_assertions::assert_eq;
+
+    fn outcome(name: &str, area: &str, status: Status) -> Outcome {
+        Outcome {
+            name: name.to_string(),
+            area: area.to_string(),
+            status,
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let outcomes = vec![
+            outcome("fixtures/arrays/a.rock", "arrays", Status::Pass),
+            outcome(
+                "fixtures/arrays/b.rock",
+                "arrays",
+                Status::Fail("expected output \"1\\n\", got \"\"\nmore".to_string()),
+            ),
+            outcome(
+                "fixtures/equality/c.rock",
+                "equality",
+                Status::Skip("Blocked upstream".to_string()),
+            ),
+            outcome("failures/d.rock", "failures", Status::Pass),
+        ];
+        assert_eq!(
+            report(&outcomes),
+            "area      pass  fail  skip
+arrays       1     1     0
+equality     0     0     1
+failures     1     0     0
+total        2     1     1
+
+Failing:
+  fixtures/arrays/b.rock: expected output \"1\\n\", got \"\"
+
+Skipped:
+  fixtures/equality/c.rock: Blocked upstream
+"
+        );
+    }
+}
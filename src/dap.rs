@@ -0,0 +1,704 @@
+use crate::common::Result;
+use crate::debugger::{can_stop_at, Stepper, StopReason};
+use crate::framing;
+use crate::parser;
+use crate::runner::{self, Hook, Snapshot, VariableType};
+use crate::value;
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::io::{self, BufRead, Cursor, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+// Rockstar programs only ever have the one thread
+const THREAD_ID: i64 = 1;
+// Fixed references for the scopes, with array values numbered after them
+const LOCALS: i64 = 1;
+const GLOBALS: i64 = 2;
+
+/// Reads and writes Debug Adapter Protocol messages
+struct Connection<'a> {
+    input: &'a mut dyn BufRead,
+    output: &'a mut dyn Write,
+    seq: i64,
+}
+
+impl<'a> Connection<'a> {
+    fn read(&mut self) -> Result<Option<Value>> {
+        framing::read(self.input)
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        framing::write(self.output, &message)
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) -> Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": true,
+            "body": body,
+        }))
+    }
+
+    fn fail(&mut self, request: &Value, message: String) -> Result<()> {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": false,
+            "message": message,
+        }))
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<()> {
+        self.send(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }))
+    }
+}
+
+// Program output, collected so it can be passed on as `output` events
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum Next {
+    Wait,
+    Resume,
+    Disconnect,
+}
+
+struct Session<'a> {
+    connection: Connection<'a>,
+    stepper: Stepper,
+    captured: Captured,
+    path: Option<String>,
+    source: String,
+    // What the program gets when it does a Listen, as stdin's used by the protocol
+    input: String,
+    launched: bool,
+    configured: bool,
+    stop_on_entry: bool,
+    // Set once the client disconnects while the program's stopped
+    disconnected: bool,
+    // Children of structured values, indexed by reference - GLOBALS - 1
+    references: Vec<Vec<(String, value::Value)>>,
+}
+
+impl<'a> Session<'a> {
+    fn send_output(&mut self) -> Result<()> {
+        let output = self.captured.0.replace(Vec::new());
+        if output.is_empty() {
+            return Ok(());
+        }
+        self.connection.event(
+            "output",
+            json!({
+                "category": "stdout",
+                "output": String::from_utf8_lossy(&output),
+            }),
+        )
+    }
+
+    fn reference(&mut self, value: &value::Value) -> i64 {
+        match value {
+            value::Value::Array { numeric, strings } => {
+                let children = numeric
+                    .iter()
+                    .map(|(index, value)| (index.to_string(), value.clone()))
+                    .chain(
+                        strings
+                            .iter()
+                            .map(|(key, value)| (format!("{:?}", key), value.clone())),
+                    )
+                    .collect();
+                self.references.push(children);
+                GLOBALS + self.references.len() as i64
+            }
+            _ => 0,
+        }
+    }
+
+    fn variables(&mut self, reference: i64, snapshot: &Snapshot) -> Vec<Value> {
+        let wanted = match reference {
+            LOCALS => VariableType::Local,
+            GLOBALS => VariableType::Global,
+            _ => {
+                let index = (reference - GLOBALS - 1) as usize;
+                let children = self.references.get(index).cloned().unwrap_or_default();
+                return children
+                    .iter()
+                    .map(|(name, value)| {
+                        json!({
+                            "name": name,
+                            "value": value::describe(value),
+                            "variablesReference": self.reference(value),
+                        })
+                    })
+                    .collect();
+            }
+        };
+        let mut names: Vec<&String> = snapshot
+            .variables
+            .iter()
+            .filter(|(_, (kind, _))| *kind == wanted)
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| {
+                let value = &snapshot.variables[name].1;
+                json!({
+                    "name": name,
+                    "value": value::describe(value),
+                    "variablesReference": self.reference(value),
+                })
+            })
+            .collect()
+    }
+
+    fn stack_trace(&self) -> Vec<Value> {
+        let path = self.path.clone().unwrap_or_default();
+        let file_name = Path::new(&path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.stepper
+            .frames()
+            .iter()
+            .enumerate()
+            .map(|(id, frame)| {
+                let name = match frame.function {
+                    Some((name, _)) => name.to_string(),
+                    None => "top level".to_string(),
+                };
+                json!({
+                    "id": id,
+                    "name": name,
+                    "source": {"name": file_name, "path": path},
+                    "line": frame.line,
+                    "column": 1,
+                })
+            })
+            .collect()
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value> {
+        let source = match arguments["source"]["path"].as_str() {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => self.source.clone(),
+        };
+        let lines: Vec<&str> = source.lines().collect();
+        self.stepper.clear_breakpoints();
+        let mut breakpoints = Vec::new();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            let verified = can_stop_at(&lines, line);
+            if verified {
+                self.stepper.add_breakpoint(line);
+            }
+            breakpoints.push(json!({"verified": verified, "line": line}));
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // Deals with one request. `snapshot` is where the program is stopped, if
+    // it's running at all.
+    fn handle(&mut self, request: &Value, snapshot: Option<&Snapshot>) -> Result<Next> {
+        let arguments = &request["arguments"];
+        match request["command"].as_str().unwrap_or("") {
+            "initialize" => {
+                self.connection.respond(
+                    request,
+                    json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsEvaluateForHovers": true,
+                    }),
+                )?;
+                self.connection.event("initialized", json!({}))?;
+            }
+            "launch" => {
+                let path = arguments["program"].as_str().unwrap_or("").to_string();
+                let input = match arguments["input"].as_str() {
+                    Some(input) => std::fs::read_to_string(input)
+                        .map_err(|err| format!("Can't read '{}': {}", input, err)),
+                    None => Ok(String::new()),
+                };
+                let source = std::fs::read_to_string(&path)
+                    .map_err(|err| format!("Can't read '{}': {}", path, err));
+                match source.and_then(|source| input.map(|input| (source, input))) {
+                    Ok((source, input)) => {
+                        self.source = source;
+                        self.input = input;
+                        self.path = Some(path);
+                        self.launched = true;
+                        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+                        self.connection.respond(request, json!({}))?;
+                    }
+                    Err(message) => {
+                        self.connection.fail(request, message)?;
+                    }
+                }
+            }
+            "setBreakpoints" => match self.set_breakpoints(arguments) {
+                Ok(body) => self.connection.respond(request, body)?,
+                Err(err) => self.connection.fail(request, err.to_string())?,
+            },
+            "configurationDone" => {
+                self.configured = true;
+                self.connection.respond(request, json!({}))?;
+            }
+            "threads" => {
+                self.connection.respond(
+                    request,
+                    json!({"threads": [{"id": THREAD_ID, "name": "main"}]}),
+                )?;
+            }
+            "stackTrace" => {
+                let frames = self.stack_trace();
+                self.connection.respond(
+                    request,
+                    json!({"totalFrames": frames.len(), "stackFrames": frames}),
+                )?;
+            }
+            "scopes" => {
+                // Only the innermost function's locals are around to look at
+                let mut scopes = Vec::new();
+                if arguments["frameId"].as_i64() == Some(0) && snapshot.is_some_and(|s| s.depth > 0)
+                {
+                    scopes.push(json!({
+                        "name": "Locals",
+                        "variablesReference": LOCALS,
+                        "expensive": false,
+                    }));
+                }
+                scopes.push(json!({
+                    "name": "Globals",
+                    "variablesReference": GLOBALS,
+                    "expensive": false,
+                }));
+                self.connection
+                    .respond(request, json!({ "scopes": scopes }))?;
+            }
+            "variables" => {
+                let variables = match snapshot {
+                    Some(snapshot) => {
+                        let reference = arguments["variablesReference"].as_i64().unwrap_or(0);
+                        self.variables(reference, snapshot)
+                    }
+                    None => Vec::new(),
+                };
+                self.connection
+                    .respond(request, json!({ "variables": variables }))?;
+            }
+            "evaluate" => {
+                let snapshot = match snapshot {
+                    Some(snapshot) => snapshot,
+                    None => {
+                        let message = "The program isn't stopped".to_string();
+                        self.connection.fail(request, message)?;
+                        return Ok(Next::Wait);
+                    }
+                };
+                let text = arguments["expression"].as_str().unwrap_or("");
+                let value = parser::parse_expression(text)
+                    .and_then(|expression| runner::evaluate(snapshot, &expression));
+                match value {
+                    Ok(value) => {
+                        let reference = self.reference(&value);
+                        self.connection.respond(
+                            request,
+                            json!({"result": value::describe(&value), "variablesReference": reference}),
+                        )?;
+                    }
+                    Err(err) => self.connection.fail(request, err.to_string())?,
+                }
+            }
+            command @ "continue" | command @ "next" | command @ "stepIn" | command @ "stepOut" => {
+                if snapshot.is_none() {
+                    let message = "The program isn't stopped".to_string();
+                    self.connection.fail(request, message)?;
+                    return Ok(Next::Wait);
+                }
+                match command {
+                    "continue" => self.stepper.resume(),
+                    "next" => self.stepper.next(),
+                    "stepIn" => self.stepper.step(),
+                    _ => self.stepper.out(),
+                }
+                self.connection
+                    .respond(request, json!({"allThreadsContinued": true}))?;
+                return Ok(Next::Resume);
+            }
+            "disconnect" => {
+                self.connection.respond(request, json!({}))?;
+                return Ok(Next::Disconnect);
+            }
+            other => {
+                let message = format!("Unsupported request '{}'", other);
+                self.connection.fail(request, message)?;
+            }
+        }
+        Ok(Next::Wait)
+    }
+
+    fn finish(&mut self, result: Result<()>) -> Result<()> {
+        self.send_output()?;
+        let exit_code = match result {
+            Ok(()) => 0,
+            Err(err) => {
+                self.connection.event(
+                    "output",
+                    json!({"category": "stderr", "output": format!("Error: {}\n", err)}),
+                )?;
+                1
+            }
+        };
+        self.connection
+            .event("exited", json!({ "exitCode": exit_code }))?;
+        self.connection.event("terminated", json!({}))
+    }
+}
+
+impl<'a> Hook for Session<'a> {
+    fn command(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.send_output()?;
+        let reason = match self.stepper.reached(snapshot) {
+            Some(StopReason::Breakpoint) => "breakpoint",
+            Some(StopReason::Step) if self.stop_on_entry => "entry",
+            Some(StopReason::Step) => "step",
+            None => return Ok(()),
+        };
+        self.stop_on_entry = false;
+        self.connection.event(
+            "stopped",
+            json!({"reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true}),
+        )?;
+        loop {
+            let request = match self.connection.read()? {
+                Some(request) => request,
+                None => {
+                    // The client's gone, so just let the program finish
+                    self.stepper.clear_breakpoints();
+                    self.stepper.resume();
+                    break;
+                }
+            };
+            match self.handle(&request, Some(snapshot))? {
+                Next::Wait => {}
+                Next::Resume => break,
+                Next::Disconnect => {
+                    // Stops the program, which serve then unwinds from
+                    self.disconnected = true;
+                    return Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "The client disconnected",
+                    )
+                    .into());
+                }
+            }
+        }
+        self.references.clear();
+        Ok(())
+    }
+
+    fn enter_function(&mut self, name: &str, args: &[value::Value]) {
+        self.stepper.enter(name, args);
+    }
+
+    fn exit_function(&mut self, _name: &str) {
+        self.stepper.exit();
+    }
+}
+
+/// Runs a Debug Adapter Protocol session, reading requests from `input` and
+/// writing responses and events to `output`, until the client disconnects
+pub fn serve(input: &mut dyn BufRead, output: &mut dyn Write) -> Result<()> {
+    let mut session = Session {
+        connection: Connection {
+            input,
+            output,
+            seq: 0,
+        },
+        stepper: Stepper::new(false),
+        captured: Captured::default(),
+        path: None,
+        source: String::new(),
+        input: String::new(),
+        launched: false,
+        configured: false,
+        stop_on_entry: false,
+        disconnected: false,
+        references: Vec::new(),
+    };
+    while !(session.launched && session.configured) {
+        let request = match session.connection.read()? {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        if let Next::Disconnect = session.handle(&request, None)? {
+            return Ok(());
+        }
+    }
+    if session.stop_on_entry {
+        session.stepper.step();
+    }
+
+    let result = parser::parse(&session.source).and_then(|mut program| {
+        let mut reader = Cursor::new(std::mem::take(&mut session.input));
+        let mut writer = session.captured.clone();
+        runner::run_with_hook(&mut program, &mut reader, &mut writer, &mut session).map(|_| ())
+    });
+    if session.disconnected {
+        return Ok(());
+    }
+    session.finish(result)?;
+
+    while let Some(request) = session.connection.read()? {
+        if let Next::Disconnect = session.handle(&request, None)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::serve;
+    use pretty_assertions::assert_eq;
+    use serde_json::{json, Value};
+    use std::io::{BufRead, Cursor, Read};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn frame(requests: &[Value]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            let body = request.to_string();
+            input.extend(format!("Content-Length: {}\r\n\r\n{}", body.len(), body).bytes());
+        }
+        input
+    }
+
+    fn unframe(output: Vec<u8>) -> Vec<Value> {
+        let mut messages = Vec::new();
+        let mut reader = Cursor::new(output);
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).unwrap() == 0 {
+                return messages;
+            }
+            let length: usize = header["Content-Length: ".len()..].trim().parse().unwrap();
+            reader.read_line(&mut String::new()).unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            messages.push(serde_json::from_slice(&body).unwrap());
+        }
+    }
+
+    // Short description of each message, so the tests can check what was said in what order
+    fn summary(message: &Value) -> String {
+        match message["type"].as_str().unwrap() {
+            "event" => format!("event {} {}", message["event"], message["body"]),
+            _ => format!(
+                "{} {} {}",
+                message["command"],
+                message["success"],
+                if message["success"] == json!(true) {
+                    message["body"].clone()
+                } else {
+                    message["message"].clone()
+                }
+            ),
+        }
+    }
+
+    // Each test gets its own program file, as they run in parallel
+    static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+    fn session(code: &str, stop_on_entry: bool, requests: Vec<Value>) -> Vec<String> {
+        let name = format!(
+            "maiden-dap-{}-{}.rock",
+            std::process::id(),
+            SESSIONS.fetch_add(1, Ordering::SeqCst)
+        );
+        let path = std::env::temp_dir().join(&name);
+        std::fs::write(&path, code).unwrap();
+        let path = path.to_str().unwrap().to_string();
+        let mut all = vec![
+            json!({"command": "initialize", "arguments": {"adapterID": "maiden"}}),
+            json!({"command": "launch", "arguments": {"program": path, "stopOnEntry": stop_on_entry}}),
+        ];
+        all.extend(requests.into_iter().map(|mut request| {
+            if request["command"] == json!("setBreakpoints") {
+                request["arguments"]["source"] = json!({ "path": path });
+            }
+            request
+        }));
+        let input = frame(&all);
+        let mut output = Vec::new();
+        serve(&mut Cursor::new(input), &mut output).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        unframe(output)
+            .iter()
+            .map(summary)
+            .map(|s| s.replace(&path, "PATH").replace(&name, "NAME"))
+            .collect()
+    }
+
+    const PROGRAM: &str = "Midnight takes your heart
+Put your heart into my soul
+Give back my soul
+
+My world is 5
+Say Midnight taking my world
+";
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let messages = session(
+            PROGRAM,
+            false,
+            vec![
+                json!({"command": "setBreakpoints", "arguments": {"breakpoints": [{"line": 2}, {"line": 4}]}}),
+                json!({"command": "configurationDone"}),
+                json!({"command": "stackTrace", "arguments": {"threadId": 1}}),
+                json!({"command": "scopes", "arguments": {"frameId": 0}}),
+                json!({"command": "variables", "arguments": {"variablesReference": 1}}),
+                json!({"command": "evaluate", "arguments": {"expression": "your heart times 2"}}),
+                json!({"command": "continue", "arguments": {"threadId": 1}}),
+                json!({"command": "disconnect"}),
+            ],
+        );
+        assert_eq!(
+            messages,
+            vec![
+                r#""initialize" true {"supportsConfigurationDoneRequest":true,"supportsEvaluateForHovers":true}"#,
+                r#"event "initialized" {}"#,
+                r#""launch" true {}"#,
+                r#""setBreakpoints" true {"breakpoints":[{"line":2,"verified":true},{"line":4,"verified":false}]}"#,
+                r#""configurationDone" true {}"#,
+                r#"event "stopped" {"allThreadsStopped":true,"reason":"breakpoint","threadId":1}"#,
+                r#""stackTrace" true {"stackFrames":[{"column":1,"id":0,"line":2,"name":"Midnight","source":{"name":"NAME","path":"PATH"}},{"column":1,"id":1,"line":6,"name":"top level","source":{"name":"NAME","path":"PATH"}}],"totalFrames":2}"#,
+                r#""scopes" true {"scopes":[{"expensive":false,"name":"Locals","variablesReference":1},{"expensive":false,"name":"Globals","variablesReference":2}]}"#,
+                r#""variables" true {"variables":[{"name":"your heart","value":"5","variablesReference":0}]}"#,
+                r#""evaluate" true {"result":"10","variablesReference":0}"#,
+                r#""continue" true {"allThreadsContinued":true}"#,
+                r#"event "output" {"category":"stdout","output":"5\n"}"#,
+                r#"event "exited" {"exitCode":0}"#,
+                r#"event "terminated" {}"#,
+                r#""disconnect" true {}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn steps_through_functions() {
+        let where_stopped = json!({"command": "stackTrace", "arguments": {"threadId": 1}});
+        let messages = session(
+            PROGRAM,
+            true,
+            vec![
+                json!({"command": "configurationDone"}),
+                where_stopped.clone(),
+                json!({"command": "next", "arguments": {"threadId": 1}}),
+                where_stopped.clone(),
+                json!({"command": "stepIn", "arguments": {"threadId": 1}}),
+                where_stopped.clone(),
+                json!({"command": "stepIn", "arguments": {"threadId": 1}}),
+                where_stopped.clone(),
+                json!({"command": "stepOut", "arguments": {"threadId": 1}}),
+                json!({"command": "disconnect"}),
+            ],
+        );
+        // Each stop, with the line from the stack trace that follows it
+        let stops: Vec<String> = messages
+            .iter()
+            .filter_map(|message| {
+                if message.starts_with("event \"stopped\"") {
+                    let reason = message.split("\"reason\":").nth(1).unwrap();
+                    Some(reason.split(',').next().unwrap().to_string())
+                } else if message.starts_with("\"stackTrace\"") {
+                    let line = message.split("\"line\":").nth(1).unwrap();
+                    Some(line.split(',').next().unwrap().to_string())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        assert_eq!(
+            stops,
+            vec![
+                "\"entry\"",
+                "1",
+                "\"step\"",
+                "5",
+                "\"step\"",
+                "6",
+                "\"step\"",
+                "2"
+            ]
+        );
+        assert!(messages.contains(&r#"event "exited" {"exitCode":0}"#.to_string()));
+    }
+
+    #[test]
+    fn reports_runtime_errors() {
+        let messages = session(
+            "My world is 5\nSay my world\nSay your heart\n",
+            false,
+            vec![
+                json!({"command": "configurationDone"}),
+                json!({"command": "disconnect"}),
+            ],
+        );
+        assert_eq!(
+            &messages[4..],
+            &[
+                r#"event "output" {"category":"stdout","output":"5\n"}"#,
+                r#"event "output" {"category":"stderr","output":"Error: Missing variable 'your heart'\n"}"#,
+                r#"event "exited" {"exitCode":1}"#,
+                r#"event "terminated" {}"#,
+                r#""disconnect" true {}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn disconnects_while_stopped() {
+        let messages = session(
+            PROGRAM,
+            true,
+            vec![
+                json!({"command": "configurationDone"}),
+                json!({"command": "disconnect"}),
+            ],
+        );
+        // The program's stopped rather than run to the end
+        assert_eq!(
+            &messages[4..],
+            &[
+                r#"event "stopped" {"allThreadsStopped":true,"reason":"entry","threadId":1}"#,
+                r#""disconnect" true {}"#,
+            ]
+        );
+    }
+}
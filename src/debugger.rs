@@ -0,0 +1,429 @@
+use crate::common::Result;
+use crate::parser;
+use crate::runner::{self, Hook, Snapshot, VariableType};
+use crate::value::{describe, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+
+const HELP: &str = "Commands:
+  s, step            run the next command, stepping into function calls
+  n, next            run the next command, stepping over function calls
+  o, out             run until the current function returns
+  c, continue        run until the next breakpoint
+  b, break [line]    set a breakpoint, or list them with no line
+  d, delete <line>   remove a breakpoint
+  p, print [expr]    show an expression, or all variables and the pronoun
+  bt, backtrace      show the call stack
+  q, quit            stop the program
+  h, help            show this message
+An empty line repeats the last command";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Step,
+    // Stop at the next command no deeper than this
+    Next(u32),
+    // Stop at the next command shallower than this
+    Out(u32),
+    Continue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Breakpoint,
+    Step,
+}
+
+struct Frame {
+    name: String,
+    args: Vec<Value>,
+    call_line: usize,
+}
+
+/// A function call (or the top level, with no function) and the line it's on
+pub struct StackFrame<'a> {
+    pub function: Option<(&'a str, &'a [Value])>,
+    pub line: usize,
+}
+
+/// Breakpoints, stepping and the call stack, shared by the debugger frontends
+pub struct Stepper {
+    breakpoints: BTreeSet<usize>,
+    mode: Mode,
+    stack: Vec<Frame>,
+    line: usize,
+    depth: u32,
+}
+
+impl Stepper {
+    pub fn new(stop_at_start: bool) -> Stepper {
+        Stepper {
+            breakpoints: BTreeSet::new(),
+            mode: if stop_at_start {
+                Mode::Step
+            } else {
+                Mode::Continue
+            },
+            stack: Vec::new(),
+            line: 0,
+            depth: 0,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    pub fn remove_breakpoint(&mut self, line: usize) -> bool {
+        self.breakpoints.remove(&line)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &usize> {
+        self.breakpoints.iter()
+    }
+
+    /// Records that the program has got to `snapshot`, and says why it should
+    /// stop there (if it should)
+    pub fn reached(&mut self, snapshot: &Snapshot) -> Option<StopReason> {
+        self.line = snapshot.line;
+        self.depth = snapshot.depth;
+        if self.breakpoints.contains(&snapshot.line) {
+            return Some(StopReason::Breakpoint);
+        }
+        let stop = match self.mode {
+            Mode::Step => true,
+            Mode::Next(depth) => snapshot.depth <= depth,
+            Mode::Out(depth) => snapshot.depth < depth,
+            Mode::Continue => false,
+        };
+        if stop {
+            Some(StopReason::Step)
+        } else {
+            None
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.mode = Mode::Step;
+    }
+
+    pub fn next(&mut self) {
+        self.mode = Mode::Next(self.depth);
+    }
+
+    pub fn out(&mut self) {
+        self.mode = Mode::Out(self.depth);
+    }
+
+    pub fn resume(&mut self) {
+        self.mode = Mode::Continue;
+    }
+
+    /// The call stack, innermost first and ending with the top level
+    pub fn frames(&self) -> Vec<StackFrame<'_>> {
+        let mut frames = Vec::with_capacity(self.stack.len() + 1);
+        let mut line = self.line;
+        for frame in self.stack.iter().rev() {
+            frames.push(StackFrame {
+                function: Some((&frame.name, &frame.args)),
+                line,
+            });
+            line = frame.call_line;
+        }
+        frames.push(StackFrame {
+            function: None,
+            line,
+        });
+        frames
+    }
+
+    pub fn enter(&mut self, name: &str, args: &[Value]) {
+        self.stack.push(Frame {
+            name: name.to_string(),
+            args: args.to_vec(),
+            call_line: self.line,
+        });
+    }
+
+    pub fn exit(&mut self) {
+        if let Some(frame) = self.stack.pop() {
+            self.line = frame.call_line;
+        }
+    }
+}
+
+pub type LineReader<'a> = Box<dyn FnMut() -> io::Result<Option<String>> + 'a>;
+
+/// Interactive `Hook` that stops at breakpoints and steps through a program
+pub struct Debugger<'a> {
+    source: Vec<&'a str>,
+    input: LineReader<'a>,
+    output: &'a mut dyn Write,
+    stepper: Stepper,
+    last_command: String,
+}
+
+/// Whether a breakpoint on `line` could ever be hit
+pub fn can_stop_at(source: &[&str], line: usize) -> bool {
+    source
+        .get(line.wrapping_sub(1))
+        .is_some_and(|text| !text.trim().is_empty())
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(source: &'a str, input: LineReader<'a>, output: &'a mut dyn Write) -> Debugger<'a> {
+        Debugger {
+            source: source.lines().collect(),
+            input,
+            output,
+            stepper: Stepper::new(true),
+            last_command: String::new(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, line: usize) {
+        self.stepper.add_breakpoint(line);
+    }
+
+    /// Runs without stopping until a breakpoint is hit, rather than stopping
+    /// at the first command
+    pub fn continue_to_breakpoint(&mut self) {
+        self.stepper.resume();
+    }
+
+    fn show_line(&mut self, line: usize) -> Result<()> {
+        let text = self.source.get(line.wrapping_sub(1)).unwrap_or(&"");
+        writeln!(self.output, "line {}: {}", line, text.trim())?;
+        Ok(())
+    }
+
+    fn print_all(
+        &mut self,
+        variables: &HashMap<String, (VariableType, Value)>,
+        pronoun: Option<&str>,
+    ) -> Result<()> {
+        let mut names: Vec<&String> = variables.keys().collect();
+        names.sort();
+        for name in names {
+            writeln!(self.output, "{} = {}", name, describe(&variables[name].1))?;
+        }
+        match pronoun {
+            Some(pronoun) => writeln!(self.output, "pronoun = {}", pronoun)?,
+            None => writeln!(self.output, "pronoun is unset")?,
+        }
+        Ok(())
+    }
+
+    fn print(&mut self, text: &str, snapshot: &Snapshot) -> Result<()> {
+        let value = parser::parse_expression(text)
+            .and_then(|expression| runner::evaluate(snapshot, &expression));
+        match value {
+            Ok(value) => writeln!(self.output, "{} = {}", text, describe(&value))?,
+            Err(err) => writeln!(self.output, "Error: {}", err)?,
+        }
+        Ok(())
+    }
+
+    fn backtrace(&mut self) -> Result<()> {
+        let frames = self.stepper.frames();
+        for (level, frame) in frames.iter().enumerate() {
+            match frame.function {
+                Some((name, args)) => {
+                    let args: Vec<String> = args.iter().map(describe).collect();
+                    writeln!(
+                        self.output,
+                        "#{} line {} in {} taking {}",
+                        level,
+                        frame.line,
+                        name,
+                        args.join(", ")
+                    )?
+                }
+                None => writeln!(self.output, "#{} line {} at top level", level, frame.line)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn breakpoint_line(&mut self, argument: &str) -> Result<Option<usize>> {
+        match argument.parse::<usize>() {
+            Ok(line) if !can_stop_at(&self.source, line) => {
+                writeln!(self.output, "Line {} has nothing to stop at", line)?;
+                Ok(None)
+            }
+            Ok(line) => Ok(Some(line)),
+            Err(_) => {
+                writeln!(self.output, "'{}' isn't a line number", argument)?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Reads commands until one of them resumes the program
+    fn prompt(&mut self, snapshot: &Snapshot) -> Result<()> {
+        loop {
+            write!(self.output, "(maiden) ")?;
+            self.output.flush()?;
+            let mut command = match (self.input)()? {
+                Some(command) => command.trim().to_string(),
+                None => {
+                    // Nothing left to read, so just let the program finish
+                    self.stepper.clear_breakpoints();
+                    self.stepper.resume();
+                    return Ok(());
+                }
+            };
+            if command.is_empty() {
+                command = self.last_command.clone();
+            } else {
+                self.last_command = command.clone();
+            }
+            let mut words = command.splitn(2, ' ');
+            let verb = words.next().unwrap_or("");
+            let argument = words.next().unwrap_or("").trim();
+            match verb {
+                "s" | "step" => {
+                    self.stepper.step();
+                    return Ok(());
+                }
+                "n" | "next" => {
+                    self.stepper.next();
+                    return Ok(());
+                }
+                "o" | "out" => {
+                    self.stepper.out();
+                    return Ok(());
+                }
+                "c" | "continue" => {
+                    self.stepper.resume();
+                    return Ok(());
+                }
+                "b" | "break" if argument.is_empty() => {
+                    let lines: Vec<String> =
+                        self.stepper.breakpoints().map(|l| l.to_string()).collect();
+                    writeln!(self.output, "Breakpoints: {}", lines.join(", "))?;
+                }
+                "b" | "break" => {
+                    if let Some(line) = self.breakpoint_line(argument)? {
+                        self.stepper.add_breakpoint(line);
+                    }
+                }
+                "d" | "delete" => {
+                    if let Some(line) = self.breakpoint_line(argument)? {
+                        if !self.stepper.remove_breakpoint(line) {
+                            writeln!(self.output, "No breakpoint on line {}", line)?;
+                        }
+                    }
+                }
+                "p" | "print" if argument.is_empty() => {
+                    self.print_all(snapshot.variables, snapshot.pronoun)?
+                }
+                "p" | "print" => self.print(argument, snapshot)?,
+                "bt" | "backtrace" => self.backtrace()?,
+                "q" | "quit" => {
+                    self.output.flush()?;
+                    std::process::exit(0);
+                }
+                "h" | "help" => writeln!(self.output, "{}", HELP)?,
+                _ => writeln!(self.output, "Unknown command '{}', try 'help'", command)?,
+            }
+        }
+    }
+}
+
+impl<'a> Hook for Debugger<'a> {
+    fn command(&mut self, snapshot: &Snapshot) -> Result<()> {
+        if self.stepper.reached(snapshot).is_none() {
+            return Ok(());
+        }
+        self.show_line(snapshot.line)?;
+        self.prompt(snapshot)
+    }
+
+    fn enter_function(&mut self, name: &str, args: &[Value]) {
+        self.stepper.enter(name, args);
+    }
+
+    fn exit_function(&mut self, _name: &str) {
+        self.stepper.exit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use crate::{parser, runner};
+    use pretty_assertions::assert_eq;
+    use std::io::{self, Cursor};
+
+    fn debug(code: &str, breakpoints: &[usize], commands: &[&str]) -> String {
+        let mut program = parser::parse(code).unwrap();
+        let mut commands = commands.iter().map(|c| c.to_string());
+        let mut transcript = Vec::new();
+        {
+            let mut debugger =
+                Debugger::new(code, Box::new(move || Ok(commands.next())), &mut transcript);
+            for line in breakpoints {
+                debugger.add_breakpoint(*line);
+            }
+            if !breakpoints.is_empty() {
+                debugger.continue_to_breakpoint();
+            }
+            let mut writer = Cursor::new(Vec::new());
+            runner::run_with_hook(&mut program, &mut io::empty(), &mut writer, &mut debugger)
+                .unwrap();
+        }
+        String::from_utf8(transcript).unwrap()
+    }
+
+    const PROGRAM: &str = "Midnight takes your heart
+Put your heart into my soul
+Give back my soul
+
+My world is 5
+Midnight taking my world
+Say it
+";
+
+    #[test]
+    fn steps_into_functions() {
+        assert_eq!(
+            debug(PROGRAM, &[], &["s", "s", "", "bt", "p your heart", "c"]),
+            "line 1: Midnight takes your heart
+(maiden) line 5: My world is 5
+(maiden) line 6: Midnight taking my world
+(maiden) line 2: Put your heart into my soul
+(maiden) #0 line 2 in Midnight taking 5
+#1 line 6 at top level
+(maiden) your heart = 5
+(maiden) "
+        );
+    }
+
+    #[test]
+    fn steps_over_functions() {
+        assert_eq!(
+            debug(PROGRAM, &[6], &["n", "p", "p my world plus 1", "c"]),
+            "line 6: Midnight taking my world
+(maiden) line 7: Say it
+(maiden) my world = 5
+pronoun = My world
+(maiden) my world plus 1 = 6
+(maiden) "
+        );
+    }
+
+    #[test]
+    fn steps_out_of_functions() {
+        assert_eq!(
+            debug(PROGRAM, &[2], &["o"]),
+            "line 2: Put your heart into my soul
+(maiden) line 7: Say it
+(maiden) "
+        );
+    }
+}
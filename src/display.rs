@@ -1,5 +1,52 @@
 #[cfg(any(test, target_arch = "wasm32"))]
-use crate::common::{Command, CommandLine, Program};
+use crate::common::{Block, Command, CommandLine, Program};
+#[cfg(any(test, target_arch = "wasm32"))]
+use crate::parser;
+#[cfg(any(test, target_arch = "wasm32"))]
+use std::fmt;
+
+// Shows a value as its derived Debug would, except that the strings in it
+// are escaped the way Rockstar writes them rather than the way Rust does
+#[cfg(any(test, target_arch = "wasm32"))]
+struct Printed<'a, T>(&'a T);
+
+#[cfg(any(test, target_arch = "wasm32"))]
+impl<T: fmt::Debug> fmt::Debug for Printed<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let debug = format!("{:?}", self.0);
+        let mut chars = debug.chars();
+        while let Some(c) = chars.next() {
+            if c != '"' {
+                write!(f, "{}", c)?;
+                continue;
+            }
+            // Undo Rust's escapes, up to the end of the string
+            let mut value = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some('t') => value.push('\t'),
+                        Some('r') => value.push('\r'),
+                        Some('0') => value.push('\0'),
+                        Some('u') => {
+                            let hex: String =
+                                chars.by_ref().skip(1).take_while(|c| *c != '}').collect();
+                            let code = u32::from_str_radix(&hex, 16).ok();
+                            value.extend(code.and_then(std::char::from_u32));
+                        }
+                        Some(other) => value.push(other),
+                        None => {}
+                    },
+                    c => value.push(c),
+                }
+            }
+            write!(f, "{}", parser::escape(&value))?;
+        }
+        Ok(())
+    }
+}
 
 #[cfg(any(test, target_arch = "wasm32"))]
 fn print_command(
@@ -23,13 +70,13 @@ fn print_command(
         ),
         Command::Until { expression, block } => format!(
             "Until {{ expression: {:?}, block: Block {{\n{}{}}}}}",
-            expression,
+            Printed(expression),
             print_commands(&block.commands, last_line, indent + 1, max_number_length),
             new_indent
         ),
         Command::While { expression, block } => format!(
             "While {{ expression: {:?}, block: Block {{\n{}{}}}}}",
-            expression,
+            Printed(expression),
             print_commands(&block.commands, last_line, indent + 1, max_number_length),
             new_indent
         ),
@@ -37,38 +84,91 @@ fn print_command(
             expression,
             then,
             otherwise,
-        } => format!(
-            "If {{ expression: {:?}, then: Block {{{}}}, otherwise: Block {{{}}}}}",
-            expression,
-            if then.is_some() {
-                String::from("\n")
-                    + &print_commands(
-                        &then.as_ref().unwrap().commands,
-                        last_line,
-                        indent + 1,
-                        max_number_length,
-                    )
-                    + &new_indent
-            } else {
-                String::new()
-            },
-            if otherwise.is_some() {
-                String::from("\n")
-                    + &print_commands(
-                        &otherwise.as_ref().unwrap().commands,
-                        last_line,
-                        indent + 1,
-                        max_number_length,
-                    )
-                    + &new_indent
+        } => {
+            let block = |block: &Option<Block>, last_line: &mut usize| match block {
+                Some(block) => {
+                    String::from("\n")
+                        + &print_commands(&block.commands, last_line, indent + 1, max_number_length)
+                }
+                None => String::new(),
+            };
+            let mut res = format!(
+                "If {{ expression: {:?}, then: Block {{{}",
+                Printed(expression),
+                block(then, last_line)
+            );
+            let mut otherwise = otherwise;
+            // Show `Else If` chains as one list of branches, rather than
+            // each If nested inside the otherwise of the one before
+            while let Some(CommandLine {
+                cmd:
+                    Command::If {
+                        expression,
+                        then,
+                        otherwise: next,
+                    },
+                line,
+            }) = else_if(otherwise, *last_line)
+            {
+                if !res.ends_with('\n') {
+                    res += "\n";
+                }
+                res += &blank_lines(*line, last_line, max_number_length);
+                *last_line = *line;
+                res += &format!(
+                    "{:0width$}: {}}}, else if: {{ expression: {:?}, then: Block {{{}",
+                    line,
+                    "  ".repeat(indent),
+                    Printed(expression),
+                    block(then, last_line),
+                    width = max_number_length
+                );
+                otherwise = next;
+            }
+            let then_end = if res.ends_with('\n') {
+                new_indent.as_str()
             } else {
-                String::new()
-            },
-        ),
-        _ => format!("{:?}", command),
+                ""
+            };
+            let otherwise = match otherwise {
+                Some(_) => block(otherwise, last_line) + &new_indent,
+                None => String::new(),
+            };
+            format!(
+                "{}{}}}, otherwise: Block {{{}}}}}",
+                res, then_end, otherwise
+            )
+        }
+        _ => format!("{:?}", Printed(command)),
     }
 }
 
+// The If in an `Else If`, i.e. a lone If on a later line than `after`
+#[cfg(any(test, target_arch = "wasm32"))]
+fn else_if(otherwise: &Option<Block>, after: usize) -> Option<&CommandLine> {
+    match otherwise {
+        Some(Block { commands }) if commands.len() == 1 => match commands[0] {
+            CommandLine {
+                cmd: Command::If { .. },
+                line,
+            } if line > after => Some(&commands[0]),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Numbers for the lines between `last_line` and `line` with no commands on them
+#[cfg(any(test, target_arch = "wasm32"))]
+fn blank_lines(line: usize, last_line: &mut usize, max_number_length: usize) -> String {
+    let mut res = String::new();
+    while line != 0 && *last_line < line - 1 {
+        *last_line += 1;
+        res += &format!("{:0width$}:\n", *last_line, width = max_number_length);
+    }
+    res
+}
+
 #[cfg(any(test, target_arch = "wasm32"))]
 fn print_commands(
     commands: &Vec<CommandLine>,
@@ -78,10 +178,7 @@ fn print_commands(
 ) -> String {
     let mut res = String::new();
     for command in commands {
-        while command.line != 0 && *last_line < command.line - 1 {
-            *last_line += 1;
-            res += &format!("{:0width$}:\n", *last_line, width = max_number_length);
-        }
+        res += &blank_lines(command.line, last_line, max_number_length);
         *last_line = command.line;
         res += &format!("{:0width$}: ", command.line, width = max_number_length);
         res += &"  ".repeat(indent);
@@ -140,6 +237,28 @@ mod tests {
         test_print(code, expected);
     }
 
+    #[test]
+    fn test_print_else_if() {
+        let code = "If a thought is 1
+        Say \"one\"
+        Else if a thought is 2
+        Say \"two\"
+        Else
+        Say \"many\"
+        ";
+        let expected =
+            "1: If { expression: Is(Variable(\"a thought\"), Floating(1.0)), then: Block {
+2:   Say { value: String(\"one\") }
+3: }, else if: { expression: Is(Variable(\"a thought\"), Floating(2.0)), then: Block {
+4:   Say { value: String(\"two\") }
+   }, otherwise: Block {
+5:
+6:   Say { value: String(\"many\") }
+   }}
+";
+        test_print(code, expected);
+    }
+
     #[test]
     fn test_print_while() {
         let code = "while char is weaker than max
@@ -165,4 +284,11 @@ mod tests {
 ";
         test_print(code, expected);
     }
+
+    #[test]
+    fn test_print_escapes() {
+        let code = "Say \"a \\\"quote\\\"\\tand a bell\\u0007\" with \"\\n\"";
+        let expected = "1: Say { value: Add(String(\"a \\\"quote\\\"\\tand a bell\\u0007\"), String(\"\\n\")) }\n";
+        test_print(code, expected);
+    }
 }
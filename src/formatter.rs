@@ -0,0 +1,459 @@
+use crate::common::{Block, Command, CommandLine, Program, SymbolType};
+use crate::peg::Rule;
+use crate::syntax::{SyntaxElement, SyntaxNode, TokenKind};
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct FormatOptions {
+    pub canonical: bool,
+}
+
+const INDENT: &str = "  ";
+
+struct Entry<'a> {
+    line: usize,
+    depth: usize,
+    closes: usize,
+    command: Option<&'a Command>,
+}
+
+// Works out which source line goes at which depth, and how many blocks
+// finish before it (each of which needs a blank line to terminate it)
+struct Layout<'a> {
+    // Line of each If's Else, keyed by the line of the If
+    else_lines: HashMap<usize, usize>,
+    entries: Vec<Entry<'a>>,
+    pending_closes: usize,
+}
+
+fn on_new_line(block: &Block, line: usize) -> bool {
+    block.commands.first().is_some_and(|c| c.line != line)
+}
+
+// The program doesn't keep where an Else was written, so take it from the
+// syntax tree. An If nested on the same line as another comes after it in
+// `descendants()`, so the outer one wins.
+fn else_lines(tree: &SyntaxNode, source: &str) -> HashMap<usize, usize> {
+    let line_of = |offset: usize| source[..offset].matches('\n').count() + 1;
+    let mut res = HashMap::new();
+    for conditional in tree.descendants() {
+        if conditional.rule != Rule::conditional {
+            continue;
+        }
+        let else_token = conditional
+            .children
+            .iter()
+            .filter_map(|child| match child {
+                SyntaxElement::Node(node) if node.rule == Rule::alternate => Some(node),
+                _ => None,
+            })
+            .flat_map(|alternate| alternate.children.iter())
+            .find_map(|child| match child {
+                SyntaxElement::Token(token)
+                    if token.kind == TokenKind::Text && token.text.eq_ignore_ascii_case("else") =>
+                {
+                    Some(token)
+                }
+                _ => None,
+            });
+        if let Some(token) = else_token {
+            res.entry(line_of(conditional.start))
+                .or_insert_with(|| line_of(token.start));
+        }
+    }
+    res
+}
+
+impl<'a> Layout<'a> {
+    fn push(&mut self, line: usize, depth: usize, command: Option<&'a Command>) {
+        if let Some(last) = self.entries.last() {
+            if line <= last.line {
+                // i.e. a statement on the same line as its parent
+                return;
+            }
+        }
+        self.entries.push(Entry {
+            line,
+            depth,
+            closes: self.pending_closes,
+            command,
+        });
+        self.pending_closes = 0;
+    }
+
+    fn block(&mut self, block: &'a Block, line: usize, depth: usize) {
+        self.commands(&block.commands, depth + 1);
+        if on_new_line(block, line) {
+            self.pending_closes += 1;
+        }
+    }
+
+    fn commands(&mut self, commands: &'a [CommandLine], depth: usize) {
+        for command in commands {
+            self.push(command.line, depth, Some(&command.cmd));
+            match command.cmd {
+                Command::FunctionDeclaration { ref block, .. }
+                | Command::While { ref block, .. }
+                | Command::Until { ref block, .. } => self.block(block, command.line, depth),
+                Command::If {
+                    ref then,
+                    ref otherwise,
+                    ..
+                } => {
+                    if let Some(then) = then {
+                        self.commands(&then.commands, depth + 1);
+                    }
+                    if let Some(otherwise) = otherwise {
+                        let else_line = self.else_lines.get(&command.line).copied();
+                        match otherwise.commands.as_slice() {
+                            // `Else If`, which lines up with the If it follows
+                            [CommandLine {
+                                cmd: Command::If { .. },
+                                line,
+                            }] if Some(*line) == else_line => {
+                                self.commands(&otherwise.commands, depth);
+                            }
+                            _ => {
+                                if let Some(else_line) = else_line {
+                                    self.push(else_line, depth, None);
+                                }
+                                self.commands(&otherwise.commands, depth + 1);
+                                // The blank line after an else block also finishes the
+                                // enclosing block, so only the top level needs its own one
+                                if depth == 0 {
+                                    self.pending_closes += 1;
+                                }
+                            }
+                        }
+                    } else if then.as_ref().is_some_and(|t| on_new_line(t, command.line)) {
+                        self.pending_closes += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn aliases(command: &Command) -> Option<(&'static [&'static str], &'static str)> {
+    match command {
+        Command::Say { .. } => Some((&["shout", "whisper", "scream"], "say")),
+        Command::Return { .. } => Some((&["give back"], "return")),
+        Command::Continue => Some((&["take it to the top"], "continue")),
+        Command::Mutation { mutator, .. } => match mutator {
+            SymbolType::Cast => Some((&["burn"], "cast")),
+            SymbolType::Join => Some((&["unite"], "join")),
+            SymbolType::Split => Some((&["cut", "shatter"], "split")),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn canonicalise(text: &str, command: &Command) -> String {
+    if let Some((aliases, canonical)) = aliases(command) {
+        let lower = text.to_lowercase();
+        for alias in aliases {
+            if lower.starts_with(alias) {
+                let rest = &text[alias.len()..];
+                if rest.chars().next().is_some_and(char::is_alphabetic) {
+                    continue;
+                }
+                let keyword = if text.starts_with(char::is_uppercase) {
+                    canonical[..1].to_uppercase() + &canonical[1..]
+                } else {
+                    canonical.to_string()
+                };
+                return keyword + rest;
+            }
+        }
+    }
+    text.to_string()
+}
+
+// Blank and comment-only lines between two statements all act as block
+// terminators, so emit exactly as many as are needed
+fn emit_between(
+    res: &mut String,
+    lines: &[&str],
+    from: usize,
+    to: usize,
+    closes: usize,
+    depth: usize,
+) {
+    let between = &lines[from..to];
+    let comments: Vec<&str> = between
+        .iter()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+    let inside = from != 0 && to != lines.len();
+    let mut eols = closes;
+    if inside && depth == 0 && eols == 0 && !between.is_empty() {
+        // Keep paragraph breaks between top-level statements
+        eols = 1;
+    }
+    if inside {
+        for _ in comments.len()..eols {
+            res.push('\n');
+        }
+    }
+    for comment in comments {
+        *res += &format!("{}{}\n", INDENT.repeat(depth), comment);
+    }
+}
+
+/// Lays out the source of an already-parsed program with one indent per
+/// nested block and exactly the blank lines needed to end each block.
+/// Statements are kept as written (including poetic literals and comments),
+/// apart from keyword aliases if `canonical` is set. `tree` is the syntax
+/// tree from `parser::parse_lossless`, which says where each Else is.
+pub fn format(
+    program: &Program,
+    tree: &SyntaxNode,
+    source: &str,
+    options: &FormatOptions,
+) -> String {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut layout = Layout {
+        else_lines: else_lines(tree, source),
+        entries: vec![],
+        pending_closes: 0,
+    };
+    layout.commands(&program.commands, 0);
+
+    let mut res = String::new();
+    let mut previous = 0;
+    for entry in &layout.entries {
+        emit_between(
+            &mut res,
+            &lines,
+            previous,
+            entry.line - 1,
+            entry.closes,
+            entry.depth,
+        );
+        let text = lines[entry.line - 1].trim();
+        let text = match entry.command {
+            Some(command) if options.canonical => canonicalise(text, command),
+            _ => text.to_string(),
+        };
+        res += &format!("{}{}\n", INDENT.repeat(entry.depth), text);
+        previous = entry.line;
+    }
+    emit_between(&mut res, &lines, previous, lines.len(), 0, 0);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, FormatOptions};
+    use crate::common::{Block, Command, CommandLine};
+    use crate::parser;
+    use pretty_assertions::assert_eq;
+
+    fn fmt(code: &str, canonical: bool) -> String {
+        let (program, tree) = parser::parse_lossless(code).unwrap();
+        format(&program, &tree, code, &FormatOptions { canonical })
+    }
+
+    fn strip_lines(commands: &[CommandLine]) -> Vec<Command> {
+        let strip_block = |block: &Block| Block {
+            commands: strip_lines(&block.commands)
+                .into_iter()
+                .map(|cmd| CommandLine { cmd, line: 0 })
+                .collect(),
+        };
+        commands
+            .iter()
+            .map(|command| match &command.cmd {
+                Command::FunctionDeclaration { name, args, block } => {
+                    Command::FunctionDeclaration {
+                        name: name.clone(),
+                        args: args.clone(),
+                        block: strip_block(block),
+                    }
+                }
+                Command::While { expression, block } => Command::While {
+                    expression: expression.clone(),
+                    block: strip_block(block),
+                },
+                Command::Until { expression, block } => Command::Until {
+                    expression: expression.clone(),
+                    block: strip_block(block),
+                },
+                Command::If {
+                    expression,
+                    then,
+                    otherwise,
+                } => Command::If {
+                    expression: expression.clone(),
+                    then: then.as_ref().map(strip_block),
+                    otherwise: otherwise.as_ref().map(strip_block),
+                },
+                other => other.clone(),
+            })
+            .collect()
+    }
+
+    fn assert_round_trip(code: &str) {
+        let formatted = fmt(code, false);
+        assert_eq!(
+            strip_lines(&parser::parse(code).unwrap().commands),
+            strip_lines(&parser::parse(&formatted).unwrap().commands)
+        );
+        assert_eq!(formatted, fmt(&formatted, false));
+    }
+
+    #[test]
+    fn indents_blocks() {
+        let code = "Midnight takes your heart
+  While your heart is as high as 5
+Put your heart without 5 into your heart
+
+Give back your heart
+
+
+
+Say Midnight taking 12
+";
+        let expected = "Midnight takes your heart
+  While your heart is as high as 5
+    Put your heart without 5 into your heart
+
+  Give back your heart
+
+Say Midnight taking 12
+";
+        assert_eq!(fmt(code, false), expected);
+    }
+
+    #[test]
+    fn lines_up_else_if() {
+        let code = "If my heart is 1
+Say \"one\"
+  Else if my heart is 2
+    Say \"two\"
+      Else
+Say \"many\"
+
+Say my heart
+";
+        let expected = "If my heart is 1
+  Say \"one\"
+Else if my heart is 2
+  Say \"two\"
+Else
+  Say \"many\"
+
+Say my heart
+";
+        assert_eq!(fmt(code, false), expected);
+        assert_round_trip(code);
+    }
+
+    #[test]
+    fn keeps_else() {
+        let code = "If nothing is nothing
+Foo says foo
+Else
+Bar says bar
+
+Say foo
+";
+        let expected = "If nothing is nothing
+  Foo says foo
+Else
+  Bar says bar
+
+Say foo
+";
+        assert_eq!(fmt(code, false), expected);
+        assert_round_trip(code);
+    }
+
+    #[test]
+    fn else_is_a_whole_word() {
+        let code = "If my heart is 2 Say \"one\" Else Say \"two\"
+Elsewhere is nothing
+Say elsewhere
+";
+        let expected = "If my heart is 2 Say \"one\" Else Say \"two\"
+
+Elsewhere is nothing
+Say elsewhere
+";
+        assert_eq!(fmt(code, false), expected);
+        assert_round_trip(code);
+    }
+
+    #[test]
+    fn else_belongs_to_its_own_if() {
+        let code = "My heart is 3
+If my heart is 2 Say \"one\" Else Say \"two\"
+Say \"middle\"
+If my heart is 3
+Say \"three\"
+Else
+Say \"four\"
+
+Say \"end\"
+";
+        let expected = "My heart is 3
+If my heart is 2 Say \"one\" Else Say \"two\"
+
+Say \"middle\"
+If my heart is 3
+  Say \"three\"
+Else
+  Say \"four\"
+
+Say \"end\"
+";
+        assert_eq!(fmt(code, false), expected);
+        assert_round_trip(code);
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let code = "(the start)
+My heart is true (a boolean)
+If my heart
+Say \"yes\"
+   (blank line ending If block)
+Say \"done\"
+";
+        let expected = "(the start)
+My heart is true (a boolean)
+If my heart
+  Say \"yes\"
+(blank line ending If block)
+Say \"done\"
+";
+        assert_eq!(fmt(code, false), expected);
+    }
+
+    #[test]
+    fn canonical_aliases() {
+        let code = "Midnight takes your heart
+Give back your heart
+
+shout Midnight taking 2
+Whisper \"x\"
+";
+        let expected = "Midnight takes your heart
+  Return your heart
+
+say Midnight taking 2
+Say \"x\"
+";
+        assert_eq!(fmt(code, true), expected);
+    }
+
+    #[test]
+    fn round_trip_local_fixtures() {
+        assert_round_trip(include_str!("../tests/local/fizzbuzz.rock"));
+        assert_round_trip(include_str!("../tests/local/fizzbuzz_minimalist.rock"));
+        assert_round_trip(include_str!("../tests/local/modulo.rock"));
+    }
+}
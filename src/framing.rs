@@ -0,0 +1,51 @@
+//! Messages as used by the Debug Adapter Protocol and the Language Server
+//! Protocol: a JSON body after a `Content-Length` header
+
+use crate::common::Result;
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Reads the next message, or None at the end of the input
+pub fn read(input: &mut dyn BufRead) -> Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        let mut parts = header.splitn(2, ':');
+        if parts
+            .next()
+            .unwrap()
+            .trim()
+            .eq_ignore_ascii_case("Content-Length")
+        {
+            let value = parts.next().unwrap_or("").trim();
+            length = Some(value.parse::<usize>().map_err(invalid_data)?);
+        }
+    }
+    let mut body = vec![0; length.unwrap()];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body).map_err(invalid_data)?))
+}
+
+pub fn write(output: &mut dyn Write, message: &Value) -> Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+    Ok(())
+}
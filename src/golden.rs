@@ -0,0 +1,271 @@
+//! In-process version of the fixture tests in `tests/file_tests.rs`, running
+//! each program against its `.in`, `.out`, `.err` and `.vars` files without
+//! needing to spawn the binary
+
+use crate::common::MaidenError;
+use crate::runner::VariableType;
+use crate::value::{describe, Value};
+use crate::{parser, runner};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Cursor;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+/// Boils an error down to e.g. "MissingVariable at line 2", the form used in `.err` files
+pub fn normalise_error(err: &MaidenError) -> String {
+    let debug = format!("{:?}", err);
+    let variant: String = debug
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect();
+    match err.line() {
+        0 => variant,
+        line => format!("{} at line {}", variant, line),
+    }
+}
+
+// One "name = value" line per variable, sorted by name, the form used in `.vars` files
+fn describe_variables(variables: &HashMap<String, (VariableType, Value)>) -> Vec<String> {
+    let mut lines: Vec<String> = variables
+        .iter()
+        .map(|(name, (_, value))| format!("{} = {}", name, describe(value)))
+        .collect();
+    lines.sort();
+    lines
+}
+
+// .vars files can list variables in any order, and have blank lines
+fn sorted_lines(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = text
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect();
+    lines.sort();
+    lines
+}
+
+/// Runs `source` with `input` as its stdin, and checks it printed
+/// `expected_output`. If `expected_error` is set, the program should fail
+/// with that error, given in the form `normalise_error` boils errors down
+/// to. If `expected_vars`
+/// is set, the program should finish with those variables.
+pub fn check(
+    source: &str,
+    input: &str,
+    expected_output: &str,
+    expected_error: Option<&str>,
+    expected_vars: Option<&str>,
+) -> std::result::Result<(), String> {
+    let mut reader = Cursor::new(input.as_bytes());
+    let mut writer = Vec::new();
+    let result = parser::parse(source)
+        .and_then(|mut program| runner::run(&mut program, &mut reader, &mut writer));
+    let output = String::from_utf8_lossy(&writer);
+    if output != expected_output {
+        return Err(format!(
+            "expected output {:?}, got {:?}",
+            expected_output, output
+        ));
+    }
+    match (result, expected_error) {
+        (Ok(variables), None) => match expected_vars.map(sorted_lines) {
+            Some(expected) if expected != describe_variables(&variables) => Err(format!(
+                "expected variables {:?}, got {:?}",
+                expected,
+                describe_variables(&variables)
+            )),
+            _ => Ok(()),
+        },
+        (Ok(_), Some(expected)) => Err(format!("expected error {:?}, but it ran", expected)),
+        (Err(err), None) => Err(format!("unexpected error: {}", err)),
+        (Err(err), Some(expected)) => {
+            let expected = expected.trim();
+            let normalised = normalise_error(&err);
+            let comparable = expected
+                .split(" at line ")
+                .all(|part| !part.is_empty() && part.chars().all(char::is_alphanumeric));
+            if !comparable {
+                // e.g. a typo, or a message from another implementation
+                Err(format!(
+                    "expected error {:?} isn't in the form \"MissingVariable at line 2\", got {:?}",
+                    expected, normalised
+                ))
+            } else if normalised != expected {
+                Err(format!(
+                    "expected error {:?}, got {:?}",
+                    expected, normalised
+                ))
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Pass,
+    Fail(String),
+    /// Failed, but it's in the skip list for this reason
+    Skip(String),
+    /// In the skip list for this reason, but passes now
+    Fixed(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    /// Path of the fixture, relative to the skip list
+    pub name: String,
+    /// Directory the fixture's in, e.g. "arrays"
+    pub area: String,
+    pub status: Status,
+}
+
+/// Fixtures that are known not to pass yet, with why. These are listed in a
+/// TOML file, with a table for each fixture containing its `reason`.
+#[cfg(feature = "cmd")]
+pub fn skip_list(path: &Path) -> crate::common::Result<BTreeMap<String, String>> {
+    let skips: toml::Value = match fs::read_to_string(path)?.parse() {
+        Ok(skips) => skips,
+        Err(err) => {
+            return Err(MaidenError::Io {
+                io_error: std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+            })
+        }
+    };
+    Ok(skips
+        .as_table()
+        .into_iter()
+        .flatten()
+        .map(|(name, entry)| {
+            let reason = entry.get("reason").and_then(|reason| reason.as_str());
+            (name.clone(), reason.unwrap_or("").to_string())
+        })
+        .collect())
+}
+
+fn collect_fixtures(dir: &Path, found: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect(),
+        // e.g. the upstream submodule not being checked out
+        Err(_) => return,
+    };
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect_fixtures(&path, found);
+        } else if path.extension().is_some_and(|ext| ext == "rock") {
+            found.push(path);
+        }
+    }
+}
+
+/// Every `.rock` file under `dir`, sorted by path
+pub fn find_fixtures(dir: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    collect_fixtures(dir, &mut found);
+    found
+}
+
+fn read_or_empty(path: &Path, extension: &str) -> String {
+    let mut name = path.as_os_str().to_owned();
+    name.push(extension);
+    fs::read_to_string(name).unwrap_or_default()
+}
+
+/// Runs the fixture at `path` as its `.in`, `.out`, `.err` and `.vars` files
+/// say, or if `parse_only` is set, checks it fails to parse
+pub fn run_fixture(path: &Path, parse_only: bool) -> std::result::Result<(), String> {
+    let source = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    if parse_only {
+        return match parser::parse(&source) {
+            Ok(_) => Err("expected a parse error".to_string()),
+            Err(_) => Ok(()),
+        };
+    }
+    let expected_error = read_or_empty(path, ".err");
+    let expected_vars = read_or_empty(path, ".vars");
+    // Some parts of the runner still panic on things they don't support
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        check(
+            &source,
+            &read_or_empty(path, ".in"),
+            &read_or_empty(path, ".out"),
+            Some(expected_error.as_str()).filter(|err| !err.is_empty()),
+            Some(expected_vars.as_str()).filter(|vars| !vars.is_empty()),
+        )
+    }))
+    .unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()));
+        Err(format!("panicked: {}", message.unwrap_or_default()))
+    })
+}
+
+/// Runs every fixture under `dir`. Ones in a `failures` directory should fail
+/// to parse, and the rest should run as their `.out`, `.err` and `.vars` files say.
+/// Names are relative to `root`, as are the ones in `skips`.
+pub fn run_fixtures(root: &Path, dir: &Path, skips: &BTreeMap<String, String>) -> Vec<Outcome> {
+    find_fixtures(dir)
+        .iter()
+        .map(|path| {
+            let name = path
+                .strip_prefix(root)
+                .unwrap_or(path)
+                .to_string_lossy()
+                .replace('\\', "/");
+            let area = path
+                .parent()
+                .and_then(Path::file_name)
+                .map_or(String::new(), |area| area.to_string_lossy().to_string());
+            let result = run_fixture(path, name.contains("failures"));
+            let status = match (result, skips.get(&name)) {
+                (Ok(()), None) => Status::Pass,
+                (Err(message), None) => Status::Fail(message),
+                (Err(_), Some(reason)) => Status::Skip(reason.clone()),
+                (Ok(()), Some(reason)) => Status::Fixed(reason.clone()),
+            };
+            Outcome { name, area, status }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check;
+
+    #[test]
+    fn checks_output_and_input() {
+        let code = "Listen to your heart\nSay your heart\n";
+        assert_eq!(check(code, "hello\n", "hello\n", None, None), Ok(()));
+        assert!(check(code, "hello\n", "goodbye\n", None, None).is_err());
+    }
+
+    #[test]
+    fn checks_errors() {
+        let code = "Say \"before\"\nIf 1 is 1\nSay it\n";
+        let check = |err| check(code, "", "before\n", Some(err), None);
+        assert_eq!(check("UndefinedPronoun at line 3"), Ok(()));
+        assert!(check("UndefinedPronoun at line 2").is_err());
+        assert!(check("MissingVariable at line 3").is_err());
+        // Anything else can't be compared, so doesn't pass
+        assert!(check("Pronoun used before anything it could refer to").is_err());
+        assert!(check("Undefined pronoun at line 3").is_err());
+    }
+
+    #[test]
+    fn checks_variables() {
+        let code = "My heart is 5\nPut \"x\" into your soul\n";
+        let check = |vars| check(code, "", "", None, Some(vars));
+        assert_eq!(check("your soul = \"x\"\n\nmy heart = 5\n"), Ok(()));
+        assert!(check("my heart = 6\nyour soul = \"x\"\n").is_err());
+        assert!(check("my heart = 5\n").is_err());
+    }
+}
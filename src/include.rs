@@ -0,0 +1,327 @@
+use crate::common::{Block, Command, CommandLine, MaidenError, Program, Result};
+use crate::parser;
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where each included file's lines went. They get numbered after the lines
+/// of the main file (and any file included before them), so the runner can
+/// treat the whole lot as one program.
+#[derive(Debug, Default)]
+pub struct Sources {
+    files: Vec<Included>,
+}
+
+#[derive(Debug)]
+struct Included {
+    path: String,
+    first_line: usize,
+    lines: Vec<String>,
+}
+
+impl Sources {
+    fn file(&self, line: usize) -> Option<&Included> {
+        self.files
+            .iter()
+            .find(|file| line >= file.first_line && line < file.first_line + file.lines.len())
+    }
+
+    /// The included file, and line within it, that `line` of the combined
+    /// program came from. None for lines of the main file.
+    pub fn locate(&self, line: usize) -> Option<(&str, usize)> {
+        self.file(line)
+            .map(|file| (file.path.as_str(), line - file.first_line + 1))
+    }
+
+    /// The text of `line` of the combined program, where `main` is the
+    /// source of the main file
+    pub fn line<'a>(&'a self, main: &'a str, line: usize) -> Option<&'a str> {
+        match self.file(line) {
+            Some(file) => Some(file.lines[line - file.first_line].as_str()),
+            None => main.lines().nth(line.checked_sub(1)?),
+        }
+    }
+}
+
+struct Loader {
+    sources: Sources,
+    next_line: usize,
+    // Files being included right now, to spot cycles
+    stack: Vec<PathBuf>,
+    done: HashSet<PathBuf>,
+}
+
+/// Parses `source`, the program at `path`, along with the functions of any
+/// files it names on `Include "file"` lines (relative to the file doing the
+/// including). Errors in included files come back as `MaidenError::Included`.
+pub fn load(path: &Path, source: &str) -> Result<(Program, Sources)> {
+    let mut loader = Loader {
+        sources: Sources::default(),
+        next_line: source.lines().count() + 1,
+        stack: vec![path.canonicalize()?],
+        done: HashSet::new(),
+    };
+    let program = loader.load_file(path, source, 0)?;
+    Ok((program, loader.sources))
+}
+
+impl Loader {
+    fn load_file(&mut self, path: &Path, source: &str, offset: usize) -> Result<Program> {
+        let (source, includes) = take_includes(source);
+        let mut program = parser::parse(&source)?;
+        let lines: Vec<usize> = includes.iter().map(|(line, _)| *line).collect();
+        program
+            .commands
+            .retain(|command| !lines.contains(&command.line));
+        let nested = program
+            .commands
+            .iter()
+            .find_map(|command| nested_include(command, &lines));
+        if let Some(line) = nested {
+            let (_, file) = includes.iter().find(|(l, _)| *l == line).unwrap();
+            let file = file.clone();
+            return Err(MaidenError::NestedInclude { file, line });
+        }
+        shift(&mut program.commands, offset);
+        for function in program.functions.values_mut() {
+            shift(&mut function.block.commands, offset);
+        }
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for (line, file) in includes {
+            let included = dir.join(&file);
+            let missing = || MaidenError::MissingInclude {
+                file: file.clone(),
+                line,
+            };
+            let key = included.canonicalize().map_err(|_| missing())?;
+            if self.stack.contains(&key) {
+                return Err(MaidenError::IncludeCycle { file, line });
+            }
+            if !self.done.insert(key.clone()) {
+                continue;
+            }
+            let text = fs::read_to_string(&included).map_err(|_| missing())?;
+            let first_line = self.next_line;
+            let lines: Vec<String> = text.lines().map(str::to_string).collect();
+            self.next_line += lines.len();
+            let name = included.display().to_string();
+            self.sources.files.push(Included {
+                path: name.clone(),
+                first_line,
+                lines,
+            });
+            self.stack.push(key);
+            let functions = match self.load_file(&included, &text, first_line - 1) {
+                Ok(program) => program.functions,
+                Err(err @ MaidenError::Included { .. }) => return Err(err),
+                Err(err) => {
+                    return Err(MaidenError::Included {
+                        file: name,
+                        error: Box::new(err),
+                    })
+                }
+            };
+            self.stack.pop();
+            // The including file's own functions win
+            for (name, function) in functions {
+                program.functions.entry(name).or_insert(function);
+            }
+        }
+        Ok(program)
+    }
+}
+
+// Stands in for an `Include` line while parsing. A blank line would end any
+// block the include was in, rather than it getting reported.
+const PLACEHOLDER: &str = "Say \"\"";
+
+// Swaps the `Include` lines for a placeholder, so the rest parses as usual,
+// and gives back the line number and file of each of them
+fn take_includes(source: &str) -> (String, Vec<(usize, String)>) {
+    let include = Regex::new(r#"(?i)^\s*include\s+"([^"]*)"\s*$"#).unwrap();
+    let mut includes = Vec::new();
+    let lines: Vec<&str> = source
+        .split('\n')
+        .enumerate()
+        .map(|(index, line)| match include.captures(line) {
+            Some(captures) => {
+                includes.push((index + 1, captures[1].to_string()));
+                PLACEHOLDER
+            }
+            None => line,
+        })
+        .collect();
+    (lines.join("\n"), includes)
+}
+
+// Line of an include placeholder somewhere in the blocks of `command`
+fn nested_include(command: &CommandLine, lines: &[usize]) -> Option<usize> {
+    let blocks: Vec<&Block> = match command.cmd {
+        Command::FunctionDeclaration { ref block, .. }
+        | Command::While { ref block, .. }
+        | Command::Until { ref block, .. } => vec![block],
+        Command::If {
+            ref then,
+            ref otherwise,
+            ..
+        } => then.iter().chain(otherwise.iter()).collect(),
+        _ => vec![],
+    };
+    blocks
+        .iter()
+        .flat_map(|block| &block.commands)
+        .find_map(|inner| {
+            if lines.contains(&inner.line) {
+                Some(inner.line)
+            } else {
+                nested_include(inner, lines)
+            }
+        })
+}
+
+fn shift(commands: &mut [CommandLine], offset: usize) {
+    for command in commands {
+        command.line += offset;
+        match command.cmd {
+            Command::FunctionDeclaration { ref mut block, .. }
+            | Command::While { ref mut block, .. }
+            | Command::Until { ref mut block, .. } => shift(&mut block.commands, offset),
+            Command::If {
+                ref mut then,
+                ref mut otherwise,
+                ..
+            } => {
+                for block in then.iter_mut().chain(otherwise.iter_mut()) {
+                    shift(&mut block.commands, offset);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load;
+    use crate::common::MaidenError;
+    use crate::runner;
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::io::{self, Cursor};
+    use std::path::PathBuf;
+
+    // Writes out `files` in a directory of their own, giving back the path of the first
+    fn write(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join("maiden-include").join(test);
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        for (name, text) in files {
+            fs::write(dir.join(name), text).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    fn run(path: &PathBuf) -> (String, Option<MaidenError>, super::Sources) {
+        let (mut program, sources) = load(path, &fs::read_to_string(path).unwrap()).unwrap();
+        let mut writer = Cursor::new(Vec::new());
+        let err = runner::run(&mut program, &mut io::empty(), &mut writer).err();
+        (
+            String::from_utf8(writer.into_inner()).unwrap(),
+            err,
+            sources,
+        )
+    }
+
+    #[test]
+    fn merges_functions() {
+        let path = write(
+            "merges",
+            &[
+                (
+                    "main.rock",
+                    "Include \"lib/maths.rock\"\nSay Double taking 4\nSay Half taking 4\n",
+                ),
+                (
+                    "lib/maths.rock",
+                    "include \"halves.rock\"\n\nDouble takes x\nGive back x with x\n",
+                ),
+                ("lib/halves.rock", "Half takes x\nGive back x over 2\n"),
+            ],
+        );
+        let (output, err, _) = run(&path);
+        assert!(err.is_none(), "{:?}", err);
+        assert_eq!(output, "8\n2\n");
+    }
+
+    #[test]
+    fn locates_errors_in_included_files() {
+        let path = write(
+            "locates",
+            &[
+                (
+                    "main.rock",
+                    "Include \"broken.rock\"\nSay Broken taking 1\n",
+                ),
+                ("broken.rock", "Broken takes x\nGive back my ghost\n"),
+            ],
+        );
+        let (_, err, sources) = run(&path);
+        match err {
+            Some(MaidenError::MissingVariable { line, .. }) => {
+                let (file, line) = sources.locate(line).unwrap();
+                assert!(file.ends_with("broken.rock"), "{}", file);
+                assert_eq!(line, 2);
+            }
+            other => panic!("Expected MissingVariable, got {:?}", other),
+        }
+        assert_eq!(sources.locate(2), None);
+    }
+
+    #[test]
+    fn include_errors() {
+        let path = write(
+            "cycle",
+            &[
+                ("a.rock", "Include \"b.rock\"\n"),
+                ("b.rock", "\nInclude \"a.rock\"\n"),
+            ],
+        );
+        match load(&path, &fs::read_to_string(&path).unwrap()) {
+            Err(MaidenError::Included { file, error }) => {
+                assert!(file.ends_with("b.rock"), "{}", file);
+                match *error {
+                    MaidenError::IncludeCycle { file, line } => {
+                        assert_eq!((file.as_str(), line), ("a.rock", 2));
+                    }
+                    other => panic!("Expected IncludeCycle, got {:?}", other),
+                }
+            }
+            other => panic!("Expected an Included error, got {:?}", other.err()),
+        }
+
+        let path = write(
+            "nested",
+            &[
+                (
+                    "main.rock",
+                    "Double takes x\nInclude \"lib.rock\"\nGive back x with x\n",
+                ),
+                ("lib.rock", ""),
+            ],
+        );
+        match load(&path, &fs::read_to_string(&path).unwrap()) {
+            Err(MaidenError::NestedInclude { file, line }) => {
+                assert_eq!((file.as_str(), line), ("lib.rock", 2));
+            }
+            other => panic!("Expected NestedInclude, got {:?}", other.err()),
+        }
+
+        let path = write("missing", &[("main.rock", "\nInclude \"nowhere.rock\"\n")]);
+        match load(&path, &fs::read_to_string(&path).unwrap()) {
+            Err(MaidenError::MissingInclude { file, line }) => {
+                assert_eq!((file.as_str(), line), ("nowhere.rock", 2));
+            }
+            other => panic!("Expected MissingInclude, got {:?}", other.err()),
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! The Rockstar interpreter itself, shared by the `maiden` binary and the
+//! fixture tests in `tests/file_tests.rs`
+
+#![deny(warnings)]
+#![allow(clippy::needless_return)]
+
+pub mod common;
+pub mod golden;
+pub mod parser;
+pub mod peg;
+pub mod runner;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod syntax;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transpile;
+pub mod value;
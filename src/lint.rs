@@ -0,0 +1,529 @@
+use crate::common::{Block, Command, CommandLine, Expression, Program};
+use crate::peg::{Rockstar, Rule};
+use pest::Parser;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LintKind {
+    UnusedVariable,
+    UnreachableCode,
+    ConstantLoopCondition,
+    ShadowedArgument,
+    SurprisingPoeticNumber,
+}
+
+const ALL_LINTS: [LintKind; 5] = [
+    LintKind::UnusedVariable,
+    LintKind::UnreachableCode,
+    LintKind::ConstantLoopCondition,
+    LintKind::ShadowedArgument,
+    LintKind::SurprisingPoeticNumber,
+];
+
+impl LintKind {
+    pub fn all() -> &'static [LintKind] {
+        &ALL_LINTS
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LintKind::UnusedVariable => "unused-variable",
+            LintKind::UnreachableCode => "unreachable-code",
+            LintKind::ConstantLoopCondition => "constant-loop-condition",
+            LintKind::ShadowedArgument => "shadowed-argument",
+            LintKind::SurprisingPoeticNumber => "surprising-poetic-number",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<LintKind> {
+        ALL_LINTS.iter().cloned().find(|kind| kind.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub kind: LintKind,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}: {} [{}]",
+            self.line,
+            self.message,
+            self.kind.name()
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LintConfig {
+    allowed: HashSet<LintKind>,
+}
+
+impl LintConfig {
+    pub fn allow(&mut self, kind: LintKind) {
+        self.allowed.insert(kind);
+    }
+
+    pub fn enabled(&self, kind: LintKind) -> bool {
+        !self.allowed.contains(&kind)
+    }
+}
+
+enum Written<'a> {
+    Name(&'a str),
+    Pronoun,
+}
+
+fn for_each_subexpression(expression: &Expression, f: &mut dyn FnMut(&Expression)) {
+    f(expression);
+    match expression {
+        Expression::ArrayRef { name, index } => {
+            for_each_subexpression(name, f);
+            for_each_subexpression(index, f);
+        }
+        Expression::Modifier(inner) | Expression::Not(inner) => for_each_subexpression(inner, f),
+        Expression::Call(_, args) => {
+            for arg in args {
+                for_each_subexpression(arg, f);
+            }
+        }
+        Expression::Is(first, second)
+        | Expression::Aint(first, second)
+        | Expression::Add(first, second)
+        | Expression::Subtract(first, second)
+        | Expression::Times(first, second)
+        | Expression::Divide(first, second)
+        | Expression::And(first, second)
+        | Expression::Or(first, second)
+        | Expression::Nor(first, second)
+        | Expression::GreaterThanOrEqual(first, second)
+        | Expression::GreaterThan(first, second)
+        | Expression::LessThanOrEqual(first, second)
+        | Expression::LessThan(first, second) => {
+            for_each_subexpression(first, f);
+            for_each_subexpression(second, f);
+        }
+        _ => {}
+    }
+}
+
+// Expressions that get evaluated (i.e. read) when running a command
+fn command_expressions(command: &Command) -> Vec<&Expression> {
+    match command {
+        Command::Assignment { target, value } => {
+            let mut expressions = vec![&**value];
+            if let Expression::ArrayRef { index, .. } = &**target {
+                expressions.push(index);
+            }
+            expressions
+        }
+        Command::Until { expression, .. }
+        | Command::While { expression, .. }
+        | Command::If { expression, .. } => vec![expression],
+        Command::Increment { target, .. }
+        | Command::Decrement { target, .. }
+        | Command::Floor { target }
+        | Command::Ceil { target }
+        | Command::Round { target } => vec![target],
+        Command::Say { value } => vec![value],
+        Command::Return { return_value } => vec![return_value],
+        Command::Call { args, .. } => args.iter().collect(),
+        Command::Mutation {
+            source,
+            lookup,
+            modifier,
+            ..
+        } => source
+            .iter()
+            .chain(lookup.iter())
+            .chain(modifier.iter())
+            .map(|b| &**b)
+            .collect(),
+        Command::Continue
+        | Command::Break
+        | Command::Listen { .. }
+        | Command::FunctionDeclaration { .. } => vec![],
+    }
+}
+
+fn written_target(expression: &Expression) -> Option<Written<'_>> {
+    match expression {
+        Expression::Variable(name) => Some(Written::Name(name)),
+        Expression::ArrayRef { name, .. } => match &**name {
+            Expression::Variable(name) => Some(Written::Name(name)),
+            _ => None,
+        },
+        Expression::Pronoun => Some(Written::Pronoun),
+        _ => None,
+    }
+}
+
+fn written_variable(command: &Command) -> Option<Written<'_>> {
+    match command {
+        Command::Assignment { target, .. } => written_target(target),
+        Command::Increment { target, .. }
+        | Command::Decrement { target, .. }
+        | Command::Floor { target }
+        | Command::Ceil { target }
+        | Command::Round { target } => written_target(target),
+        Command::Listen {
+            target: Some(target),
+        } => Some(Written::Name(target)),
+        Command::Mutation { target, lookup, .. } => target
+            .as_ref()
+            .or(lookup.as_ref())
+            .and_then(|b| written_target(b)),
+        _ => None,
+    }
+}
+
+fn nested_blocks(command: &Command) -> Vec<&Block> {
+    match command {
+        Command::Until { block, .. }
+        | Command::While { block, .. }
+        | Command::FunctionDeclaration { block, .. } => vec![block],
+        Command::If {
+            then, otherwise, ..
+        } => then.iter().chain(otherwise.iter()).collect(),
+        _ => vec![],
+    }
+}
+
+pub fn for_each_command(
+    commands: &[CommandLine],
+    into_functions: bool,
+    f: &mut dyn FnMut(&CommandLine),
+) {
+    for command in commands {
+        f(command);
+        if let Command::FunctionDeclaration { .. } = command.cmd {
+            if !into_functions {
+                continue;
+            }
+        }
+        for block in nested_blocks(&command.cmd) {
+            for_each_command(&block.commands, into_functions, f);
+        }
+    }
+}
+
+fn unused_variables(program: &Program, warnings: &mut Vec<Warning>) {
+    let mut written: HashMap<String, usize> = HashMap::new();
+    let mut read = HashSet::new();
+    let mut last_written: Option<String> = None;
+    for_each_command(&program.commands, true, &mut |command| {
+        for expression in command_expressions(&command.cmd) {
+            for_each_subexpression(expression, &mut |sub| match sub {
+                Expression::Variable(name) => {
+                    read.insert(name.to_lowercase());
+                }
+                Expression::Pronoun => {
+                    if let Some(name) = &last_written {
+                        read.insert(name.clone());
+                    }
+                }
+                _ => {}
+            });
+        }
+        if let Some(Written::Name(name)) = written_variable(&command.cmd) {
+            let name = name.to_lowercase();
+            written.entry(name.clone()).or_insert(command.line);
+            last_written = Some(name);
+        }
+    });
+    let mut unused: Vec<_> = written
+        .into_iter()
+        .filter(|(name, _)| !read.contains(name))
+        .collect();
+    unused.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+    for (name, line) in unused {
+        warnings.push(Warning {
+            kind: LintKind::UnusedVariable,
+            line,
+            message: format!("'{}' is assigned but never read", name),
+        });
+    }
+}
+
+fn unreachable_code(commands: &[CommandLine], warnings: &mut Vec<Warning>) {
+    let mut terminator: Option<(&str, usize)> = None;
+    for command in commands {
+        if let Some((keyword, line)) = terminator {
+            warnings.push(Warning {
+                kind: LintKind::UnreachableCode,
+                line: command.line,
+                message: format!("unreachable code after the {} on line {}", keyword, line),
+            });
+            return;
+        }
+        for block in nested_blocks(&command.cmd) {
+            unreachable_code(&block.commands, warnings);
+        }
+        terminator = match command.cmd {
+            Command::Return { .. } => Some(("return", command.line)),
+            Command::Break => Some(("break", command.line)),
+            Command::Continue => Some(("continue", command.line)),
+            _ => None,
+        };
+    }
+}
+
+fn constant_loop_conditions(commands: &[CommandLine], warnings: &mut Vec<Warning>) {
+    for command in commands {
+        let loop_parts = match &command.cmd {
+            Command::While { expression, block } => Some(("while", expression, block)),
+            Command::Until { expression, block } => Some(("until", expression, block)),
+            _ => None,
+        };
+        if let Some((keyword, expression, block)) = loop_parts {
+            // Pronouns and function calls could touch anything, so only judge
+            // conditions we can fully see into
+            let mut analysable = true;
+            let mut condition_variables = HashSet::new();
+            for_each_subexpression(expression, &mut |sub| match sub {
+                Expression::Variable(name) => {
+                    condition_variables.insert(name.to_lowercase());
+                }
+                Expression::Pronoun | Expression::Call(..) => analysable = false,
+                _ => {}
+            });
+            let mut changed = HashSet::new();
+            for_each_command(&block.commands, false, &mut |inner| {
+                match inner.cmd {
+                    Command::Break
+                    | Command::Return { .. }
+                    | Command::Call { .. }
+                    | Command::FunctionDeclaration { .. } => analysable = false,
+                    _ => {}
+                }
+                for expression in command_expressions(&inner.cmd) {
+                    for_each_subexpression(expression, &mut |sub| {
+                        if let Expression::Call(..) = sub {
+                            analysable = false;
+                        }
+                    });
+                }
+                match written_variable(&inner.cmd) {
+                    Some(Written::Name(name)) => {
+                        changed.insert(name.to_lowercase());
+                    }
+                    Some(Written::Pronoun) => analysable = false,
+                    None => {}
+                }
+            });
+            if analysable && condition_variables.is_disjoint(&changed) {
+                warnings.push(Warning {
+                    kind: LintKind::ConstantLoopCondition,
+                    line: command.line,
+                    message: format!(
+                        "nothing in this {} loop changes its condition, so it either never runs or never stops",
+                        keyword
+                    ),
+                });
+            }
+        }
+        for block in nested_blocks(&command.cmd) {
+            constant_loop_conditions(&block.commands, warnings);
+        }
+    }
+}
+
+fn shadowed_arguments(program: &Program, warnings: &mut Vec<Warning>) {
+    let mut globals = HashSet::new();
+    for_each_command(&program.commands, false, &mut |command| {
+        if let Some(Written::Name(name)) = written_variable(&command.cmd) {
+            globals.insert(name.to_lowercase());
+        }
+    });
+    for_each_command(&program.commands, true, &mut |command| {
+        if let Command::FunctionDeclaration { name, args, .. } = &command.cmd {
+            for arg in args {
+                if globals.contains(&arg.to_lowercase()) {
+                    warnings.push(Warning {
+                        kind: LintKind::ShadowedArgument,
+                        line: command.line,
+                        message: format!(
+                            "argument '{}' of '{}' shadows a global variable",
+                            arg, name
+                        ),
+                    });
+                }
+            }
+        }
+    });
+}
+
+// Why a word of a poetic number might not give the digit its writer expected
+fn surprising_digit(raw_word: &str) -> Option<String> {
+    let mut word = raw_word.to_string();
+    word.retain(|c| c.is_alphabetic() || c == '-');
+    let letters = word.chars().count();
+    if letters > 10 {
+        Some(format!(
+            "'{}' has {} letters, so it counts as the digit {}",
+            word,
+            letters,
+            letters % 10
+        ))
+    } else if word.contains('-') {
+        Some(format!(
+            "hyphens in '{}' count as letters, so it counts as the digit {}",
+            word,
+            letters % 10
+        ))
+    } else {
+        None
+    }
+}
+
+fn surprising_poetic_numbers(source: &str, warnings: &mut Vec<Warning>) {
+    let pairs = match Rockstar::parse(Rule::program, source) {
+        Ok(pairs) => pairs,
+        Err(_) => return,
+    };
+    for pair in pairs.flatten() {
+        if pair.as_rule() != Rule::poetic_number {
+            continue;
+        }
+        let line = pair.as_span().start_pos().line_col().0;
+        for raw_word in pair.as_str().split_whitespace() {
+            if let Some(message) = surprising_digit(raw_word) {
+                warnings.push(Warning {
+                    kind: LintKind::SurprisingPoeticNumber,
+                    line,
+                    message,
+                });
+            }
+        }
+    }
+}
+
+pub fn check(program: &Program, source: &str, config: &LintConfig) -> Vec<Warning> {
+    let mut warnings = vec![];
+    if config.enabled(LintKind::UnusedVariable) {
+        unused_variables(program, &mut warnings);
+    }
+    if config.enabled(LintKind::UnreachableCode) {
+        unreachable_code(&program.commands, &mut warnings);
+    }
+    if config.enabled(LintKind::ConstantLoopCondition) {
+        constant_loop_conditions(&program.commands, &mut warnings);
+    }
+    if config.enabled(LintKind::ShadowedArgument) {
+        shadowed_arguments(program, &mut warnings);
+    }
+    if config.enabled(LintKind::SurprisingPoeticNumber) {
+        surprising_poetic_numbers(source, &mut warnings);
+    }
+    warnings.sort_by_key(|warning| warning.line);
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, surprising_digit, LintConfig, LintKind};
+    use crate::parser;
+    use pretty_assertions::assert_eq;
+
+    fn lint(code: &str) -> Vec<(LintKind, usize)> {
+        let program = parser::parse(code).unwrap();
+        check(&program, code, &LintConfig::default())
+            .into_iter()
+            .map(|warning| (warning.kind, warning.line))
+            .collect()
+    }
+
+    #[test]
+    fn unused_variable() {
+        assert_eq!(
+            lint("Desire is 5\nMy world is 3\nSay my world\n"),
+            vec![(LintKind::UnusedVariable, 1)]
+        );
+    }
+
+    #[test]
+    fn pronoun_counts_as_read() {
+        assert_eq!(lint("Desire is 5\nSay it\n"), vec![]);
+    }
+
+    #[test]
+    fn unreachable_after_return() {
+        let code = "Midnight takes your heart
+Give back your heart
+Say your heart
+
+Say Midnight taking 1
+";
+        assert_eq!(lint(code), vec![(LintKind::UnreachableCode, 3)]);
+    }
+
+    #[test]
+    fn constant_loop() {
+        let code = "My world is 0
+Your heart is 0
+While my world is nothing
+Build your heart up
+Say your heart
+
+";
+        assert_eq!(lint(code), vec![(LintKind::ConstantLoopCondition, 3)]);
+    }
+
+    #[test]
+    fn changing_loop() {
+        let code = "My world is 0
+While my world is as low as 10
+Build my world up
+
+";
+        assert_eq!(lint(code), vec![]);
+    }
+
+    #[test]
+    fn shadowed_argument() {
+        let code = "Your heart is 5
+Midnight takes your heart
+Give back your heart
+
+Say Midnight taking your heart
+";
+        assert_eq!(lint(code), vec![(LintKind::ShadowedArgument, 2)]);
+    }
+
+    #[test]
+    fn surprising_poetic_number() {
+        assert_eq!(
+            lint("My heart is understanding\nSay my heart\n"),
+            vec![(LintKind::SurprisingPoeticNumber, 1)]
+        );
+    }
+
+    #[test]
+    fn poetic_numbers_count_letters_not_bytes() {
+        // Ten letters, but more bytes than that
+        assert_eq!(surprising_digit("héllowörld"), None);
+        assert_eq!(
+            surprising_digit("héllo-there").unwrap(),
+            "'héllo-there' has 11 letters, so it counts as the digit 1"
+        );
+        assert_eq!(
+            surprising_digit("héllo-you").unwrap(),
+            "hyphens in 'héllo-you' count as letters, so it counts as the digit 9"
+        );
+    }
+
+    #[test]
+    fn allowed_lints() {
+        let code = "Desire is 5\n";
+        let program = parser::parse(code).unwrap();
+        let mut config = LintConfig::default();
+        config.allow(LintKind::UnusedVariable);
+        assert_eq!(check(&program, code, &config), vec![]);
+    }
+}
@@ -0,0 +1,657 @@
+use crate::common::{Command, MaidenError, Program, Result};
+use crate::framing;
+use crate::lint::{self, LintConfig};
+use crate::parser;
+use crate::peg::Rule;
+use crate::syntax::{SyntaxElement, SyntaxNode};
+use crate::value::{describe, Value as RockstarValue};
+use pest::error::LineColLocation;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// LSP severities
+const ERROR: i64 = 1;
+const WARNING: i64 = 2;
+
+// LSP symbol and completion kinds
+const FUNCTION_SYMBOL: i64 = 12;
+const FUNCTION_COMPLETION: i64 = 3;
+const VARIABLE_COMPLETION: i64 = 6;
+
+/// Converts between byte offsets and LSP positions, which count UTF-16 code
+/// units from the start of the line
+struct LineIndex<'a> {
+    text: &'a str,
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(text: &'a str) -> LineIndex<'a> {
+        let mut starts = vec![0];
+        starts.extend(text.match_indices('\n').map(|(index, _)| index + 1));
+        LineIndex { text, starts }
+    }
+
+    fn line_text(&self, line: usize) -> &'a str {
+        let start = self.starts.get(line).cloned().unwrap_or(self.text.len());
+        let end = self
+            .starts
+            .get(line + 1)
+            .cloned()
+            .unwrap_or(self.text.len());
+        self.text[start..end].trim_end_matches(['\n', '\r'])
+    }
+
+    fn position(&self, offset: usize) -> Value {
+        let line = match self.starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        let character = self.text[self.starts[line]..offset].encode_utf16().count();
+        json!({"line": line, "character": character})
+    }
+
+    fn offset(&self, position: &Value) -> usize {
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let mut character = position["character"].as_u64().unwrap_or(0) as usize;
+        let start = match self.starts.get(line) {
+            Some(start) => *start,
+            None => return self.text.len(),
+        };
+        for (index, c) in self.line_text(line).char_indices() {
+            if character < c.len_utf16() {
+                return start + index;
+            }
+            character -= c.len_utf16();
+        }
+        start + self.line_text(line).len()
+    }
+
+    fn range(&self, start: usize, end: usize) -> Value {
+        json!({"start": self.position(start), "end": self.position(end)})
+    }
+
+    // The whole of a (1-based, as in `CommandLine`) line
+    fn line_range(&self, line: usize) -> Value {
+        let line = line.max(1) - 1;
+        let start = self.starts.get(line).cloned().unwrap_or(self.text.len());
+        self.range(start, start + self.line_text(line).len())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Read,
+    Assign,
+    Call,
+    Declare,
+}
+
+#[derive(Debug)]
+struct Occurrence {
+    name: String,
+    text: String,
+    start: usize,
+    end: usize,
+    role: Role,
+}
+
+// Variables are case insensitive, and spacing inside common variables doesn't matter
+fn normalise(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn child_nodes(node: &SyntaxNode) -> impl Iterator<Item = &SyntaxNode> {
+    node.children.iter().filter_map(|child| match child {
+        SyntaxElement::Node(node) => Some(node),
+        SyntaxElement::Token(_) => None,
+    })
+}
+
+// Finds every use of a name, and what it was being used for
+fn collect_occurrences(node: &SyntaxNode, role: Role, occurrences: &mut Vec<Occurrence>) {
+    match node.rule {
+        Rule::common_variable | Rule::proper_variable | Rule::simple_variable => {
+            let text = node.text();
+            occurrences.push(Occurrence {
+                name: normalise(&text),
+                text,
+                start: node.start,
+                end: node.end,
+                role,
+            });
+            return;
+        }
+        Rule::function | Rule::function_call => {
+            let (name_role, list_role) = if node.rule == Rule::function {
+                (Role::Declare, Role::Assign)
+            } else {
+                (Role::Call, Role::Read)
+            };
+            for (index, child) in child_nodes(node).enumerate() {
+                match (index, child.rule) {
+                    (0, _) => collect_occurrences(child, name_role, occurrences),
+                    (_, Rule::variable_list) => collect_occurrences(child, list_role, occurrences),
+                    _ => collect_occurrences(child, Role::Read, occurrences),
+                }
+            }
+            return;
+        }
+        // The variable being written to, but not the index expression after it
+        Rule::assignable if role == Role::Assign => {
+            for (index, child) in child_nodes(node).enumerate() {
+                let role = if index == 0 { Role::Assign } else { Role::Read };
+                collect_occurrences(child, role, occurrences);
+            }
+            return;
+        }
+        Rule::assignment | Rule::target | Rule::readline => {
+            for (index, child) in child_nodes(node).enumerate() {
+                let role = if index == 0 { Role::Assign } else { Role::Read };
+                collect_occurrences(child, role, occurrences);
+            }
+            return;
+        }
+        _ => {}
+    }
+    for child in child_nodes(node) {
+        collect_occurrences(child, role, occurrences);
+    }
+}
+
+struct Analysis {
+    program: Program,
+    tree: SyntaxNode,
+    occurrences: Vec<Occurrence>,
+}
+
+impl Analysis {
+    fn occurrence_at(&self, offset: usize) -> Option<&Occurrence> {
+        self.occurrences
+            .iter()
+            .find(|occurrence| occurrence.start <= offset && offset <= occurrence.end)
+    }
+
+    fn definition(&self, occurrence: &Occurrence) -> Option<&Occurrence> {
+        let wanted = match occurrence.role {
+            Role::Call | Role::Declare => Role::Declare,
+            Role::Read | Role::Assign => Role::Assign,
+        };
+        self.occurrences
+            .iter()
+            .find(|other| other.role == wanted && other.name == occurrence.name)
+    }
+
+    // Smallest node of one of `rules` around `offset`
+    fn node_at(&self, offset: usize, rules: &[Rule]) -> Option<&SyntaxNode> {
+        self.tree
+            .descendants()
+            .into_iter()
+            .filter(|node| rules.contains(&node.rule))
+            .filter(|node| node.start <= offset && offset <= node.end)
+            .min_by_key(|node| node.end - node.start)
+    }
+}
+
+struct Document {
+    text: String,
+    analysis: Option<Analysis>,
+    // Names from the last version that parsed, for completion while editing
+    names: Vec<(String, Role)>,
+}
+
+fn parse_error(text: &str, err: &MaidenError) -> Value {
+    let lines = LineIndex::new(text);
+    let (range, message) = match err {
+        MaidenError::Pest { kind } => {
+            let (line, column) = match kind.line_col {
+                LineColLocation::Pos(position) => position,
+                LineColLocation::Span(start, _) => start,
+            };
+            let line_text = lines.line_text(line - 1);
+            let start = lines.starts[line - 1]
+                + line_text
+                    .char_indices()
+                    .nth(column - 1)
+                    .map_or(line_text.len(), |(index, _)| index);
+            let end = lines.starts[line - 1] + line_text.len();
+            // The last line of pest's message is the bit saying what it expected
+            let message = kind.to_string();
+            let message = message
+                .lines()
+                .last()
+                .unwrap_or("")
+                .trim_start_matches([' ', '='])
+                .to_string();
+            (lines.range(start, end), message)
+        }
+        other => (lines.line_range(other.line()), other.to_string()),
+    };
+    json!({
+        "range": range,
+        "severity": ERROR,
+        "source": "maiden",
+        "message": message,
+    })
+}
+
+fn analyse(document: &mut Document) -> Vec<Value> {
+    match parser::parse_lossless(&document.text) {
+        Ok((program, tree)) => {
+            let lines = LineIndex::new(&document.text);
+            let diagnostics = lint::check(&program, &document.text, &LintConfig::default())
+                .iter()
+                .map(|warning| {
+                    json!({
+                        "range": lines.line_range(warning.line),
+                        "severity": WARNING,
+                        "source": "maiden",
+                        "code": warning.kind.name(),
+                        "message": warning.message,
+                    })
+                })
+                .collect();
+            let mut occurrences = vec![];
+            collect_occurrences(&tree, Role::Read, &mut occurrences);
+            document.names.clear();
+            for occurrence in &occurrences {
+                if !document
+                    .names
+                    .iter()
+                    .any(|(name, _)| normalise(name) == occurrence.name)
+                {
+                    document
+                        .names
+                        .push((occurrence.text.clone(), occurrence.role));
+                }
+            }
+            document.analysis = Some(Analysis {
+                program,
+                tree,
+                occurrences,
+            });
+            diagnostics
+        }
+        Err(err) => {
+            document.analysis = None;
+            vec![parse_error(&document.text, &err)]
+        }
+    }
+}
+
+fn hover(analysis: &Analysis, lines: &LineIndex, offset: usize) -> Value {
+    let node = match analysis.node_at(offset, &[Rule::poetic_number, Rule::poetic_string]) {
+        Some(node) => node,
+        None => return Value::Null,
+    };
+    let line = lines
+        .starts
+        .binary_search(&node.start)
+        .unwrap_or_else(|l| l - 1)
+        + 1;
+    let mut value = None;
+    lint::for_each_command(&analysis.program.commands, true, &mut |command| {
+        if let Command::Assignment { value: v, .. } = &command.cmd {
+            if command.line == line && value.is_none() {
+                value = Some(match RockstarValue::literal(v) {
+                    Some(literal) => describe(&literal),
+                    None => format!("{:?}", v),
+                });
+            }
+        }
+    });
+    match value {
+        Some(value) => json!({
+            "contents": {"kind": "markdown", "value": format!("`{}`", value)},
+            "range": lines.range(node.start, node.end),
+        }),
+        None => Value::Null,
+    }
+}
+
+fn document_symbols(analysis: &Analysis, lines: &LineIndex) -> Vec<Value> {
+    let mut symbols = vec![];
+    for node in analysis.tree.descendants() {
+        if node.rule != Rule::function {
+            continue;
+        }
+        let name = match analysis
+            .occurrences
+            .iter()
+            .find(|o| o.role == Role::Declare && o.start >= node.start && o.end <= node.end)
+        {
+            Some(name) => name,
+            None => continue,
+        };
+        let args: Vec<&str> = child_nodes(node)
+            .filter(|child| child.rule == Rule::variable_list)
+            .flat_map(|list| {
+                analysis
+                    .occurrences
+                    .iter()
+                    .filter(move |o| o.start >= list.start && o.end <= list.end)
+            })
+            .map(|o| o.text.as_str())
+            .collect();
+        symbols.push(json!({
+            "name": name.text,
+            "detail": format!("takes {}", args.join(", ")),
+            "kind": FUNCTION_SYMBOL,
+            "range": lines.range(node.start, node.end),
+            "selectionRange": lines.range(name.start, name.end),
+        }));
+    }
+    symbols
+}
+
+struct Server<'a> {
+    output: &'a mut dyn Write,
+    documents: HashMap<String, Document>,
+}
+
+impl<'a> Server<'a> {
+    fn send(&mut self, message: Value) -> Result<()> {
+        framing::write(self.output, &message)
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Value>) -> Result<()> {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics},
+        }))
+    }
+
+    fn update(&mut self, uri: &str, text: String) -> Result<()> {
+        let document = self
+            .documents
+            .entry(uri.to_string())
+            .or_insert_with(|| Document {
+                text: String::new(),
+                analysis: None,
+                names: vec![],
+            });
+        document.text = text;
+        let diagnostics = analyse(document);
+        self.publish(uri, diagnostics)
+    }
+
+    // Answers a request about a position in a document
+    fn at_position(&self, method: &str, params: &Value) -> Value {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let document = match self.documents.get(uri) {
+            Some(document) => document,
+            None => return Value::Null,
+        };
+        if method == "textDocument/completion" {
+            let items: Vec<Value> = document
+                .names
+                .iter()
+                .map(|(name, role)| {
+                    let kind = match role {
+                        Role::Declare | Role::Call => FUNCTION_COMPLETION,
+                        Role::Read | Role::Assign => VARIABLE_COMPLETION,
+                    };
+                    json!({"label": name, "kind": kind})
+                })
+                .collect();
+            return json!(items);
+        }
+        let analysis = match &document.analysis {
+            Some(analysis) => analysis,
+            None => return Value::Null,
+        };
+        let lines = LineIndex::new(&document.text);
+        match method {
+            "textDocument/definition" => {
+                let offset = lines.offset(&params["position"]);
+                match analysis
+                    .occurrence_at(offset)
+                    .and_then(|occurrence| analysis.definition(occurrence))
+                {
+                    Some(definition) => json!({
+                        "uri": uri,
+                        "range": lines.range(definition.start, definition.end),
+                    }),
+                    None => Value::Null,
+                }
+            }
+            "textDocument/hover" => hover(analysis, &lines, lines.offset(&params["position"])),
+            _ => json!(document_symbols(analysis, &lines)),
+        }
+    }
+
+    // Deals with a message, returning false once the client says to exit
+    fn handle(&mut self, message: &Value) -> Result<bool> {
+        let params = &message["params"];
+        let result = match message["method"].as_str().unwrap_or("") {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": {"name": "maiden"},
+            }),
+            "shutdown" => Value::Null,
+            "exit" => return Ok(false),
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+                let uri = document["uri"].as_str().unwrap_or("");
+                let text = document["text"].as_str().unwrap_or("");
+                self.update(uri, text.to_string())?;
+                return Ok(true);
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                let changes = params["contentChanges"].as_array();
+                if let Some(change) = changes.and_then(|changes| changes.last()) {
+                    let text = change["text"].as_str().unwrap_or("");
+                    self.update(uri, text.to_string())?;
+                }
+                return Ok(true);
+            }
+            "textDocument/didClose" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                self.documents.remove(uri);
+                self.publish(uri, vec![])?;
+                return Ok(true);
+            }
+            method @ "textDocument/definition"
+            | method @ "textDocument/hover"
+            | method @ "textDocument/documentSymbol"
+            | method @ "textDocument/completion" => self.at_position(method, params),
+            method => {
+                // Notifications we don't care about can just be ignored
+                if message.get("id").is_some() {
+                    self.send(json!({
+                        "jsonrpc": "2.0",
+                        "id": message["id"],
+                        "error": {
+                            "code": METHOD_NOT_FOUND,
+                            "message": format!("Unsupported method '{}'", method),
+                        },
+                    }))?;
+                }
+                return Ok(true);
+            }
+        };
+        self.send(json!({"jsonrpc": "2.0", "id": message["id"], "result": result}))?;
+        Ok(true)
+    }
+}
+
+/// Runs a Language Server Protocol session, reading messages from `input`
+/// and writing to `output`, until the client says to exit
+pub fn serve(input: &mut dyn BufRead, output: &mut dyn Write) -> Result<()> {
+    let mut server = Server {
+        output,
+        documents: HashMap::new(),
+    };
+    while let Some(message) = framing::read(input)? {
+        if !server.handle(&message)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::serve;
+    use crate::framing;
+    use pretty_assertions::assert_eq;
+    use serde_json::{json, Value};
+    use std::io::Cursor;
+
+    const URI: &str = "file:///song.rock";
+
+    const PROGRAM: &str = "Midnight takes your heart
+Give back your heart
+
+My heart is a lovestruck ladykiller
+Put Midnight taking my heart into the night
+Say the night
+";
+
+    // Runs a session with `messages` after the document is opened, giving
+    // back everything the server sent
+    fn session(text: &str, messages: Vec<Value>) -> Vec<Value> {
+        let mut input = Vec::new();
+        let open = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": URI, "languageId": "rockstar", "version": 1, "text": text}},
+        });
+        for (id, mut message) in std::iter::once(open).chain(messages).enumerate() {
+            message["jsonrpc"] = json!("2.0");
+            if message["method"]
+                .as_str()
+                .is_some_and(|m| !m.starts_with("textDocument/did"))
+            {
+                message["id"] = json!(id);
+            }
+            framing::write(&mut input, &message).unwrap();
+        }
+        let mut output = Vec::new();
+        serve(&mut Cursor::new(input), &mut output).unwrap();
+        let mut reader = Cursor::new(output);
+        let mut sent = vec![];
+        while let Some(message) = framing::read(&mut reader).unwrap() {
+            sent.push(message);
+        }
+        sent
+    }
+
+    fn at(method: &str, line: usize, character: usize) -> Value {
+        json!({
+            "method": method,
+            "params": {"textDocument": {"uri": URI}, "position": {"line": line, "character": character}},
+        })
+    }
+
+    fn range(start: (usize, usize), end: (usize, usize)) -> Value {
+        json!({
+            "start": {"line": start.0, "character": start.1},
+            "end": {"line": end.0, "character": end.1},
+        })
+    }
+
+    #[test]
+    fn definitions() {
+        let sent = session(
+            PROGRAM,
+            vec![
+                at("textDocument/definition", 4, 6),
+                at("textDocument/definition", 4, 22),
+                at("textDocument/definition", 5, 6),
+            ],
+        );
+        assert_eq!(sent[0]["params"]["diagnostics"], json!([]));
+        assert_eq!(sent[1]["result"]["range"], range((0, 0), (0, 8)));
+        assert_eq!(sent[2]["result"]["range"], range((3, 0), (3, 8)));
+        assert_eq!(sent[3]["result"]["range"], range((4, 34), (4, 43)));
+    }
+
+    #[test]
+    fn hover_poetic_literal() {
+        let sent = session(PROGRAM, vec![at("textDocument/hover", 3, 16)]);
+        assert_eq!(
+            sent[1]["result"],
+            json!({
+                "contents": {"kind": "markdown", "value": "`100`"},
+                "range": range((3, 12), (3, 35)),
+            })
+        );
+    }
+
+    #[test]
+    fn symbols_and_completion() {
+        let sent = session(
+            PROGRAM,
+            vec![
+                at("textDocument/documentSymbol", 0, 0),
+                at("textDocument/completion", 5, 0),
+            ],
+        );
+        assert_eq!(
+            sent[1]["result"],
+            json!([{
+                "name": "Midnight",
+                "detail": "takes your heart",
+                "kind": 12,
+                "range": range((0, 0), (2, 0)),
+                "selectionRange": range((0, 0), (0, 8)),
+            }])
+        );
+        let labels: Vec<&str> = sent[2]["result"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            labels,
+            vec!["Midnight", "your heart", "My heart", "the night"]
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let change = |text: &str| {
+            json!({
+                "method": "textDocument/didChange",
+                "params": {"textDocument": {"uri": URI, "version": 2}, "contentChanges": [{"text": text}]},
+            })
+        };
+        let sent = session(
+            "My heart is 5\n",
+            vec![
+                change("Put 5 into\n"),
+                at("textDocument/completion", 0, 0),
+                change("Put 5 into my heart\nSay my heart\n"),
+            ],
+        );
+        let diagnostics: Vec<&Value> = sent
+            .iter()
+            .filter(|message| message["method"] == json!("textDocument/publishDiagnostics"))
+            .map(|message| &message["params"]["diagnostics"])
+            .collect();
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics[0][0]["code"], json!("unused-variable"));
+        assert_eq!(diagnostics[0][0]["range"], range((0, 0), (0, 13)));
+        assert_eq!(diagnostics[1][0]["severity"], json!(1));
+        assert_eq!(diagnostics[1][0]["range"]["start"]["line"], json!(0));
+        assert_eq!(diagnostics[2], &json!([]));
+        // Completion still knows about the last version that parsed
+        assert_eq!(sent[2]["result"], json!([{"label": "My heart", "kind": 6}]));
+    }
+}
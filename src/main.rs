@@ -6,49 +6,445 @@ use stdweb::web::IParentNode;
 #[cfg(target_arch = "wasm32")]
 use yew::prelude::*;
 
-mod common;
+#[cfg(not(target_arch = "wasm32"))]
+mod conformance;
+#[cfg(not(target_arch = "wasm32"))]
+mod dap;
+#[cfg(not(target_arch = "wasm32"))]
+mod debugger;
 mod display;
-mod parser;
-mod peg;
-mod runner;
+#[cfg(not(target_arch = "wasm32"))]
+mod formatter;
+#[cfg(not(target_arch = "wasm32"))]
+mod framing;
+#[cfg(not(target_arch = "wasm32"))]
+mod include;
+#[cfg(not(target_arch = "wasm32"))]
+mod lint;
+#[cfg(not(target_arch = "wasm32"))]
+mod lsp;
+#[cfg(not(target_arch = "wasm32"))]
+mod profile;
+#[cfg(not(target_arch = "wasm32"))]
+mod stdlib;
+#[cfg(not(target_arch = "wasm32"))]
+mod trace;
+
+use maiden::{common, parser, runner};
+#[cfg(not(target_arch = "wasm32"))]
+use maiden::{golden, peg, syntax, transpile, value};
 
 #[cfg(not(target_arch = "wasm32"))]
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 #[cfg(not(target_arch = "wasm32"))]
 use std::fs::File;
 #[cfg(not(target_arch = "wasm32"))]
 use std::io::{self, Read};
 
+#[cfg(not(target_arch = "wasm32"))]
+fn read_input(path: &str) -> common::Result<String> {
+    let mut f = File::open(path)?;
+    let mut buffer = String::new();
+    f.read_to_string(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn parse_or_exit(buffer: &str) -> common::Program {
+    match parser::parse(buffer) {
+        Err(err) => {
+            // This hack is in here as the standard Err printing uses Debug, not Display
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        Ok(program) => program,
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn check(matches: &ArgMatches) -> common::Result<()> {
+    let input = matches.value_of("INPUT").unwrap();
+    let buffer = read_input(input)?;
+    let program = parse_or_exit(&buffer);
+    let mut config = lint::LintConfig::default();
+    for name in matches.values_of("allow").into_iter().flatten() {
+        config.allow(lint::LintKind::from_name(name).unwrap());
+    }
+    let warnings = lint::check(&program, &buffer, &config);
+    for warning in &warnings {
+        println!("{}: {}", input, warning);
+    }
+    if !warnings.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn fmt(matches: &ArgMatches) -> common::Result<()> {
+    let input = matches.value_of("INPUT").unwrap();
+    let buffer = read_input(input)?;
+    let (program, tree) = match parser::parse_lossless(&buffer) {
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        Ok(parsed) => parsed,
+    };
+    let options = formatter::FormatOptions {
+        canonical: matches.is_present("canonical"),
+    };
+    let formatted = formatter::format(&program, &tree, &buffer, &options);
+    if formatted == buffer {
+        return Ok(());
+    }
+    // Never write back something that's no longer a program
+    if let Err(err) = parser::parse(&formatted) {
+        eprintln!(
+            "Error: formatting {} gave output that doesn't parse ({}), so left it alone",
+            input, err
+        );
+        std::process::exit(1);
+    }
+    if matches.is_present("check") {
+        println!("{} is not formatted", input);
+        std::process::exit(1);
+    }
+    std::fs::write(input, formatted)?;
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn transpile(matches: &ArgMatches) -> common::Result<()> {
+    let buffer = read_input(matches.value_of("INPUT").unwrap())?;
+    let program = parse_or_exit(&buffer);
+    let target = transpile::Target::from_name(matches.value_of("target").unwrap()).unwrap();
+    print!("{}", transpile::transpile(&program, target)?);
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn debug(matches: &ArgMatches) -> common::Result<()> {
+    let buffer = read_input(matches.value_of("INPUT").unwrap())?;
+    let mut program = parse_or_exit(&buffer);
+    let mut output = io::stdout();
+    let read_line = || {
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(line))
+        }
+    };
+    let mut debugger = debugger::Debugger::new(&buffer, Box::new(read_line), &mut output);
+    if let Some(lines) = matches.values_of("break") {
+        for line in lines {
+            debugger.add_breakpoint(line.parse().unwrap());
+        }
+        debugger.continue_to_breakpoint();
+    }
+    // Buffers no more than it needs, leaving the rest of stdin for the debugger's commands
+    let mut input = io::BufReader::with_capacity(1, io::stdin());
+    runner::run_with_hook(&mut program, &mut input, &mut io::stdout(), &mut debugger)?;
+    Ok(())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn dap() -> common::Result<()> {
+    let stdin = io::stdin();
+    dap::serve(&mut stdin.lock(), &mut io::stdout())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn lsp() -> common::Result<()> {
+    let stdin = io::stdin();
+    lsp::serve(&mut stdin.lock(), &mut io::stdout())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn conformance(matches: &ArgMatches) -> common::Result<()> {
+    let dir = std::path::Path::new(matches.value_of("DIR").unwrap());
+    let skip_file = std::path::Path::new(matches.value_of("skip").unwrap());
+    let skips = if skip_file.exists() {
+        golden::skip_list(skip_file)?
+    } else {
+        Default::default()
+    };
+    // Anything that panics gets reported as a failure, so don't print them as they happen
+    std::panic::set_hook(Box::new(|_| {}));
+    let root = skip_file.parent().unwrap_or(dir);
+    let outcomes = golden::run_fixtures(root, dir, &skips);
+    if outcomes.is_empty() {
+        eprintln!(
+            "No fixtures found in {} (is the spec submodule checked out?)",
+            dir.display()
+        );
+        std::process::exit(1);
+    }
+    print!("{}", conformance::report(&outcomes));
+    Ok(())
+}
+
 #[cfg(not(target_arch = "wasm32"))]
 fn main() -> common::Result<()> {
     pretty_env_logger::try_init().unwrap_or(());
+    let lint_names: Vec<&str> = lint::LintKind::all().iter().map(|k| k.name()).collect();
+    let target_names: Vec<&str> = transpile::Target::all().iter().map(|t| t.name()).collect();
+    let trace_formats: Vec<&str> = trace::TraceFormat::all().iter().map(|f| f.name()).collect();
     let matches = App::new("Maiden")
         .version("1.0")
         .author("Tom Parker <palfrey@tevp.net>")
         .about("Rockstar interpreter")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("INPUT")
                 .help("Sets the input file to use")
-                .required(true)
+                .required_unless("list-builtins")
                 .index(1),
         )
+        .arg(
+            Arg::with_name("allow-include")
+                .help("Lets the program use the functions of other files, with lines like Include \"helpers.rock\"")
+                .long("allow-include"),
+        )
+        .arg(
+            Arg::with_name("stdlib")
+                .help("Lets the program call the built-in functions (see --list-builtins)")
+                .long("stdlib"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .help("Seeds the --stdlib random numbers, so runs can be repeated")
+                .long("seed")
+                .takes_value(true)
+                .value_name("NUMBER")
+                .requires("stdlib")
+                .validator(|seed| {
+                    seed.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| format!("'{}' isn't a whole number", seed))
+                }),
+        )
+        .arg(
+            Arg::with_name("list-builtins")
+                .help("Lists the functions --stdlib provides")
+                .long("list-builtins"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .help("Writes a record of each command run to stderr")
+                .long("trace"),
+        )
+        .arg(
+            Arg::with_name("trace-format")
+                .help("Format for --trace records")
+                .long("trace-format")
+                .takes_value(true)
+                .default_value("text")
+                .possible_values(&trace_formats),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .help("Writes how often each line and function ran, and how long they took, to stderr")
+                .long("profile")
+                .conflicts_with("trace"),
+        )
+        .arg(
+            Arg::with_name("coverage")
+                .help("Writes line and branch coverage to this file in lcov format")
+                .long("coverage")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with("trace"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Reports style warnings for a Rockstar program")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("allow")
+                        .help("Disables a warning")
+                        .long("allow")
+                        .short("A")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .possible_values(&lint_names),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Reformats a Rockstar program in place")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("check")
+                        .help("Exits non-zero instead of reformatting if the file would change")
+                        .long("check"),
+                )
+                .arg(
+                    Arg::with_name("canonical")
+                        .help("Replaces keyword aliases with their canonical forms (e.g. Shout with Say)")
+                        .long("canonical"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("transpile")
+                .about("Converts a Rockstar program into source for another language")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .help("Language to generate")
+                        .long("target")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&target_names),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Runs a Rockstar program under an interactive step debugger")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("break")
+                        .help("Runs until this line rather than stopping at the start")
+                        .long("break")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(|line| {
+                            line.parse::<usize>()
+                                .map(|_| ())
+                                .map_err(|_| format!("'{}' isn't a line number", line))
+                        }),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("conformance")
+                .about("Runs the upstream Rockstar test fixtures and summarises how many pass")
+                .arg(
+                    Arg::with_name("DIR")
+                        .help("Directory of fixtures to run")
+                        .default_value("tests/upstream")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("skip")
+                        .help("List of fixtures that are known to fail, and why")
+                        .long("skip")
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .default_value("tests/skip.toml"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("dap")
+                .about("Runs a Debug Adapter Protocol server over stdin and stdout"),
+        )
+        .subcommand(
+            SubCommand::with_name("lsp")
+                .about("Runs a Language Server Protocol server over stdin and stdout"),
+        )
         .get_matches();
-    let mut f = File::open(matches.value_of("INPUT").unwrap())?;
-    let mut buffer = String::new();
-    f.read_to_string(&mut buffer)?;
-
-    let mut program = match parser::parse(&buffer) {
-        Err(err) => {
-            // This hack is in here as the standard Err printing uses Debug, not Display
-            eprintln!("Error: {}", err);
-            std::process::exit(1);
+    match matches.subcommand() {
+        ("check", Some(matches)) => return check(matches),
+        ("fmt", Some(matches)) => return fmt(matches),
+        ("transpile", Some(matches)) => return transpile(matches),
+        ("debug", Some(matches)) => return debug(matches),
+        ("conformance", Some(matches)) => return conformance(matches),
+        ("dap", Some(_)) => return dap(),
+        ("lsp", Some(_)) => return lsp(),
+        _ => {}
+    }
+    if matches.is_present("list-builtins") {
+        print!("{}", stdlib::list());
+        return Ok(());
+    }
+    let mut runner = runner::Runner::new();
+    if matches.is_present("stdlib") {
+        let seed = match matches.value_of("seed") {
+            Some(seed) => seed.parse().unwrap(),
+            None => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |time| time.as_nanos() as u64),
+        };
+        stdlib::register(&mut runner, seed);
+    }
+    let input = matches.value_of("INPUT").unwrap();
+    let buffer = read_input(input)?;
+    let (mut program, sources) = if matches.is_present("allow-include") {
+        match include::load(std::path::Path::new(input), &buffer) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
         }
-        other => other?,
+    } else {
+        (parse_or_exit(&buffer), include::Sources::default())
     };
-    runner::run(&mut program, &mut io::stdout())?;
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    if matches.is_present("profile") || matches.is_present("coverage") {
+        let mut profiler = profile::Profiler::new();
+        let result =
+            runner.run_with_hook(&mut program, &mut reader, &mut io::stdout(), &mut profiler);
+        // Reported even if the program errored, as that's often when it's most useful
+        if matches.is_present("profile") {
+            eprint!("{}", profiler.report(&buffer, &sources));
+        }
+        if let Some(path) = matches.value_of("coverage") {
+            std::fs::write(path, profiler.lcov(&program, input))?;
+        }
+        locate_error(result, &sources)?;
+    } else if matches.is_present("trace") {
+        let format = trace::TraceFormat::from_name(matches.value_of("trace-format").unwrap());
+        let mut stderr = io::stderr();
+        let mut tracer = trace::Tracer::new(&mut stderr, format.unwrap());
+        let result =
+            runner.run_with_hook(&mut program, &mut reader, &mut io::stdout(), &mut tracer);
+        locate_error(result, &sources)?;
+    } else {
+        locate_error(
+            runner.run(&mut program, &mut reader, &mut io::stdout()),
+            &sources,
+        )?;
+    }
     Ok(())
 }
 
+// Errors on lines of included files get reported with the file and line they
+// came from, rather than the line of the program as a whole
+#[cfg(not(target_arch = "wasm32"))]
+fn locate_error<T>(result: common::Result<T>, sources: &include::Sources) -> common::Result<T> {
+    if let Err(ref err) = result {
+        if let Some((file, line)) = sources.locate(err.line()) {
+            eprintln!("Error: {} ({} line {})", err, file, line);
+            std::process::exit(1);
+        }
+    }
+    result
+}
+
 #[cfg(target_arch = "wasm32")]
 mod web;
 
@@ -67,18 +463,19 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::common::Expression;
+    use crate::value::Value;
     use log::{debug, info};
     use pretty_assertions::assert_eq;
     use std::collections::HashMap;
+    use std::convert::TryFrom;
     use std::io::Cursor;
 
-    fn test_program(code: &str, end_variables: HashMap<String, Expression>, expected_output: &str) {
+    fn test_program(code: &str, end_variables: HashMap<String, Value>, expected_output: &str) {
         pretty_env_logger::try_init().unwrap_or(());
         let mut program = parser::parse(code).unwrap();
         info!("Commands: {:?}", program.commands);
         let mut writer = Cursor::new(Vec::new());
-        let variables = runner::run(&mut program, &mut writer)
+        let variables = runner::run(&mut program, &mut io::empty(), &mut writer)
             .unwrap()
             .drain()
             .map(|(k, v)| (k, v.1))
@@ -115,10 +512,10 @@ mod tests {
         Build Counter up
     ";
         let end_variables = hashmap! {
-            "buzz" => Expression::Floating(5f64),
-            "limit" => Expression::Floating(100f64),
-            "counter" => Expression::Floating(100f64),
-            "fizz" => Expression::Floating(3f64),
+            "buzz" => Value::Number(5f64),
+            "limit" => Value::Number(100f64),
+            "counter" => Value::Number(100f64),
+            "fizz" => Value::Number(3f64),
         };
         test_program(program, end_variables, "");
     }
@@ -133,10 +530,10 @@ mod tests {
     Build my world up
     ";
         let end_variables = hashmap! {
-            "my world" => Expression::Floating(100f64),
-            "fire" => Expression::Floating(3f64),
-            "hate" => Expression::Floating(5f64),
-            "desire" => Expression::Floating(100f64),
+            "my world" => Value::Number(100f64),
+            "fire" => Value::Number(3f64),
+            "hate" => Value::Number(5f64),
+            "desire" => Value::Number(100f64),
         };
         test_program(program, end_variables, "");
     }
@@ -170,10 +567,10 @@ mod tests {
     Whisper my world
     ";
         let end_variables = hashmap! {
-            "my world" => Expression::Floating(100f64),
-            "fire" => Expression::Floating(3f64),
-            "hate" => Expression::Floating(5f64),
-            "desire" => Expression::Floating(100f64),
+            "my world" => Value::Number(100f64),
+            "fire" => Value::Number(3f64),
+            "hate" => Value::Number(5f64),
+            "desire" => Value::Number(100f64),
         };
         test_program(
             program,
@@ -202,7 +599,7 @@ mod tests {
     #[test]
     fn multiple_uppercase_proper_variable() {
         let end_variables = hashmap! {
-            "id" => Expression::Floating(3f64),
+            "id" => Value::Number(3f64),
         };
         test_program("put 3 into ID", end_variables, "");
     }
@@ -210,7 +607,7 @@ mod tests {
     #[test]
     fn double_increment() {
         let end_variables = hashmap! {
-            "my world" => Expression::Floating(2f64),
+            "my world" => Value::Number(2f64),
         };
         test_program(
             "Put 0 into my world\nBuild my world up, up",
@@ -222,7 +619,7 @@ mod tests {
     #[test]
     fn double_decrement() {
         let end_variables = hashmap! {
-            "the walls" => Expression::Floating(-2f64),
+            "the walls" => Value::Number(-2f64),
         };
         test_program(
             "Put 0 into the walls\nKnock the walls down, down",
@@ -234,7 +631,7 @@ mod tests {
     #[test]
     fn skip_else() {
         let end_variables = hashmap! {
-            "foo" => Expression::String("foo".to_string()),
+            "foo" => Value::String("foo".to_string()),
         };
         test_program(
             "if nothing is nothing
@@ -263,7 +660,9 @@ mod tests {
         pretty_env_logger::try_init().unwrap_or(());
         let mut program = parser::parse(input).unwrap();
         let mut writer = Cursor::new(Vec::new());
-        runner::run(&mut program, &mut writer).err().unwrap()
+        runner::run(&mut program, &mut io::empty(), &mut writer)
+            .err()
+            .unwrap()
     }
 
     #[test]
@@ -276,4 +675,52 @@ mod tests {
             assert!(false, err);
         }
     }
+    fn run_with_natives(input: &str) -> common::Result<String> {
+        let mut runner = runner::Runner::new();
+        runner.register_fn("Multiply", 2, |args| {
+            let x = f64::try_from(args[0].clone())?;
+            let y = f64::try_from(args[1].clone())?;
+            Ok(Value::from(x * y))
+        });
+        let mut program = parser::parse(input).unwrap();
+        let mut writer = Cursor::new(Vec::new());
+        runner.run(&mut program, &mut io::empty(), &mut writer)?;
+        Ok(String::from_utf8(writer.into_inner()).unwrap())
+    }
+
+    #[test]
+    fn native_functions() {
+        let output = run_with_natives("Say Multiply taking 3, 5\nSay multiply taking 2, 0.5\n");
+        assert_eq!(output.unwrap(), "15\n1\n");
+        // Functions declared in the program win
+        let output =
+            run_with_natives("Multiply takes x, y\nGive back x\n\nSay Multiply taking 3, 5\n");
+        assert_eq!(output.unwrap(), "3\n");
+    }
+
+    #[test]
+    fn native_function_errors() {
+        match run_with_natives("Say Multiply taking 3") {
+            Err(common::MaidenError::WrongArgCount {
+                expected,
+                got,
+                line,
+            }) => {
+                assert_eq!((expected, got, line), (2, 1, 1));
+            }
+            other => panic!("Expected WrongArgCount, got {:?}", other),
+        }
+        match run_with_natives("\nSay Multiply taking \"3\", 5") {
+            Err(common::MaidenError::Native {
+                name,
+                description,
+                line,
+            }) => {
+                assert_eq!(name, "Multiply");
+                assert_eq!(description, "Expected a number value, got a string");
+                assert_eq!(line, 2);
+            }
+            other => panic!("Expected a Native error, got {:?}", other),
+        }
+    }
 }
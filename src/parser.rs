@@ -1,7 +1,9 @@
 use crate::common::{
-    Block, Command, CommandLine, Expression, MaidenError, Program, Result, SymbolType,
+    Block, Command, CommandLine, Expression, Function, MaidenError, Program, Result, SymbolType,
 };
 use crate::peg::{Rockstar, Rule};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::syntax::{self, SyntaxNode};
 use log::debug;
 use pest::iterators::Pair;
 use pest::Parser;
@@ -48,13 +50,17 @@ impl Item {
         }
     }
     fn block(self) -> Result<Block> {
-        if let Item::Block(e) = self {
-            Ok(e)
-        } else {
-            Err(MaidenError::NotABlock {
+        match self {
+            Item::Block(e) => Ok(e),
+            // Single statements, like the ones after `If <expression>` or `Else`
+            // on the same line, including an `Else If` chain
+            Item::Command(command) => Ok(Block {
+                commands: vec![command],
+            }),
+            _ => Err(MaidenError::NotABlock {
                 other: format!("{:?}", self),
                 line: 0,
-            })
+            }),
         }
     }
 }
@@ -116,9 +122,23 @@ where
             }
         }
     }
+    // Top-level functions can be called from anywhere, including before they're declared
+    let functions = commands
+        .iter()
+        .filter_map(|command| match &command.cmd {
+            Command::FunctionDeclaration { name, args, block } => Some((
+                name.to_lowercase(),
+                Function {
+                    args: args.clone(),
+                    block: block.clone(),
+                },
+            )),
+            _ => None,
+        })
+        .collect();
     Ok(Program {
         commands,
-        functions: HashMap::new(),
+        functions,
     })
 }
 
@@ -154,6 +174,70 @@ fn pair_to_command_line(pair: &Pair<Rule>, command: Command) -> Item {
     .into()
 }
 
+// Decodes the escapes in the inside of a string literal
+fn unescape(value: &str, line: usize) -> Result<String> {
+    let mut res = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            res.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('"') => res.push('"'),
+            Some('\\') => res.push('\\'),
+            Some('n') => res.push('\n'),
+            Some('t') => res.push('\t'),
+            Some('u') => {
+                let hex: String = chars.clone().take(4).collect();
+                let decoded = if hex.len() == 4 && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(std::char::from_u32)
+                } else {
+                    None
+                };
+                match decoded {
+                    Some(decoded) => {
+                        res.push(decoded);
+                        chars.nth(3);
+                    }
+                    None => {
+                        return Err(MaidenError::BadEscape {
+                            sequence: format!("\\u{}", hex),
+                            line,
+                        })
+                    }
+                }
+            }
+            other => {
+                return Err(MaidenError::BadEscape {
+                    sequence: format!("\\{}", other.map_or(String::new(), |c| c.to_string())),
+                    line,
+                })
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// `value` as a string literal, with the escapes needed to parse back to it
+pub fn escape(value: &str) -> String {
+    let mut res = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
 fn remove<T>(items: &mut Vec<T>, index: usize, line: usize) -> Result<T> {
     if items.len() > index {
         Ok(items.remove(index))
@@ -193,7 +277,7 @@ fn depair_core(pair: Pair<'_, Rule>, level: usize) -> Result<Item> {
                 });
             }
             value = &value[1..value.len() - 1];
-            Expression::String(value.to_string()).into()
+            Expression::String(unescape(value, line)?).into()
         }
         Rule::number => {
             let value = pair.as_str();
@@ -536,7 +620,9 @@ fn depair_core(pair: Pair<'_, Rule>, level: usize) -> Result<Item> {
                 if number > 0.0 {
                     number *= 10.0;
                 }
-                number += (word.len() % 10) as f64;
+                // Letters rather than bytes, as the lint counts them. The grammar
+                // only lets ASCII letters through for now, where they're the same.
+                number += (word.chars().count() % 10) as f64;
                 if decimal {
                     decimal_places += 1;
                 }
@@ -917,13 +1003,42 @@ pub fn parse(buffer: &str) -> Result<Program> {
     return depair_program(&mut parsed, &buffer);
 }
 
+/// Parses a single expression, e.g. a watch expression from a debugger
+#[cfg(not(target_arch = "wasm32"))]
+pub fn parse_expression(text: &str) -> Result<Expression> {
+    let text = text.trim();
+    let mut parsed =
+        Rockstar::parse(Rule::expression, text).map_err(|e| MaidenError::Pest { kind: e })?;
+    let consumed = parsed
+        .clone()
+        .map(|pair| pair.as_str().len())
+        .sum::<usize>();
+    if consumed != text.len() {
+        return Err(MaidenError::NotAnExpression {
+            other: text.to_string(),
+            line: 0,
+        });
+    }
+    depair(&mut parsed, 0)?.expr()
+}
+
+/// As `parse`, but also returns the lossless syntax tree the program was built from
+#[cfg(not(target_arch = "wasm32"))]
+pub fn parse_lossless(buffer: &str) -> Result<(Program, SyntaxNode)> {
+    let parsed =
+        Rockstar::parse(Rule::program, &buffer).map_err(|e| MaidenError::Pest { kind: e })?;
+    let tree = syntax::build(parsed.clone().next().expect("one pair"), buffer);
+    let program = depair_program(&mut parsed.into_iter(), &buffer)?;
+    return Ok((program, tree));
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
 
     use crate::common::{Command, CommandLine, Expression, Program};
 
-    use super::{parse, MaidenError};
+    use super::{escape, parse, parse_expression, MaidenError};
 
     #[test]
     fn end_of_if() {
@@ -952,4 +1067,67 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn single_expression() {
+        assert_eq!(
+            parse_expression("my world plus 2").unwrap(),
+            Expression::Add(
+                Box::new(Expression::Variable("my world".to_string())),
+                Box::new(Expression::Floating(2.0))
+            )
+        );
+        assert!(parse_expression("my world plus").is_err());
+    }
+
+    #[test]
+    fn hoists_top_level_functions() {
+        let program = parse(
+            "Say Midnight taking 1
+
+Midnight takes your heart
+Desire takes my soul
+Give back my soul
+
+Give back your heart
+",
+        )
+        .unwrap();
+        let names: Vec<&String> = program.functions.keys().collect();
+        // Only the top-level one, as Desire is declared inside Midnight
+        assert_eq!(names, vec!["midnight"]);
+        assert_eq!(program.functions["midnight"].args, vec!["your heart"]);
+    }
+
+    #[test]
+    fn string_escapes() {
+        let program = parse("Say \"a \\\"quote\\\", \\\\, \\n, \\t and \\u00e9\"\n").unwrap();
+        assert_eq!(
+            program.commands[0].cmd,
+            Command::Say {
+                value: Expression::String("a \"quote\", \\, \n, \t and \u{e9}".to_string())
+            }
+        );
+        assert_eq!(
+            escape("a \"quote\", \\, \n, \t and \u{e9}\u{7}"),
+            "\"a \\\"quote\\\", \\\\, \\n, \\t and \u{e9}\\u0007\""
+        );
+    }
+
+    #[test]
+    fn bad_string_escapes() {
+        for (code, sequence) in &[
+            ("Say \"\\q\"", "\\q"),
+            ("Say \"\\u12\"", "\\u12"),
+            ("Say \"\\ud800\"", "\\ud800"),
+        ] {
+            match parse(code) {
+                Err(MaidenError::BadEscape {
+                    sequence: ref found,
+                    line: 1,
+                }) => assert_eq!(found, sequence),
+                other => panic!("{:?} gave {:?}", code, other),
+            }
+        }
+    }
 }
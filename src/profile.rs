@@ -0,0 +1,280 @@
+use crate::common::{Command, Program, Result};
+use crate::include::Sources;
+use crate::lint::for_each_command;
+use crate::runner::{Hook, Snapshot};
+use crate::value::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LineStats {
+    pub hits: u64,
+    /// Time spent in the line's own command, excluding any blocks or
+    /// function calls it ran
+    pub time: Duration,
+    /// How often an If/While/Until condition on this line was true and false
+    pub branches: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FunctionStats {
+    pub calls: u64,
+    /// Time spent inside the function, including anything it called
+    pub time: Duration,
+}
+
+struct Running {
+    line: usize,
+    started: Instant,
+    nested: Duration,
+}
+
+/// `Hook` that counts how often each line and function runs, and how long they take
+#[derive(Default)]
+pub struct Profiler {
+    pub lines: BTreeMap<usize, LineStats>,
+    pub functions: BTreeMap<String, FunctionStats>,
+    running: Vec<Running>,
+    calls: Vec<(String, Instant)>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Default::default()
+    }
+
+    /// Human readable report, slowest lines first. `source` is the main
+    /// file's, and lines of included files are shown as `file:line`.
+    pub fn report(&self, source: &str, sources: &Sources) -> String {
+        let mut lines: Vec<(&usize, &LineStats)> = self.lines.iter().collect();
+        lines.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+        let mut report = format!("{:>6} {:>8} {:>12}  source\n", "line", "hits", "time");
+        for (line, stats) in lines {
+            let location = match sources.locate(*line) {
+                Some((file, line)) => format!("{}:{}", file, line),
+                None => line.to_string(),
+            };
+            report += &format!(
+                "{:>6} {:>8} {:>12}  {}\n",
+                location,
+                stats.hits,
+                format!("{:?}", stats.time),
+                sources.line(source, *line).map_or("", |text| text.trim())
+            );
+        }
+        if !self.functions.is_empty() {
+            let mut functions: Vec<(&String, &FunctionStats)> = self.functions.iter().collect();
+            functions.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(b.0)));
+            report += &format!("\n{:>8} {:>12}  function\n", "calls", "time");
+            for (name, stats) in functions {
+                report += &format!(
+                    "{:>8} {:>12}  {}\n",
+                    stats.calls,
+                    format!("{:?}", stats.time),
+                    name
+                );
+            }
+        }
+        report
+    }
+
+    /// Coverage in lcov's tracefile format. `program` should be the one that
+    /// was run, so that lines which never ran still get listed.
+    pub fn lcov(&self, program: &Program, path: &str) -> String {
+        let mut lines = BTreeSet::new();
+        let mut conditions = BTreeSet::new();
+        for_each_command(&program.commands, true, &mut |command| {
+            lines.insert(command.line);
+            match command.cmd {
+                Command::If { .. } | Command::While { .. } | Command::Until { .. } => {
+                    conditions.insert(command.line);
+                }
+                _ => {}
+            }
+        });
+        let hits = |line: &usize| self.lines.get(line).map_or(0, |stats| stats.hits);
+
+        let mut lcov = format!("TN:\nSF:{}\n", path);
+        let mut branches_hit = 0;
+        for line in &conditions {
+            let taken = self.lines.get(line).and_then(|stats| stats.branches);
+            for (branch, count) in [taken.map(|t| t.0), taken.map(|t| t.1)].iter().enumerate() {
+                match count {
+                    Some(count) if hits(line) > 0 => {
+                        if *count > 0 {
+                            branches_hit += 1;
+                        }
+                        lcov += &format!("BRDA:{},0,{},{}\n", line, branch, count);
+                    }
+                    _ => {
+                        lcov += &format!("BRDA:{},0,{},-\n", line, branch);
+                    }
+                }
+            }
+        }
+        lcov += &format!("BRF:{}\nBRH:{}\n", conditions.len() * 2, branches_hit);
+        for line in &lines {
+            lcov += &format!("DA:{},{}\n", line, hits(line));
+        }
+        let lines_hit = lines.iter().filter(|line| hits(line) > 0).count();
+        lcov += &format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), lines_hit);
+        lcov
+    }
+}
+
+impl Hook for Profiler {
+    fn command(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.lines.entry(snapshot.line).or_default().hits += 1;
+        self.running.push(Running {
+            line: snapshot.line,
+            started: Instant::now(),
+            nested: Duration::default(),
+        });
+        Ok(())
+    }
+
+    fn command_done(&mut self, _snapshot: &Snapshot) {
+        if let Some(running) = self.running.pop() {
+            let elapsed = running.started.elapsed();
+            self.lines.entry(running.line).or_default().time += elapsed - running.nested;
+            if let Some(parent) = self.running.last_mut() {
+                parent.nested += elapsed;
+            }
+        }
+    }
+
+    fn condition(&mut self, line: usize, _value: &Value, truthy: bool) {
+        let branches = self
+            .lines
+            .entry(line)
+            .or_default()
+            .branches
+            .get_or_insert((0, 0));
+        if truthy {
+            branches.0 += 1;
+        } else {
+            branches.1 += 1;
+        }
+    }
+
+    fn enter_function(&mut self, name: &str, _args: &[Value]) {
+        self.calls.push((name.to_string(), Instant::now()));
+    }
+
+    fn exit_function(&mut self, _name: &str) {
+        if let Some((name, started)) = self.calls.pop() {
+            let stats = self.functions.entry(name).or_default();
+            stats.calls += 1;
+            stats.time += started.elapsed();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profiler;
+    use crate::include;
+    use crate::{parser, runner};
+    use pretty_assertions::assert_eq;
+    use std::fs;
+    use std::io::{self, Cursor};
+
+    const PROGRAM: &str = "Midnight takes your heart
+If your heart is nothing
+Give back 0
+Else
+Give back your heart
+
+My world is 1
+Until my world is 3
+Put Midnight taking my world into your love
+Build my world up
+
+If my world is 5
+Say my world
+";
+
+    fn profile() -> Profiler {
+        let mut program = parser::parse(PROGRAM).unwrap();
+        let mut profiler = Profiler::new();
+        let mut writer = Cursor::new(Vec::new());
+        runner::run_with_hook(&mut program, &mut io::empty(), &mut writer, &mut profiler).unwrap();
+        profiler
+    }
+
+    #[test]
+    fn counts() {
+        let profiler = profile();
+        let hits: Vec<(usize, u64)> = profiler
+            .lines
+            .iter()
+            .map(|(line, stats)| (*line, stats.hits))
+            .collect();
+        assert_eq!(
+            hits,
+            vec![
+                (1, 1),
+                (2, 2),
+                (5, 2),
+                (7, 1),
+                (8, 1),
+                (9, 2),
+                (10, 2),
+                (12, 1)
+            ]
+        );
+        assert_eq!(profiler.lines[&8].branches, Some((1, 2)));
+        assert_eq!(profiler.functions["Midnight"].calls, 2);
+    }
+
+    #[test]
+    fn report_shows_included_lines() {
+        let dir = std::env::temp_dir().join("maiden-profile");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.rock");
+        let source = "Include \"lib.rock\"\nSay Double taking 2\n";
+        fs::write(&path, source).unwrap();
+        fs::write(dir.join("lib.rock"), "Double takes x\nGive back x with x\n").unwrap();
+        let (mut program, sources) = include::load(&path, source).unwrap();
+        let mut profiler = Profiler::new();
+        let mut writer = Cursor::new(Vec::new());
+        runner::run_with_hook(&mut program, &mut io::empty(), &mut writer, &mut profiler).unwrap();
+        let report = profiler.report(source, &sources);
+        assert!(report.contains("  Say Double taking 2\n"), "{}", report);
+        assert!(report.contains("lib.rock:2"), "{}", report);
+        assert!(report.contains("  Give back x with x\n"), "{}", report);
+    }
+
+    #[test]
+    fn lcov() {
+        let profiler = profile();
+        let program = parser::parse(PROGRAM).unwrap();
+        assert_eq!(
+            profiler.lcov(&program, "test.rock"),
+            "TN:
+SF:test.rock
+BRDA:2,0,0,0
+BRDA:2,0,1,2
+BRDA:8,0,0,1
+BRDA:8,0,1,2
+BRDA:12,0,0,0
+BRDA:12,0,1,1
+BRF:6
+BRH:4
+DA:1,1
+DA:2,2
+DA:3,0
+DA:5,2
+DA:7,1
+DA:8,1
+DA:9,2
+DA:10,2
+DA:12,1
+DA:13,0
+LF:10
+LH:8
+end_of_record
+"
+        );
+    }
+}
@@ -1,7 +1,8 @@
 use crate::common::*;
+use crate::value::{Closure, Value};
 use log::debug;
 use std::collections::{BTreeMap, HashMap};
-use std::io::{self, Write};
+use std::io::{BufRead, Write};
 use std::ops::Deref;
 use std::str::FromStr;
 
@@ -11,51 +12,114 @@ pub enum VariableType {
     Local,
 }
 
+/// What a `Hook` can see of the running program around each command
+pub struct Snapshot<'a> {
+    pub line: usize,
+    pub command: &'a Command,
+    pub depth: u32,
+    pub variables: &'a HashMap<String, (VariableType, Value)>,
+    pub pronoun: Option<&'a str>,
+    pub functions: &'a HashMap<String, Function>,
+    pub natives: &'a HashMap<String, NativeFunction>,
+}
+
+/// A function written in Rust that programs can call like one of their own
+pub struct NativeFunction {
+    pub arity: usize,
+    function: Box<dyn Fn(&[Value]) -> Result<Value>>,
+}
+
+/// Runs programs, with any native functions that have been registered
+#[derive(Default)]
+pub struct Runner {
+    natives: HashMap<String, NativeFunction>,
+}
+
+/// Gets told about progress through a program, e.g. for a debugger
+pub trait Hook {
+    /// Called before each command is run
+    fn command(&mut self, snapshot: &Snapshot) -> Result<()>;
+    /// Called once a command has finished, unless it errored
+    fn command_done(&mut self, _snapshot: &Snapshot) {}
+    /// Called with the value of each If/While/Until condition, and whether
+    /// it counted as true
+    fn condition(&mut self, _line: usize, _value: &Value, _truthy: bool) {}
+    /// Called once a function's arguments have been evaluated, before its body runs
+    fn enter_function(&mut self, name: &str, args: &[Value]);
+    /// Called after a function has returned (or errored)
+    fn exit_function(&mut self, name: &str);
+}
+
+struct NoHook;
+
+impl Hook for NoHook {
+    fn command(&mut self, _snapshot: &Snapshot) -> Result<()> {
+        Ok(())
+    }
+    fn enter_function(&mut self, _name: &str, _args: &[Value]) {}
+    fn exit_function(&mut self, _name: &str) {}
+}
+
+// Not a function, as the hook gets borrowed mutably alongside the rest of the state
+macro_rules! snapshot {
+    ($state:expr, $program:expr, $command_line:expr) => {
+        Snapshot {
+            line: $command_line.line,
+            command: &$command_line.cmd,
+            depth: $state.depth,
+            variables: $state.variables,
+            pronoun: $state.pronoun.as_deref(),
+            functions: &$program.functions,
+            natives: $state.natives,
+        }
+    };
+}
+
 struct State<'a> {
+    reader: &'a mut dyn BufRead,
     writer: &'a mut dyn Write,
-    variables: &'a mut HashMap<String, (VariableType, Expression)>,
+    hook: &'a mut dyn Hook,
+    natives: &'a HashMap<String, NativeFunction>,
+    variables: &'a mut HashMap<String, (VariableType, Value)>,
     current_line: usize,
     depth: u32,
     pronoun: Option<String>,
 }
 
+// How running a block of commands finished
+#[derive(Debug, PartialEq)]
+enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(Value),
+}
+
 fn run_binop(
     state: &mut State,
     program: &Program,
     first: &Expression,
     second: &Expression,
-    f: fn(&State, &Expression, &Expression) -> Result<bool>,
-) -> Result<Expression> {
+    f: fn(&State, &Value, &Value) -> Result<bool>,
+) -> Result<Value> {
     let res = run_binop_shortcut(state, program, first, second, None, false, f);
-    if res? {
-        Ok(Expression::True)
-    } else {
-        Ok(Expression::False)
-    }
+    Ok(Value::Boolean(res?))
 }
 
-fn expression_to_number(inp: Expression, line: usize) -> Result<Expression> {
-    debug!("x to number: {:?}", inp);
-    return match inp {
-        Expression::Floating(_) => Ok(inp),
-        Expression::Null => Ok(Expression::Floating(0.0)),
-        Expression::String(ref s) => {
-            let as_float = s.parse::<f64>();
-            if let Ok(float) = as_float {
-                return Ok(Expression::Floating(float));
-            } else {
-                return Err(MaidenError::ParseNumberError {
-                    number: s.to_string(),
-                    line,
-                });
-            }
-        }
-        _ => {
-            return Err(MaidenError::Unimplemented {
-                description: format!("Can't convert {:?} to number", inp),
+fn to_number(value: &Value, line: usize) -> Result<f64> {
+    debug!("x to number: {:?}", value);
+    return match value.to_number() {
+        Some(number) => Ok(number),
+        None => match value {
+            Value::String(ref s) => Err(MaidenError::ParseNumberError {
+                number: s.to_string(),
                 line,
-            });
-        }
+            }),
+            _ => Err(MaidenError::Unimplemented {
+                description: format!("Can't convert {:?} to number", value),
+                line,
+            }),
+        },
     };
 }
 
@@ -64,9 +128,9 @@ fn run_binop_shortcut(
     program: &Program,
     first: &Expression,
     second: &Expression,
-    shortcut: Option<Expression>,
+    shortcut: Option<Value>,
     shortcut_return: bool,
-    f: fn(&State, &Expression, &Expression) -> Result<bool>,
+    f: fn(&State, &Value, &Value) -> Result<bool>,
 ) -> Result<bool> {
     let res_first = run_expression(state, program, first.deref())?;
     if shortcut.is_some() && shortcut.unwrap() == res_first {
@@ -77,31 +141,27 @@ fn run_binop_shortcut(
 
     // Check for same types comparison first
     match res_first {
-        Expression::True | Expression::False => match res_second {
-            Expression::True | Expression::False => {
+        Value::Boolean(_) => match res_second {
+            Value::Boolean(_) => {
                 return Ok(f(state, &res_first, &res_second)?);
             }
             _ => {
                 let val = to_boolean(state, &res_second);
                 if let Ok(b) = val {
-                    if b {
-                        return Ok(f(state, &res_first, &Expression::True)?);
-                    } else {
-                        return Ok(f(state, &res_first, &Expression::False)?);
-                    }
+                    return Ok(f(state, &res_first, &Value::Boolean(b))?);
                 }
             }
         },
-        Expression::String(_) => {
-            if let Expression::String(_) = res_second {
+        Value::String(_) => {
+            if let Value::String(_) = res_second {
                 return Ok(f(state, &res_first, &res_second)?);
             }
-            if Expression::Null == res_second {
+            if Value::Null == res_second {
                 return Ok(false);
             }
         }
-        Expression::Mysterious => {
-            if let Expression::Mysterious = res_second {
+        Value::Mysterious => {
+            if let Value::Mysterious = res_second {
                 return Ok(true);
             } else {
                 return Ok(false);
@@ -109,20 +169,13 @@ fn run_binop_shortcut(
         }
         _ => {}
     }
-    match res_second {
-        Expression::True | Expression::False => {
-            let val = to_boolean(state, &res_first);
-            if let Ok(b) = val {
-                if b {
-                    return Ok(f(state, &Expression::True, &res_second)?);
-                } else {
-                    return Ok(f(state, &Expression::False, &res_second)?);
-                }
-            }
+    if let Value::Boolean(_) = res_second {
+        let val = to_boolean(state, &res_first);
+        if let Ok(b) = val {
+            return Ok(f(state, &Value::Boolean(b), &res_second)?);
         }
-        _ => {}
     }
-    let converted_second = expression_to_number(res_second, state.current_line);
+    let converted_second = to_number(&res_second, state.current_line);
     if converted_second.is_err() {
         return Ok(false);
     }
@@ -130,8 +183,8 @@ fn run_binop_shortcut(
     // Try numeric conversion instead
     return Ok(f(
         state,
-        &expression_to_number(res_first, state.current_line)?,
-        &converted_second?,
+        &Value::Number(to_number(&res_first, state.current_line)?),
+        &Value::Number(converted_second?),
     )?);
 }
 
@@ -142,79 +195,79 @@ fn run_mathbinop(
     second: &Expression,
     op: &Expression,
     f: fn(f64, f64) -> f64,
-) -> Result<Expression> {
+) -> Result<Value> {
     let res_first = run_expression(state, program, first.deref())?;
     let res_second = run_expression(state, program, second.deref())?;
     match res_first {
-        Expression::Floating(ref i) => {
+        Value::Number(ref i) => {
             let first_value = *i;
             match res_second {
-                Expression::Floating(ref i) => {
+                Value::Number(ref i) => {
                     let second_value = *i;
-                    return Ok(Expression::Floating(f(first_value, second_value)));
+                    return Ok(Value::Number(f(first_value, second_value)));
                 }
-                Expression::String(ref s_s) => match op {
+                Value::String(ref s_s) => match op {
                     Expression::Add(_, _) => {
-                        return Ok(Expression::String(format!("{}{}", first_value, s_s)));
+                        return Ok(Value::String(format!("{}{}", first_value, s_s)));
                     }
                     Expression::Times(_, _) => {
-                        return Ok(Expression::String(s_s.repeat(first_value as usize)));
+                        return Ok(Value::String(s_s.repeat(first_value as usize)));
                     }
                     _ => {}
                 },
-                Expression::Null => {
-                    return Ok(Expression::Floating(f(first_value, 0f64)));
+                Value::Null => {
+                    return Ok(Value::Number(f(first_value, 0f64)));
                 }
                 _ => {}
             };
         }
-        Expression::String(ref s_f) => match op {
+        Value::String(ref s_f) => match op {
             Expression::Add(_, _) => match res_second {
-                Expression::String(ref s_s) => {
-                    return Ok(Expression::String(s_f.clone() + s_s));
+                Value::String(ref s_s) => {
+                    return Ok(Value::String(s_f.clone() + s_s));
                 }
                 _ => {
                     let printed_second = get_printable(&res_second, state);
                     if let Ok(p_s) = printed_second {
-                        return Ok(Expression::String(format!("{}{}", s_f, p_s)));
+                        return Ok(Value::String(format!("{}{}", s_f, p_s)));
                     }
                 }
             },
             Expression::Times(_, _) => match res_second {
-                Expression::Floating(ref i) => {
+                Value::Number(ref i) => {
                     let second_value = *i;
-                    return Ok(Expression::String(s_f.repeat(second_value as usize)));
+                    return Ok(Value::String(s_f.repeat(second_value as usize)));
                 }
-                Expression::Null => {
-                    return Ok(Expression::String("".to_string()));
+                Value::Null => {
+                    return Ok(Value::String("".to_string()));
                 }
                 _ => {}
             },
             _ => {}
         },
-        Expression::Null => {
+        Value::Null => {
             match res_second {
-                Expression::Floating(ref i) => {
+                Value::Number(ref i) => {
                     let second_value = *i;
-                    return Ok(Expression::Floating(f(0f64, second_value)));
+                    return Ok(Value::Number(f(0f64, second_value)));
                 }
-                Expression::String(ref s_s) => {
+                Value::String(ref s_s) => {
                     if let Expression::Add(_, _) = op {
-                        return Ok(Expression::String(format!("null{}", s_s)));
+                        return Ok(Value::String(format!("null{}", s_s)));
                     }
                 }
-                Expression::Null => {
-                    return Ok(Expression::Floating(f(0f64, 0f64)));
+                Value::Null => {
+                    return Ok(Value::Number(f(0f64, 0f64)));
                 }
                 _ => {}
             };
         }
         _ => {
             if let Expression::Add(_, _) = op {
-                if let Expression::String(ref s_s) = res_second {
+                if let Value::String(ref s_s) = res_second {
                     let printed_first = get_printable(&res_first, state);
                     if let Ok(p_f) = printed_first {
-                        return Ok(Expression::String(format!("{}{}", p_f, s_s)));
+                        return Ok(Value::String(format!("{}{}", p_f, s_s)));
                     }
                 }
             }
@@ -229,25 +282,13 @@ fn run_mathbinop(
     });
 }
 
-fn to_boolean(state: &State, expression: &Expression) -> Result<bool> {
-    return match *expression {
-        Expression::False | Expression::Mysterious | Expression::Null => Ok(false),
-        Expression::True => Ok(true),
-        Expression::Floating(ref val) => {
-            if *val == 0f64 {
-                Ok(false)
-            } else {
-                Ok(true)
-            }
-        }
-        Expression::String(ref val) => Ok(!matches!(val.to_lowercase().as_str(), "")),
-        Expression::Object(_) => Ok(true),
-        _ => {
-            return Err(MaidenError::BadBooleanResolve {
-                expression: format!("{:?}", expression),
-                line: state.current_line,
-            });
-        }
+fn to_boolean(state: &State, value: &Value) -> Result<bool> {
+    return match value.to_boolean() {
+        Some(b) => Ok(b),
+        None => Err(MaidenError::BadBooleanResolve {
+            expression: format!("{:?}", value),
+            line: state.current_line,
+        }),
     };
 }
 
@@ -256,15 +297,28 @@ fn call_function(
     program: &Program,
     target: &str,
     args: &[Expression],
-) -> Result<Expression> {
-    let func_wrap = program.functions.get(target);
-    if func_wrap.is_none() {
-        return Err(MaidenError::MissingFunction {
-            name: target.to_string(),
-            line: state.current_line,
-        });
-    }
-    let func = func_wrap.unwrap();
+) -> Result<Value> {
+    // Variables holding functions take precedence over declared functions
+    let closure = match state.variables.get(&target.to_lowercase()) {
+        Some((_, Value::Function(closure))) => Some(closure.deref().clone()),
+        _ => None,
+    };
+    let natives = state.natives;
+    let func = match closure {
+        Some(ref closure) => &closure.function,
+        None => match program.functions.get(&target.to_lowercase()) {
+            Some(func) => func,
+            None => match natives.get(&target.to_lowercase()) {
+                Some(native) => return call_native(state, program, target, native, args),
+                None => {
+                    return Err(MaidenError::MissingFunction {
+                        name: target.to_string(),
+                        line: state.current_line,
+                    });
+                }
+            },
+        },
+    };
     if args.len() != func.args.len() {
         return Err(MaidenError::WrongArgCount {
             expected: func.args.len(),
@@ -273,7 +327,26 @@ fn call_function(
         });
     }
 
+    // In the caller's state, so the pronoun still means what it did there
+    let mut values = Vec::with_capacity(args.len());
+    for arg in args {
+        values.push(run_expression(state, program, arg)?);
+    }
+
     let mut new_variables = state.variables.clone();
+    let mut functions = program.functions.clone();
+    if let Some(ref closure) = closure {
+        for (name, value) in &closure.captured {
+            new_variables.insert(name.clone(), (VariableType::Local, value.clone()));
+        }
+        // So it can still call itself from outside where it was declared
+        functions
+            .entry(closure.name.to_lowercase())
+            .or_insert_with(|| closure.function.clone());
+    }
+    for (name, value) in func.args.iter().zip(&values) {
+        new_variables.insert(name.to_lowercase(), (VariableType::Local, value.clone()));
+    }
     if state.depth == 100 {
         return Err(MaidenError::StackOverflow {
             depth: state.depth,
@@ -281,27 +354,25 @@ fn call_function(
         });
     }
     let mut new_state = State {
+        reader: state.reader,
         writer: state.writer,
+        hook: state.hook,
+        natives: state.natives,
         variables: &mut new_variables,
         current_line: state.current_line,
         depth: state.depth + 1,
         pronoun: None,
     };
-    for (i, arg) in args.iter().enumerate() {
-        let value = run_expression(&mut new_state, program, &arg)?;
-        new_state
-            .variables
-            .insert(func.args[i].to_lowercase(), (VariableType::Local, value));
-    }
-
+    new_state.hook.enter_function(target, &values);
     let result = run_core(
         &mut new_state,
         &mut Program {
             commands: func.block.commands.clone(),
-            functions: program.functions.clone(),
+            functions,
         },
         0,
     );
+    new_state.hook.exit_function(target);
     for (name, (kind, value)) in new_state.variables.iter() {
         if kind == &VariableType::Global {
             state
@@ -309,15 +380,47 @@ fn call_function(
                 .insert(name.to_string(), (VariableType::Global, value.clone()));
         }
     }
-    return result;
+    return match result? {
+        Flow::Return(value) => Ok(value),
+        // Running off the end without giving anything back
+        _ => Ok(Value::Mysterious),
+    };
 }
 
-#[allow(clippy::cognitive_complexity)] // FIXME: break this up a bit
-fn run_expression(
+fn call_native(
     state: &mut State,
     program: &Program,
-    expression: &Expression,
-) -> Result<Expression> {
+    target: &str,
+    native: &NativeFunction,
+    args: &[Expression],
+) -> Result<Value> {
+    if args.len() != native.arity {
+        return Err(MaidenError::WrongArgCount {
+            expected: native.arity,
+            got: args.len(),
+            line: state.current_line,
+        });
+    }
+    let mut values = Vec::with_capacity(args.len());
+    for arg in args {
+        values.push(run_expression(state, program, arg)?);
+    }
+    state.hook.enter_function(target, &values);
+    let result = (native.function)(&values).map_err(|err| MaidenError::Native {
+        name: target.to_string(),
+        description: match err {
+            // Native functions can give their own description this way
+            MaidenError::Native { description, .. } => description,
+            err => err.to_string(),
+        },
+        line: state.current_line,
+    });
+    state.hook.exit_function(target);
+    result
+}
+
+#[allow(clippy::cognitive_complexity)] // FIXME: break this up a bit
+fn run_expression(state: &mut State, program: &Program, expression: &Expression) -> Result<Value> {
     debug!("Expression: {:?}", expression);
     return match *expression {
         Expression::Is(ref first, ref second) => {
@@ -336,17 +439,13 @@ fn run_expression(
                 program,
                 first,
                 second,
-                Some(Expression::False),
+                Some(Value::Boolean(false)),
                 false,
                 |st, f, s| {
                     return Ok(to_boolean(st, f)? && to_boolean(st, s)?);
                 },
             )?;
-            if res {
-                return Ok(Expression::True);
-            } else {
-                return Ok(Expression::False);
-            };
+            return Ok(Value::Boolean(res));
         }
         Expression::Or(ref first, ref second) => {
             let res = run_binop_shortcut(
@@ -354,17 +453,13 @@ fn run_expression(
                 program,
                 first,
                 second,
-                Some(Expression::True),
+                Some(Value::Boolean(true)),
                 true,
                 |st, f, s| {
                     return Ok(to_boolean(st, f)? || to_boolean(st, s)?);
                 },
             )?;
-            if res {
-                return Ok(Expression::True);
-            } else {
-                return Ok(Expression::False);
-            };
+            return Ok(Value::Boolean(res));
         }
         Expression::Nor(ref first, ref second) => {
             let res = run_binop_shortcut(
@@ -372,17 +467,13 @@ fn run_expression(
                 program,
                 first,
                 second,
-                Some(Expression::True),
+                Some(Value::Boolean(true)),
                 true,
                 |st, f, s| {
                     return Ok(to_boolean(st, f)? || to_boolean(st, s)?);
                 },
             )?;
-            if res {
-                return Ok(Expression::False);
-            } else {
-                return Ok(Expression::True);
-            };
+            return Ok(Value::Boolean(!res));
         }
         Expression::GreaterThanOrEqual(ref first, ref second) => {
             return run_binop(state, program, first, second, |_, f, s| Ok(f >= s));
@@ -408,7 +499,7 @@ fn run_expression(
         Expression::Divide(ref first, ref second) => {
             let res = run_mathbinop(state, program, first, second, expression, |f, s| f / s);
             if let Ok(ok) = res {
-                if let Expression::Floating(val) = ok {
+                if let Value::Number(val) = ok {
                     if val == std::f64::INFINITY {
                         return Err(MaidenError::Infinity {
                             x: format!("{:?}", first),
@@ -423,13 +514,23 @@ fn run_expression(
             }
         }
         Expression::Variable(ref name) => match state.variables.get(&name.to_lowercase()) {
-            Some((_, exp)) => {
-                debug!("Got variable {} with value {:?}", &name, exp);
-                Ok(exp.clone())
+            Some((_, value)) => {
+                debug!("Got variable {} with value {:?}", &name, value);
+                Ok(value.clone())
             }
             None => {
-                if program.functions.get(name).is_some() {
-                    return Ok(Expression::Object(name.clone()));
+                if let Some(function) = program.functions.get(&name.to_lowercase()) {
+                    let captured = state
+                        .variables
+                        .iter()
+                        .filter(|(_, (kind, _))| kind == &VariableType::Local)
+                        .map(|(name, (_, value))| (name.clone(), value.clone()))
+                        .collect();
+                    return Ok(Value::Function(Box::new(Closure {
+                        name: name.clone(),
+                        function: function.clone(),
+                        captured,
+                    })));
                 }
                 return Err(MaidenError::MissingVariable {
                     name: name.clone(),
@@ -437,12 +538,16 @@ fn run_expression(
                 });
             }
         },
+        Expression::ArrayRef {
+            ref name,
+            ref index,
+        } => lookup_array_ref(state, program, name, index),
         Expression::Call(ref target, ref args) => call_function(state, program, target, args),
         Expression::Pronoun => match state.pronoun {
             Some(ref pronoun) => match state.variables.get(&pronoun.to_lowercase()) {
-                Some((_, exp)) => {
-                    debug!("Got variable {} with value {:?}", &pronoun, exp);
-                    Ok(exp.clone())
+                Some((_, value)) => {
+                    debug!("Got variable {} with value {:?}", &pronoun, value);
+                    Ok(value.clone())
                 }
                 None => {
                     return Err(MaidenError::MissingVariable {
@@ -461,135 +566,181 @@ fn run_expression(
             let res = run_expression(state, program, arg)?;
             let boolean = to_boolean(state, &res);
             if let Ok(good_boolean) = boolean {
-                if good_boolean {
-                    return Ok(Expression::False);
-                } else {
-                    return Ok(Expression::True);
-                }
+                return Ok(Value::Boolean(!good_boolean));
             } else {
-                return Ok(Expression::False);
+                return Ok(Value::Boolean(false));
             }
         }
-        _ => Ok(expression.clone()),
+        _ => match Value::literal(expression) {
+            Some(value) => Ok(value),
+            None => Err(MaidenError::Unimplemented {
+                description: format!("Evaluating '{:?}'", expression),
+                line: state.current_line,
+            }),
+        },
     };
 }
 
+impl Runner {
+    pub fn new() -> Runner {
+        Runner::default()
+    }
+
+    /// Makes `name` callable from programs, taking `arity` arguments. Functions
+    /// the program declares itself take precedence, and any error `function`
+    /// gives back is reported as a `MaidenError::Native` on the calling line
+    /// (giving back a `Native` error itself just sets its description).
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn register_fn<F>(&mut self, name: &str, arity: usize, function: F) -> &mut Runner
+    where
+        F: Fn(&[Value]) -> Result<Value> + 'static,
+    {
+        self.natives.insert(
+            name.to_lowercase(),
+            NativeFunction {
+                arity,
+                function: Box::new(function),
+            },
+        );
+        self
+    }
+
+    /// Runs `program`, with `Listen` reading lines from `reader` and `Say` writing to `writer`
+    pub fn run(
+        &self,
+        program: &mut Program,
+        reader: &mut dyn BufRead,
+        writer: &mut dyn Write,
+    ) -> Result<HashMap<String, (VariableType, Value)>> {
+        self.run_with_hook(program, reader, writer, &mut NoHook)
+    }
+
+    pub fn run_with_hook(
+        &self,
+        program: &mut Program,
+        reader: &mut dyn BufRead,
+        writer: &mut dyn Write,
+        hook: &mut dyn Hook,
+    ) -> Result<HashMap<String, (VariableType, Value)>> {
+        let pc = 0;
+        let mut variables = HashMap::new();
+        let mut state = State {
+            variables: &mut variables,
+            reader,
+            writer,
+            hook,
+            natives: &self.natives,
+            current_line: 0,
+            depth: 0,
+            pronoun: None,
+        };
+        run_core(&mut state, program, pc)?;
+        return Ok(variables);
+    }
+}
+
+/// Runs `program`, with `Listen` reading lines from `reader` and `Say` writing to `writer`
 pub fn run(
     program: &mut Program,
+    reader: &mut dyn BufRead,
     writer: &mut dyn Write,
-) -> Result<HashMap<String, (VariableType, Expression)>> {
-    let pc = 0;
-    let mut variables = HashMap::new();
+) -> Result<HashMap<String, (VariableType, Value)>> {
+    Runner::new().run(program, reader, writer)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn run_with_hook(
+    program: &mut Program,
+    reader: &mut dyn BufRead,
+    writer: &mut dyn Write,
+    hook: &mut dyn Hook,
+) -> Result<HashMap<String, (VariableType, Value)>> {
+    Runner::new().run_with_hook(program, reader, writer, hook)
+}
+
+/// Works out the value of `expression` at the point `snapshot` was taken.
+/// Runs against a copy of the variables and discards any output, so the
+/// program itself is left as it was.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn evaluate(snapshot: &Snapshot, expression: &Expression) -> Result<Value> {
+    let mut variables = snapshot.variables.clone();
+    let program = Program {
+        commands: Vec::new(),
+        functions: snapshot.functions.clone(),
+    };
     let mut state = State {
+        reader: &mut std::io::empty(),
+        writer: &mut std::io::sink(),
+        hook: &mut NoHook,
+        natives: snapshot.natives,
         variables: &mut variables,
-        writer,
-        current_line: 0,
-        depth: 0,
-        pronoun: None,
+        current_line: snapshot.line,
+        depth: snapshot.depth,
+        pronoun: snapshot.pronoun.map(str::to_string),
     };
-    run_core(&mut state, program, pc)?;
-    return Ok(variables);
+    run_expression(&mut state, &program, expression)
 }
 
-fn get_printable(value: &Expression, state: &State) -> Result<String> {
-    match *value {
-        Expression::Floating(ref x) => Ok(format!("{}", x)),
-        Expression::String(ref s) => Ok(s.to_string()),
-        Expression::Variable(ref x) => {
-            let (_, v) = {
-                let current_line = state.current_line;
-                let v = state.variables.get(&x.to_lowercase());
-                if v.is_none() {
-                    return Err(MaidenError::MissingVariable {
-                        name: x.to_string(),
-                        line: current_line,
-                    });
-                }
-                v.unwrap().clone()
-            };
-            get_printable(&v, state)
-        }
-        Expression::Array { ref numeric, .. } => {
-            Ok(format!("{}", numeric.keys().max().map_or(0, |x| x + 1)))
-        }
-        Expression::ArrayRef {
-            ref name,
-            ref index,
-        } => {
-            let mysterious_box = Box::new(Expression::Mysterious);
-            let string_box;
-            let v = {
-                let current_line = state.current_line;
-                let var_name = match **name {
-                    Expression::Variable(ref s) => s,
-                    _ => {
-                        panic!("Other expression for array name: {:?}", name);
-                    }
-                };
-                let v = state.variables.get(&var_name.to_lowercase());
-                if v.is_none() {
-                    return Err(MaidenError::MissingVariable {
-                        name: var_name.to_string(),
-                        line: current_line,
-                    });
-                }
-                let mut local_index = &**index;
-                let variable;
-                if let Expression::Variable(ref var) = local_index {
-                    variable = state.variables.get(var).unwrap().1.clone();
-                    local_index = &variable;
-                }
-                let entry = match v.unwrap().1 {
-                    Expression::Array {
-                        ref numeric,
-                        ref strings,
-                    } => match local_index {
-                        Expression::String(ref s) => strings.get(s),
-                        Expression::Floating(f) => numeric.get(&(*f as usize)),
-                        _ => {
-                            panic!("Don't know how to array lookup with: {:?}", local_index);
-                        }
-                    },
-                    Expression::String(ref s) => match local_index {
-                        Expression::Floating(f) => {
-                            let g = *f as usize;
-                            match s.get(g..(g + 1)) {
-                                Some(slice) => {
-                                    string_box = Box::new(Expression::String(slice.to_string()));
-                                    Some(&string_box)
-                                }
-                                None => None,
-                            }
-                        }
-                        _ => {
-                            panic!("Don't know how to do string lookup with: {:?}", local_index);
-                        }
-                    },
-                    _ => {
-                        panic!("Array ref to non-array: {:?}", v);
-                    }
-                };
-                entry.unwrap_or(&mysterious_box)
-            };
-            get_printable(&v, state)
-        }
-        Expression::True => Ok("true".to_string()),
-        Expression::False => Ok("false".to_string()),
-        Expression::Mysterious => Ok("mysterious".to_string()),
-        Expression::Null => Ok("null".to_string()),
-        _ => {
-            return Err(MaidenError::Unimplemented {
-                description: format!("Say '{:?}'", value),
+// Resolves `name at index`, giving mysterious for missing entries
+fn lookup_array_ref(
+    state: &mut State,
+    program: &Program,
+    name: &Expression,
+    index: &Expression,
+) -> Result<Value> {
+    let var_name = variable_name(state, name, "Array lookup")?;
+    let index = run_expression(state, program, index)?;
+    let v = match state.variables.get(&var_name) {
+        Some((_, v)) => v,
+        None => {
+            return Err(MaidenError::MissingVariable {
+                name: var_name.to_string(),
                 line: state.current_line,
             });
         }
+    };
+    let entry = match v {
+        Value::Array {
+            ref numeric,
+            ref strings,
+        } => match index {
+            Value::String(ref s) => strings.get(s).cloned(),
+            Value::Number(f) => numeric.get(&(f as usize)).cloned(),
+            _ => {
+                panic!("Don't know how to array lookup with: {:?}", index);
+            }
+        },
+        Value::String(ref s) => match index {
+            // By character, to agree with splitting into characters
+            Value::Number(f) => s
+                .chars()
+                .nth(f as usize)
+                .map(|c| Value::String(c.to_string())),
+            _ => {
+                panic!("Don't know how to do string lookup with: {:?}", index);
+            }
+        },
+        _ => {
+            panic!("Array ref to non-array: {:?}", v);
+        }
+    };
+    Ok(entry.unwrap_or(Value::Mysterious))
+}
+
+fn get_printable(value: &Value, state: &State) -> Result<String> {
+    match value {
+        Value::Function(_) => Err(MaidenError::Unimplemented {
+            description: format!("Say '{:?}'", value),
+            line: state.current_line,
+        }),
+        _ => Ok(value.to_string()),
     }
 }
 
 fn flip_boolean(
     state: &mut State,
     target: &str,
-    val: &Expression,
+    val: &Value,
     count: usize,
     kind: VariableType,
 ) -> Result<()> {
@@ -598,12 +749,9 @@ fn flip_boolean(
         return Ok(());
     }
     match val {
-        Expression::True => state
-            .variables
-            .insert(target.to_lowercase(), (kind, Expression::False)),
-        Expression::False => state
+        Value::Boolean(b) => state
             .variables
-            .insert(target.to_lowercase(), (kind, Expression::True)),
+            .insert(target.to_lowercase(), (kind, Value::Boolean(!b))),
         _ => {
             return Err(MaidenError::Unimplemented {
                 description: format!("Attempt to flip non-boolean '{}'", target),
@@ -620,9 +768,10 @@ fn alter_variable(
     f: &dyn Fn(f64) -> f64,
     count: usize,
 ) -> Result<()> {
+    refer(state, target);
     let name = match target {
         Expression::Variable(n) => n.to_lowercase(),
-        Expression::Pronoun => state.pronoun.as_ref().unwrap().to_lowercase(),
+        Expression::Pronoun => pronoun_name(state)?,
         _ => {
             return Err(MaidenError::Unimplemented {
                 line: state.current_line,
@@ -643,17 +792,13 @@ fn alter_variable(
     };
     debug!("Value of {} is {:?}", name, val);
     match val {
-        Expression::Floating(x) => {
-            state
-                .variables
-                .insert(name, (kind, Expression::Floating(f(x))));
+        Value::Number(x) => {
+            state.variables.insert(name, (kind, Value::Number(f(x))));
         }
-        Expression::Null => {
-            state
-                .variables
-                .insert(name, (kind, Expression::Floating(f(0f64))));
+        Value::Null => {
+            state.variables.insert(name, (kind, Value::Number(f(0f64))));
         }
-        Expression::False | Expression::True => {
+        Value::Boolean(_) => {
             return flip_boolean(state, &name, &val, count, kind);
         }
         _ => {
@@ -667,9 +812,10 @@ fn alter_variable(
 }
 
 fn round_variable(state: &mut State, target: &Expression, f: &dyn Fn(f64) -> f64) -> Result<()> {
+    refer(state, target);
     let name = match target {
         Expression::Variable(n) => n.to_lowercase(),
-        Expression::Pronoun => state.pronoun.as_ref().unwrap().to_lowercase(),
+        Expression::Pronoun => pronoun_name(state)?,
         _ => {
             return Err(MaidenError::Unimplemented {
                 line: state.current_line,
@@ -690,15 +836,11 @@ fn round_variable(state: &mut State, target: &Expression, f: &dyn Fn(f64) -> f64
     };
     debug!("Value of {} is {:?}", name, val);
     match val {
-        Expression::Floating(x) => {
-            state
-                .variables
-                .insert(name, (kind, Expression::Floating(f(x))));
+        Value::Number(x) => {
+            state.variables.insert(name, (kind, Value::Number(f(x))));
         }
-        Expression::Null => {
-            state
-                .variables
-                .insert(name, (kind, Expression::Floating(f(0f64))));
+        Value::Null => {
+            state.variables.insert(name, (kind, Value::Number(f(0f64))));
         }
         _ => {
             return Err(MaidenError::Unimplemented {
@@ -710,6 +852,51 @@ fn round_variable(state: &mut State, target: &Expression, f: &dyn Fn(f64) -> f64
     return Ok(());
 }
 
+// Name of the variable the pronoun refers to, i.e. the last one assigned or tested
+fn pronoun_name(state: &State) -> Result<String> {
+    match state.pronoun {
+        Some(ref pronoun) => Ok(pronoun.to_lowercase()),
+        None => Err(MaidenError::UndefinedPronoun {
+            line: state.current_line,
+        }),
+    }
+}
+
+// Points the pronoun at the variable `target` names, if it names one
+fn refer(state: &mut State, target: &Expression) {
+    if let Expression::Variable(name) = target {
+        state.pronoun = Some(name.clone());
+    }
+}
+
+// Name of the variable `target` names, following the pronoun if that's what it is
+fn variable_name(state: &State, target: &Expression, mutator: &str) -> Result<String> {
+    match target {
+        Expression::Variable(n) => Ok(n.to_lowercase()),
+        Expression::Pronoun => pronoun_name(state),
+        _ => Err(MaidenError::Unimplemented {
+            line: state.current_line,
+            description: format!("{} for non-variable: {:?}", mutator, target),
+        }),
+    }
+}
+
+// Name, kind and current value of the variable a mutation reads
+fn mutated_variable(
+    state: &State,
+    target: &Expression,
+    mutator: &str,
+) -> Result<(String, VariableType, Value)> {
+    let name = variable_name(state, target, mutator)?;
+    match state.variables.get(&name) {
+        Some((kind, value)) => Ok((name, *kind, value.clone())),
+        None => Err(MaidenError::MissingVariable {
+            name,
+            line: state.current_line,
+        }),
+    }
+}
+
 fn get_variable_type(state: &State) -> VariableType {
     if state.depth == 0 {
         VariableType::Global
@@ -719,7 +906,7 @@ fn get_variable_type(state: &State) -> VariableType {
 }
 
 #[allow(clippy::cognitive_complexity)] // FIXME: break this up a bit
-fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<Expression> {
+fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<Flow> {
     let mut total_instr = 0;
     loop {
         total_instr += 1;
@@ -734,6 +921,9 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
         };
         state.current_line = command_line.line;
         debug!("command: {:?}", command_line);
+        state
+            .hook
+            .command(&snapshot!(state, program, command_line))?;
         match command_line.cmd {
             Command::Assignment {
                 ref target,
@@ -741,41 +931,31 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
             } => {
                 let val = run_expression(state, program, &value)?;
                 match &**target {
-                    Expression::Variable(name) => {
-                        state.pronoun = Some(name.clone());
-                        let kind = if let Some((inner_kind, _var)) =
-                            state.variables.get(&name.to_lowercase())
-                        {
+                    Expression::Variable(_) | Expression::Pronoun => {
+                        let name = match &**target {
+                            Expression::Variable(name) => name.to_lowercase(),
+                            _ => pronoun_name(state)?,
+                        };
+                        refer(state, target);
+                        let kind = if let Some((inner_kind, _var)) = state.variables.get(&name) {
                             *inner_kind
                         } else {
                             get_variable_type(&state)
                         };
-                        state.variables.insert(name.to_lowercase(), (kind, val));
-                    }
-                    Expression::Pronoun => {
-                        let pronoun = state.pronoun.as_ref().unwrap();
-                        state
-                            .variables
-                            .insert(pronoun.to_lowercase(), (get_variable_type(&state), val));
+                        state.variables.insert(name, (kind, val));
                     }
                     // FIXME: improve with box patterns once stabilised https://github.com/rust-lang/rust/issues/29641
                     Expression::ArrayRef { name, index } => {
                         if let Expression::Variable(var_name) = name.deref() {
-                            let mut local_index = &**index;
-                            let variable;
-                            if let Expression::Variable(ref var) = local_index {
-                                variable = state.variables.get(var).unwrap().clone().1;
-                                local_index = &variable;
-                            }
-                            match local_index {
-                                Expression::Floating(ref idx) => {
+                            match run_expression(state, program, index)? {
+                                Value::Number(ref idx) => {
                                     if let Some((_kind, array)) = state.variables.get_mut(var_name)
                                     {
-                                        if let Expression::Array {
+                                        if let Value::Array {
                                             ref mut numeric, ..
                                         } = array
                                         {
-                                            numeric.insert(*idx as usize, Box::new(val));
+                                            numeric.insert(*idx as usize, val);
                                         } else {
                                             panic!(
                                                 "Array ref assignment to non-array {} {}",
@@ -784,12 +964,12 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                                         }
                                     } else {
                                         let mut numeric = BTreeMap::new();
-                                        numeric.insert(*idx as usize, Box::new(val));
+                                        numeric.insert(*idx as usize, val);
                                         state.variables.insert(
                                             var_name.to_string(),
                                             (
                                                 get_variable_type(&state),
-                                                Expression::Array {
+                                                Value::Array {
                                                     numeric,
                                                     strings: BTreeMap::new(),
                                                 },
@@ -797,14 +977,14 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                                         );
                                     }
                                 }
-                                Expression::String(ref idx) => {
+                                Value::String(ref idx) => {
                                     if let Some((_kind, array)) = state.variables.get_mut(var_name)
                                     {
-                                        if let Expression::Array {
+                                        if let Value::Array {
                                             ref mut strings, ..
                                         } = array
                                         {
-                                            strings.insert(idx.to_string(), Box::new(val));
+                                            strings.insert(idx.to_string(), val);
                                         } else {
                                             panic!(
                                                 "Array ref assignment to non-array {} {}",
@@ -813,12 +993,12 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                                         }
                                     } else {
                                         let mut strings = BTreeMap::new();
-                                        strings.insert(idx.to_string(), Box::new(val));
+                                        strings.insert(idx.to_string(), val);
                                         state.variables.insert(
                                             var_name.to_string(),
                                             (
                                                 get_variable_type(&state),
-                                                Expression::Array {
+                                                Value::Array {
                                                     numeric: BTreeMap::new(),
                                                     strings,
                                                 },
@@ -854,25 +1034,43 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                 ref block,
             } => loop {
                 let resolve = run_expression(state, program, &expression)?;
-                if to_boolean(state, &resolve)? {
+                if let Some(subject) = expression.subject() {
+                    state.pronoun = Some(subject.to_string());
+                }
+                let truthy = to_boolean(state, &resolve)?;
+                state.hook.condition(command_line.line, &resolve, truthy);
+                if truthy {
                     break;
                 }
-                run_core(
+                let res = run_core(
                     state,
                     &mut Program {
                         commands: block.commands.clone(),
                         functions: program.functions.clone(),
                     },
                     0,
-                )
-                .unwrap();
+                )?;
+                if res == Flow::Break {
+                    break;
+                }
+                if let Flow::Return(_) = res {
+                    state
+                        .hook
+                        .command_done(&snapshot!(state, program, command_line));
+                    return Ok(res);
+                }
             },
             Command::While {
                 ref expression,
                 ref block,
             } => loop {
                 let resolve = run_expression(state, program, &expression)?;
-                if !to_boolean(state, &resolve)? {
+                if let Some(subject) = expression.subject() {
+                    state.pronoun = Some(subject.to_string());
+                }
+                let truthy = to_boolean(state, &resolve)?;
+                state.hook.condition(command_line.line, &resolve, truthy);
+                if !truthy {
                     break;
                 }
                 let res = run_core(
@@ -882,17 +1080,28 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                         functions: program.functions.clone(),
                     },
                     0,
-                )
-                .unwrap();
-                if res == Expression::Break {
+                )?;
+                if res == Flow::Break {
                     break;
                 }
+                if let Flow::Return(_) = res {
+                    state
+                        .hook
+                        .command_done(&snapshot!(state, program, command_line));
+                    return Ok(res);
+                }
             },
             Command::Continue => {
-                return Ok(Expression::Continue);
+                state
+                    .hook
+                    .command_done(&snapshot!(state, program, command_line));
+                return Ok(Flow::Continue);
             }
             Command::Break => {
-                return Ok(Expression::Break);
+                state
+                    .hook
+                    .command_done(&snapshot!(state, program, command_line));
+                return Ok(Flow::Break);
             }
             Command::Say { ref value } => {
                 let resolve = run_expression(state, program, &value)?;
@@ -905,7 +1114,7 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                 ref block,
             } => {
                 program.functions.insert(
-                    name.to_string(),
+                    name.to_lowercase(),
                     Function {
                         args: args.to_vec(),
                         block: block.clone(),
@@ -913,7 +1122,11 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                 );
             }
             Command::Return { ref return_value } => {
-                return run_expression(state, program, &return_value);
+                let value = run_expression(state, program, &return_value)?;
+                state
+                    .hook
+                    .command_done(&snapshot!(state, program, command_line));
+                return Ok(Flow::Return(value));
             }
             Command::If {
                 ref expression,
@@ -922,7 +1135,12 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
             } => {
                 let resolve = run_expression(state, program, &expression)?;
                 debug!("if: {:?} {:?}", &resolve, expression);
-                if to_boolean(state, &resolve)? {
+                if let Some(subject) = expression.subject() {
+                    state.pronoun = Some(subject.to_string());
+                }
+                let truthy = to_boolean(state, &resolve)?;
+                state.hook.condition(command_line.line, &resolve, truthy);
+                if truthy {
                     if let Some(block) = then {
                         let res = run_core(
                             state,
@@ -931,9 +1149,11 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                                 functions: program.functions.clone(),
                             },
                             0,
-                        )
-                        .unwrap();
-                        if res != Expression::Nothing {
+                        )?;
+                        if res != Flow::Next {
+                            state
+                                .hook
+                                .command_done(&snapshot!(state, program, command_line));
                             return Ok(res);
                         }
                     }
@@ -945,9 +1165,11 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                             functions: program.functions.clone(),
                         },
                         0,
-                    )
-                    .unwrap();
-                    if res != Expression::Nothing {
+                    )?;
+                    if res != Flow::Next {
+                        state
+                            .hook
+                            .command_done(&snapshot!(state, program, command_line));
                         return Ok(res);
                     }
                 }
@@ -959,13 +1181,14 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                 target: ref opt_target,
             } => {
                 let mut input = String::new();
-                io::stdin().read_line(&mut input)?;
+                state.reader.read_line(&mut input)?;
                 if let Some(target) = opt_target {
+                    state.pronoun = Some(target.clone());
                     state.variables.insert(
                         target.to_lowercase(),
                         (
                             get_variable_type(&state),
-                            Expression::String(input.trim_end_matches('\n').to_string()),
+                            Value::String(input.trim_end_matches('\n').to_string()),
                         ),
                     );
                 }
@@ -1000,31 +1223,40 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                             modifier
                         );
                     }
-                    if let Expression::Variable(var_name) = lookup.as_ref().unwrap().deref() {
-                        match state.variables.get(&var_name.to_lowercase()).unwrap() {
-                            (kind, Expression::String(ref s)) => {
-                                let val = f64::from_str(s).unwrap();
-                                let new_kind = *kind;
-                                state.variables.insert(
-                                    var_name.to_lowercase(),
-                                    (new_kind, Expression::Floating(val)),
-                                );
-                            }
-                            (kind, Expression::Floating(f)) => {
-                                let val = std::char::from_u32(*f as u32).unwrap().to_string();
-                                let new_kind = *kind;
-                                state.variables.insert(
-                                    var_name.to_lowercase(),
-                                    (new_kind, Expression::String(val)),
-                                );
-                            }
-                            var => {
-                                unimplemented!("Cast for {:?}", var);
+                    let (var_name, kind, value) =
+                        mutated_variable(state, lookup.as_ref().unwrap(), "Cast")?;
+                    let val = match value {
+                        Value::String(ref s) => {
+                            let mut chars = s.chars();
+                            Value::Number(match (f64::from_str(s), chars.next(), chars.next()) {
+                                (Ok(val), _, _) => val,
+                                // A single character that isn't a digit becomes its code point
+                                (Err(_), Some(c), None) => f64::from(c as u32),
+                                (Err(_), _, _) => {
+                                    return Err(MaidenError::ParseNumberError {
+                                        number: s.to_string(),
+                                        line: state.current_line,
+                                    });
+                                }
+                            })
+                        }
+                        Value::Number(f) => match std::char::from_u32(f as u32) {
+                            Some(c) => Value::String(c.to_string()),
+                            None => {
+                                return Err(MaidenError::Unimplemented {
+                                    description: format!("Cast for {}", f),
+                                    line: state.current_line,
+                                });
                             }
+                        },
+                        var => {
+                            return Err(MaidenError::Unimplemented {
+                                description: format!("Cast for {:?}", var),
+                                line: state.current_line,
+                            });
                         }
-                    } else {
-                        unimplemented!("Cast for non-variable: {:?}", lookup);
-                    }
+                    };
+                    state.variables.insert(var_name, (kind, val));
                 }
                 SymbolType::Split => {
                     let split_by = modifier
@@ -1034,65 +1266,28 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                             other => panic!("Modifier with non-string: {:?}", other),
                         })
                         .unwrap_or("");
-                    let split_array = |to_split: &String| Expression::Array {
+                    let split_array = |to_split: &String| Value::Array {
                         numeric: to_split
                             .split(split_by)
                             .filter(|x| !x.is_empty())
                             .enumerate()
-                            .map(|(k, v)| (k, Box::new(Expression::String(v.to_string()))))
+                            .map(|(k, v)| (k, Value::String(v.to_string())))
                             .collect(),
                         strings: BTreeMap::new(),
                     };
-                    if lookup.is_some() {
-                        if let Expression::Variable(var_name) = lookup.as_ref().unwrap().deref() {
-                            match state.variables.get(&var_name.to_lowercase()).unwrap() {
-                                (kind, Expression::String(s)) => {
-                                    let val = split_array(s);
-                                    let new_kind = *kind;
-                                    state
-                                        .variables
-                                        .insert(var_name.to_lowercase(), (new_kind, val));
-                                }
-                                var => {
-                                    unimplemented!("Split for {:?}", var);
-                                }
-                            }
-                        } else {
-                            unimplemented!("Split for non-variable: {:?}", lookup);
-                        }
-                    } else if target.is_some() && source.is_some() {
-                        match source.as_ref().unwrap().deref() {
+                    let (name, kind, value) = if let Some(lookup) = lookup {
+                        mutated_variable(state, lookup, "Split")?
+                    } else if let (Some(source), Some(target)) = (source, target) {
+                        let (kind, value) = match source.deref() {
                             Expression::String(src) => {
-                                if let Expression::Variable(tar) = target.as_ref().unwrap().deref()
-                                {
-                                    let val = split_array(src);
-                                    state.variables.insert(
-                                        tar.to_lowercase(),
-                                        (get_variable_type(&state), val),
-                                    );
-                                } else {
-                                    unimplemented!("Split to {:?}", target);
-                                }
-                            }
-                            Expression::Variable(src) => {
-                                if let Expression::Variable(tar) = target.as_ref().unwrap().deref()
-                                {
-                                    let var = state.variables.get(src).unwrap();
-                                    let kind = var.0;
-                                    if let Expression::String(ref var_str) = var.1 {
-                                        let val = split_array(var_str);
-                                        state.variables.insert(tar.to_lowercase(), (kind, val));
-                                    } else {
-                                        unimplemented!("Split of {:?}", var);
-                                    }
-                                } else {
-                                    unimplemented!("Split to {:?}", target);
-                                }
+                                (get_variable_type(&state), Value::String(src.clone()))
                             }
-                            _ => {
-                                unimplemented!("Split for {:?}", source);
+                            src => {
+                                let (_, kind, value) = mutated_variable(state, src, "Split")?;
+                                (kind, value)
                             }
-                        }
+                        };
+                        (variable_name(state, target, "Split")?, kind, value)
                     } else {
                         unimplemented!(
                             "Split for {:?} {:?} {:?} {:?}",
@@ -1101,6 +1296,17 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                             lookup,
                             modifier
                         );
+                    };
+                    match value {
+                        Value::String(ref s) => {
+                            state.variables.insert(name, (kind, split_array(s)));
+                        }
+                        var => {
+                            return Err(MaidenError::Unimplemented {
+                                description: format!("Split for {:?}", var),
+                                line: state.current_line,
+                            });
+                        }
                     }
                 }
                 SymbolType::Join => {
@@ -1111,12 +1317,12 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                             other => panic!("Modifier with non-string: {:?}", other),
                         })
                         .unwrap_or("");
-                    let join_array = |to_join: &BTreeMap<usize, Box<Expression>>| {
-                        Expression::String(
+                    let join_array = |to_join: &BTreeMap<usize, Value>| {
+                        Value::String(
                             to_join
                                 .values()
-                                .map(|b| match b.deref() {
-                                    Expression::String(s) => s.as_str(),
+                                .map(|b| match b {
+                                    Value::String(s) => s.as_str(),
                                     other => panic!("Modifier with non-string: {:?}", other),
                                 })
                                 .fold(String::new(), |acc, x| {
@@ -1128,56 +1334,11 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                                 }),
                         )
                     };
-                    if lookup.is_some() {
-                        if let Expression::Variable(var_name) = lookup.as_ref().unwrap().deref() {
-                            match state.variables.get(&var_name.to_lowercase()).unwrap() {
-                                (kind, Expression::Array { ref numeric, .. }) => {
-                                    let val = join_array(numeric);
-                                    let new_kind = *kind;
-                                    state
-                                        .variables
-                                        .insert(var_name.to_lowercase(), (new_kind, val));
-                                }
-                                var => {
-                                    unimplemented!("Join for {:?}", var);
-                                }
-                            }
-                        } else {
-                            unimplemented!("Join for non-variable: {:?}", lookup);
-                        }
-                    } else if target.is_some() && source.is_some() {
-                        match source.as_ref().unwrap().deref() {
-                            Expression::Array { ref numeric, .. } => {
-                                if let Expression::Variable(tar) = target.as_ref().unwrap().deref()
-                                {
-                                    let val = join_array(numeric);
-                                    state.variables.insert(
-                                        tar.to_lowercase(),
-                                        (get_variable_type(&state), val),
-                                    );
-                                } else {
-                                    unimplemented!("Join to {:?}", target);
-                                }
-                            }
-                            Expression::Variable(src) => {
-                                if let Expression::Variable(tar) = target.as_ref().unwrap().deref()
-                                {
-                                    let (kind, var) = state.variables.get(src).unwrap();
-                                    if let Expression::Array { ref numeric, .. } = var {
-                                        let val = join_array(numeric);
-                                        let new_kind = *kind;
-                                        state.variables.insert(tar.to_lowercase(), (new_kind, val));
-                                    } else {
-                                        unimplemented!("Join of {:?}", var);
-                                    }
-                                } else {
-                                    unimplemented!("Join to {:?}", target);
-                                }
-                            }
-                            _ => {
-                                unimplemented!("Join for {:?}", source);
-                            }
-                        }
+                    let (name, kind, value) = if let Some(lookup) = lookup {
+                        mutated_variable(state, lookup, "Join")?
+                    } else if let (Some(source), Some(target)) = (source, target) {
+                        let (_, kind, value) = mutated_variable(state, source, "Join")?;
+                        (variable_name(state, target, "Join")?, kind, value)
                     } else {
                         unimplemented!(
                             "Join for {:?} {:?} {:?} {:?}",
@@ -1186,6 +1347,17 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                             lookup,
                             modifier
                         );
+                    };
+                    match value {
+                        Value::Array { ref numeric, .. } => {
+                            state.variables.insert(name, (kind, join_array(numeric)));
+                        }
+                        var => {
+                            return Err(MaidenError::Unimplemented {
+                                description: format!("Join for {:?}", var),
+                                line: state.current_line,
+                            });
+                        }
                     }
                 }
                 _ => {
@@ -1200,7 +1372,21 @@ fn run_core(state: &mut State, program: &mut Program, mut pc: usize) -> Result<E
                 }
             },
         }
+        // Cast, Split and Join count as assigning to the variable they change
+        if let Command::Mutation {
+            ref target,
+            ref lookup,
+            ..
+        } = command_line.cmd
+        {
+            if let Some(place) = lookup.as_ref().or(target.as_ref()) {
+                refer(state, place);
+            }
+        }
+        state
+            .hook
+            .command_done(&snapshot!(state, program, command_line));
         pc += 1;
     }
-    return Ok(Expression::Nothing);
+    return Ok(Flow::Next);
 }
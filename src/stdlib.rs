@@ -0,0 +1,287 @@
+use crate::common::{MaidenError, Result};
+use crate::runner::Runner;
+use crate::value::{self, Value};
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// A function that `--stdlib` lets programs call
+pub struct Builtin {
+    pub name: &'static str,
+    pub args: &'static [&'static str],
+    pub about: &'static str,
+    function: fn(&[Value], &Random) -> Result<Value>,
+}
+
+const BUILTINS: [Builtin; 9] = [
+    Builtin {
+        name: "Modulo",
+        args: &["x", "y"],
+        about: "remainder of dividing x by y, with the sign of x",
+        function: modulo,
+    },
+    Builtin {
+        name: "Power",
+        args: &["x", "y"],
+        about: "x to the power of y",
+        function: power,
+    },
+    Builtin {
+        name: "Root",
+        args: &["x"],
+        about: "square root of x",
+        function: root,
+    },
+    Builtin {
+        name: "Absolute",
+        args: &["x"],
+        about: "x without its sign",
+        function: absolute,
+    },
+    Builtin {
+        name: "Length",
+        args: &["value"],
+        about: "number of characters in a string, or entries in an array",
+        function: length,
+    },
+    Builtin {
+        name: "Substring",
+        args: &["text", "start", "count"],
+        about: "count characters of text, starting from character start (counting from 0)",
+        function: substring,
+    },
+    Builtin {
+        name: "Uppercase",
+        args: &["text"],
+        about: "text in upper case",
+        function: upper,
+    },
+    Builtin {
+        name: "Lowercase",
+        args: &["text"],
+        about: "text in lower case",
+        function: lower,
+    },
+    Builtin {
+        name: "Random",
+        args: &["limit"],
+        about: "whole number from 0 up to (but not including) limit, repeatable with --seed",
+        function: random,
+    },
+];
+
+/// Pseudo-random numbers (splitmix64), so runs with the same seed match
+pub struct Random {
+    state: Cell<u64>,
+}
+
+impl Random {
+    fn new(seed: u64) -> Random {
+        Random {
+            state: Cell::new(seed),
+        }
+    }
+
+    fn next(&self) -> u64 {
+        let mut z = self.state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        self.state.set(z);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Somewhere in [0, 1)
+    fn fraction(&self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+pub fn builtins() -> &'static [Builtin] {
+    &BUILTINS
+}
+
+/// Makes all the builtins callable from programs `runner` runs
+pub fn register(runner: &mut Runner, seed: u64) {
+    let random = Rc::new(Random::new(seed));
+    for builtin in builtins() {
+        let random = random.clone();
+        let function = builtin.function;
+        runner.register_fn(builtin.name, builtin.args.len(), move |args| {
+            function(args, &random)
+        });
+    }
+}
+
+/// One line per builtin, for `--list-builtins`
+pub fn list() -> String {
+    builtins()
+        .iter()
+        .map(|builtin| {
+            format!(
+                "{} taking {}: {}\n",
+                builtin.name,
+                builtin.args.join(", "),
+                builtin.about
+            )
+        })
+        .collect()
+}
+
+fn failure(description: &str) -> MaidenError {
+    MaidenError::Native {
+        name: String::new(),
+        description: description.to_string(),
+        line: 0,
+    }
+}
+
+fn number(value: &Value) -> Result<f64> {
+    value
+        .to_number()
+        .ok_or_else(|| value::wrong_type("number", value))
+}
+
+fn text(value: &Value) -> Result<&str> {
+    match value {
+        Value::String(s) => Ok(s),
+        other => Err(value::wrong_type("string", other)),
+    }
+}
+
+fn modulo(args: &[Value], _: &Random) -> Result<Value> {
+    let (x, y) = (number(&args[0])?, number(&args[1])?);
+    if y == 0f64 {
+        return Err(failure("Can't take the modulo of dividing by zero"));
+    }
+    Ok(Value::Number(x % y))
+}
+
+fn power(args: &[Value], _: &Random) -> Result<Value> {
+    Ok(Value::Number(number(&args[0])?.powf(number(&args[1])?)))
+}
+
+fn root(args: &[Value], _: &Random) -> Result<Value> {
+    let x = number(&args[0])?;
+    if x < 0f64 {
+        return Err(failure("Can't take the square root of a negative number"));
+    }
+    Ok(Value::Number(x.sqrt()))
+}
+
+fn absolute(args: &[Value], _: &Random) -> Result<Value> {
+    Ok(Value::Number(number(&args[0])?.abs()))
+}
+
+fn length(args: &[Value], _: &Random) -> Result<Value> {
+    match &args[0] {
+        Value::String(s) => Ok(Value::Number(s.chars().count() as f64)),
+        // Same as what saying the array prints
+        Value::Array { numeric, .. } => Ok(Value::Number(
+            numeric.keys().max().map_or(0, |x| x + 1) as f64,
+        )),
+        other => Err(value::wrong_type("string or array", other)),
+    }
+}
+
+fn substring(args: &[Value], _: &Random) -> Result<Value> {
+    let start = number(&args[1])?.max(0f64) as usize;
+    let count = number(&args[2])?.max(0f64) as usize;
+    Ok(Value::String(
+        text(&args[0])?.chars().skip(start).take(count).collect(),
+    ))
+}
+
+fn upper(args: &[Value], _: &Random) -> Result<Value> {
+    Ok(Value::String(text(&args[0])?.to_uppercase()))
+}
+
+fn lower(args: &[Value], _: &Random) -> Result<Value> {
+    Ok(Value::String(text(&args[0])?.to_lowercase()))
+}
+
+fn random(args: &[Value], random: &Random) -> Result<Value> {
+    let limit = number(&args[0])?.floor();
+    if limit < 1f64 {
+        return Err(failure("Random needs a limit of at least 1"));
+    }
+    Ok(Value::Number((random.fraction() * limit).floor()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::register;
+    use crate::common::{MaidenError, Result};
+    use crate::parser;
+    use crate::runner::Runner;
+    use pretty_assertions::assert_eq;
+    use std::io::{self, Cursor};
+
+    fn run(code: &str, seed: u64) -> Result<String> {
+        let mut runner = Runner::new();
+        register(&mut runner, seed);
+        let mut program = parser::parse(code).unwrap();
+        let mut writer = Cursor::new(Vec::new());
+        runner.run(&mut program, &mut io::empty(), &mut writer)?;
+        Ok(String::from_utf8(writer.into_inner()).unwrap())
+    }
+
+    #[test]
+    fn maths() {
+        let code = "Say Modulo taking 17, 5
+Say modulo taking -7, 2
+Say Power taking 2, 10
+Say Root taking 16
+Say Absolute taking -2.5
+";
+        assert_eq!(run(code, 0).unwrap(), "2\n-1\n1024\n4\n2.5\n");
+    }
+
+    #[test]
+    fn strings() {
+        let code = "My words says héllo world
+Say Length taking my words
+Say Substring taking my words, 1, 4
+Say Uppercase taking my words
+Say Lowercase taking \"LOUD\"
+Split my words into the list with \" \"
+Say Length taking the list
+";
+        assert_eq!(run(code, 0).unwrap(), "11\néllo\nHÉLLO WORLD\nloud\n2\n");
+    }
+
+    #[test]
+    fn seeded_random() {
+        let code = "Say Random taking 100\nSay Random taking 100\nSay Random taking 100\n";
+        let first = run(code, 42).unwrap();
+        assert_eq!(first, run(code, 42).unwrap());
+        assert_ne!(first, run(code, 43).unwrap());
+        for line in first.lines() {
+            let number: f64 = line.parse().unwrap();
+            assert!((0.0..100.0).contains(&number) && number.fract() == 0.0);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        match run("Say Root taking -1", 0) {
+            Err(MaidenError::Native {
+                name,
+                description,
+                line,
+            }) => {
+                assert_eq!(name, "Root");
+                assert_eq!(
+                    description,
+                    "Can't take the square root of a negative number"
+                );
+                assert_eq!(line, 1);
+            }
+            other => panic!("Expected a Native error, got {:?}", other),
+        }
+        match run("Say Uppercase taking 5", 0) {
+            Err(MaidenError::Native { description, .. }) => {
+                assert_eq!(description, "Expected a string value, got a number");
+            }
+            other => panic!("Expected a Native error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::peg::Rule;
+use pest::iterators::Pair;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Whitespace,
+    Newline,
+    Comment,
+    // Keywords, punctuation and anything else not covered by a child rule
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxToken {
+    pub kind: TokenKind,
+    pub text: String,
+    pub start: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+/// Concrete syntax tree node for a grammar rule. Unlike `Program`, this keeps
+/// every byte of the source (whitespace, comments and the exact keyword
+/// spellings), so `text()` gives back the original input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxNode {
+    pub rule: Rule,
+    pub start: usize,
+    pub end: usize,
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    pub fn text(&self) -> String {
+        self.text_with(&|_| None)
+    }
+
+    /// As `text()`, but with any node for which `replace` returns something
+    /// swapped out for that text
+    pub fn text_with(&self, replace: &dyn Fn(&SyntaxNode) -> Option<String>) -> String {
+        if let Some(text) = replace(self) {
+            return text;
+        }
+        let mut res = String::new();
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => res += &node.text_with(replace),
+                SyntaxElement::Token(token) => res += &token.text,
+            }
+        }
+        res
+    }
+
+    pub fn tokens(&self) -> Vec<&SyntaxToken> {
+        let mut res = vec![];
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => res.extend(node.tokens()),
+                SyntaxElement::Token(token) => res.push(token),
+            }
+        }
+        res
+    }
+
+    pub fn descendants(&self) -> Vec<&SyntaxNode> {
+        let mut res = vec![self];
+        for child in &self.children {
+            if let SyntaxElement::Node(node) = child {
+                res.extend(node.descendants());
+            }
+        }
+        res
+    }
+
+    /// The text of the keyword(s) directly in this node, e.g. which of
+    /// say/shout/whisper/scream an `output` used
+    pub fn keyword(&self) -> Option<String> {
+        let words: Vec<&str> = self
+            .children
+            .iter()
+            .filter_map(|child| match child {
+                SyntaxElement::Token(token) if token.kind == TokenKind::Text => {
+                    Some(token.text.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+        if words.is_empty() {
+            None
+        } else {
+            Some(words.join(" "))
+        }
+    }
+}
+
+fn trivia(text: &str, offset: usize, tokens: &mut Vec<SyntaxElement>) {
+    let mut push = |kind, start: usize, end: usize| {
+        tokens.push(SyntaxElement::Token(SyntaxToken {
+            kind,
+            text: text[start..end].to_string(),
+            start: offset + start,
+        }))
+    };
+    let bytes = text.as_bytes();
+    let mut index = 0;
+    while index < bytes.len() {
+        let start = index;
+        let kind = match bytes[index] {
+            b' ' | b'\t' => {
+                while index < bytes.len() && (bytes[index] == b' ' || bytes[index] == b'\t') {
+                    index += 1;
+                }
+                TokenKind::Whitespace
+            }
+            b'\r' | b'\n' => {
+                if bytes[index] == b'\r' && bytes.get(index + 1) == Some(&b'\n') {
+                    index += 1;
+                }
+                index += 1;
+                TokenKind::Newline
+            }
+            b'(' => {
+                index = text[index..]
+                    .find(')')
+                    .map_or(bytes.len(), |end| index + end + 1);
+                TokenKind::Comment
+            }
+            _ => {
+                while index < bytes.len() && !b" \t\r\n(".contains(&bytes[index]) {
+                    index += 1;
+                }
+                TokenKind::Text
+            }
+        };
+        push(kind, start, index);
+    }
+}
+
+pub fn build(pair: Pair<'_, Rule>, source: &str) -> SyntaxNode {
+    let span = pair.as_span();
+    let rule = pair.as_rule();
+    let mut children = vec![];
+    let mut position = span.start();
+    for inner in pair.into_inner() {
+        let inner_span = inner.as_span();
+        trivia(
+            &source[position..inner_span.start()],
+            position,
+            &mut children,
+        );
+        position = inner_span.end();
+        children.push(SyntaxElement::Node(build(inner, source)));
+    }
+    trivia(&source[position..span.end()], position, &mut children);
+    SyntaxNode {
+        rule,
+        start: span.start(),
+        end: span.end(),
+        children,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TokenKind;
+    use crate::parser;
+    use crate::peg::Rule;
+
+    const CODE: &str = "(lyrics)\nMidnight takes your heart\nGive back your heart (done)\n\nSHOUT Midnight taking 2\r\nwhisper \"x\"\n";
+
+    #[test]
+    fn round_trips() {
+        let (_, tree) = parser::parse_lossless(CODE).unwrap();
+        assert_eq!(tree.text(), CODE);
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let (_, tree) = parser::parse_lossless(CODE).unwrap();
+        let comments: Vec<&str> = tree
+            .tokens()
+            .into_iter()
+            .filter(|token| token.kind == TokenKind::Comment)
+            .map(|token| token.text.as_str())
+            .collect();
+        assert_eq!(comments, vec!["(lyrics)", "(done)"]);
+    }
+
+    #[test]
+    fn keeps_keywords() {
+        let (program, tree) = parser::parse_lossless(CODE).unwrap();
+        assert_eq!(program, parser::parse(CODE).unwrap());
+        let keywords: Vec<String> = tree
+            .descendants()
+            .into_iter()
+            .filter(|node| node.rule == Rule::output || node.rule == Rule::return_kw)
+            .filter_map(|node| node.keyword())
+            .collect();
+        assert_eq!(keywords, vec!["Give back", "SHOUT", "whisper"]);
+    }
+}
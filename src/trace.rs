@@ -0,0 +1,391 @@
+use crate::common::{Command, Result};
+use crate::runner::{Hook, Snapshot, VariableType};
+use crate::value::{describe, Value};
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Json,
+}
+
+const ALL_FORMATS: [TraceFormat; 2] = [TraceFormat::Text, TraceFormat::Json];
+
+impl TraceFormat {
+    pub fn all() -> &'static [TraceFormat] {
+        &ALL_FORMATS
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceFormat::Text => "text",
+            TraceFormat::Json => "json",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        ALL_FORMATS
+            .iter()
+            .cloned()
+            .find(|format| format.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub name: String,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+/// One step of a program's execution
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    /// A command that's finished, with the variables it changed. Commands
+    /// with blocks are recorded before their blocks run instead, and their
+    /// blocks' commands get records of their own.
+    Command {
+        line: usize,
+        depth: u32,
+        kind: &'static str,
+        changes: Vec<Change>,
+    },
+    Condition {
+        line: usize,
+        depth: u32,
+        value: Value,
+        truthy: bool,
+    },
+    Enter {
+        depth: u32,
+        name: String,
+        args: Vec<Value>,
+    },
+    Exit {
+        depth: u32,
+        name: String,
+    },
+}
+
+fn optional(value: &Option<Value>) -> String {
+    value.as_ref().map_or("unset".to_string(), describe)
+}
+
+impl Record {
+    pub fn to_text(&self) -> String {
+        match self {
+            Record::Command {
+                line,
+                depth,
+                kind,
+                changes,
+            } => {
+                let changes: Vec<String> = changes
+                    .iter()
+                    .map(|change| {
+                        format!(
+                            "{}: {} -> {}",
+                            change.name,
+                            optional(&change.old),
+                            optional(&change.new)
+                        )
+                    })
+                    .collect();
+                let changes = if changes.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", changes.join(", "))
+                };
+                format!("{}line {}: {}{}", indent(*depth), line, kind, changes)
+            }
+            Record::Condition {
+                line,
+                depth,
+                value,
+                truthy,
+            } => format!(
+                "{}line {}: condition {} is {}",
+                indent(*depth),
+                line,
+                describe(value),
+                truthy
+            ),
+            Record::Enter { depth, name, args } => {
+                let args: Vec<String> = args.iter().map(describe).collect();
+                format!(
+                    "{}enter {} taking {}",
+                    indent(*depth),
+                    name,
+                    args.join(", ")
+                )
+            }
+            Record::Exit { depth, name } => format!("{}exit {}", indent(*depth), name),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Record::Command {
+                line,
+                depth,
+                kind,
+                changes,
+            } => {
+                let changes: Vec<serde_json::Value> = changes
+                    .iter()
+                    .map(|change| {
+                        json!({
+                            "name": change.name,
+                            "old": change.old.as_ref().map(describe),
+                            "new": change.new.as_ref().map(describe),
+                        })
+                    })
+                    .collect();
+                json!({
+                    "event": "command",
+                    "line": line,
+                    "depth": depth,
+                    "kind": kind,
+                    "changes": changes,
+                })
+            }
+            Record::Condition {
+                line,
+                depth,
+                value,
+                truthy,
+            } => json!({
+                "event": "condition",
+                "line": line,
+                "depth": depth,
+                "value": describe(value),
+                "truthy": truthy,
+            }),
+            Record::Enter { depth, name, args } => json!({
+                "event": "enter",
+                "depth": depth,
+                "function": name,
+                "args": args.iter().map(describe).collect::<Vec<String>>(),
+            }),
+            Record::Exit { depth, name } => json!({
+                "event": "exit",
+                "depth": depth,
+                "function": name,
+            }),
+        }
+    }
+}
+
+fn indent(depth: u32) -> String {
+    "  ".repeat(depth as usize)
+}
+
+pub fn command_kind(command: &Command) -> &'static str {
+    match command {
+        Command::Assignment { .. } => "Assignment",
+        Command::Until { .. } => "Until",
+        Command::While { .. } => "While",
+        Command::If { .. } => "If",
+        Command::Increment { .. } => "Increment",
+        Command::Decrement { .. } => "Decrement",
+        Command::Continue => "Continue",
+        Command::Break => "Break",
+        Command::Say { .. } => "Say",
+        Command::Listen { .. } => "Listen",
+        Command::FunctionDeclaration { .. } => "FunctionDeclaration",
+        Command::Return { .. } => "Return",
+        Command::Call { .. } => "Call",
+        Command::Floor { .. } => "Floor",
+        Command::Ceil { .. } => "Ceil",
+        Command::Round { .. } => "Round",
+        Command::Mutation { .. } => "Mutation",
+    }
+}
+
+fn has_block(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Until { .. } | Command::While { .. } | Command::If { .. }
+    )
+}
+
+fn changes(
+    before: &HashMap<String, (VariableType, Value)>,
+    after: &HashMap<String, (VariableType, Value)>,
+) -> Vec<Change> {
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old = before.get(name).map(|(_, value)| value);
+            let new = after.get(name).map(|(_, value)| value);
+            if old == new {
+                None
+            } else {
+                Some(Change {
+                    name: name.to_string(),
+                    old: old.cloned(),
+                    new: new.cloned(),
+                })
+            }
+        })
+        .collect()
+}
+
+/// `Hook` that writes out a `Record` for each step of the program
+pub struct Tracer<'a> {
+    output: &'a mut dyn Write,
+    format: TraceFormat,
+    // Variables from before each command that's currently running
+    before: Vec<HashMap<String, (VariableType, Value)>>,
+    depth: u32,
+}
+
+impl<'a> Tracer<'a> {
+    pub fn new(output: &'a mut dyn Write, format: TraceFormat) -> Tracer<'a> {
+        Tracer {
+            output,
+            format,
+            before: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    fn record(&mut self, record: Record) {
+        let line = match self.format {
+            TraceFormat::Text => record.to_text(),
+            TraceFormat::Json => record.to_json().to_string(),
+        };
+        // Tracing is best effort, and shouldn't stop the program itself
+        writeln!(self.output, "{}", line).unwrap_or(());
+    }
+}
+
+impl<'a> Hook for Tracer<'a> {
+    fn command(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.depth = snapshot.depth;
+        self.before.push(snapshot.variables.clone());
+        if has_block(snapshot.command) {
+            self.record(Record::Command {
+                line: snapshot.line,
+                depth: snapshot.depth,
+                kind: command_kind(snapshot.command),
+                changes: vec![],
+            });
+        }
+        Ok(())
+    }
+
+    fn command_done(&mut self, snapshot: &Snapshot) {
+        let before = self.before.pop().unwrap_or_default();
+        if has_block(snapshot.command) {
+            return;
+        }
+        self.record(Record::Command {
+            line: snapshot.line,
+            depth: snapshot.depth,
+            kind: command_kind(snapshot.command),
+            changes: changes(&before, snapshot.variables),
+        });
+    }
+
+    fn condition(&mut self, line: usize, value: &Value, truthy: bool) {
+        self.record(Record::Condition {
+            line,
+            depth: self.depth,
+            value: value.clone(),
+            truthy,
+        });
+    }
+
+    fn enter_function(&mut self, name: &str, args: &[Value]) {
+        self.record(Record::Enter {
+            depth: self.depth + 1,
+            name: name.to_string(),
+            args: args.to_vec(),
+        });
+    }
+
+    fn exit_function(&mut self, name: &str) {
+        self.record(Record::Exit {
+            depth: self.depth,
+            name: name.to_string(),
+        });
+        self.depth = self.depth.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TraceFormat, Tracer};
+    use crate::{parser, runner};
+    use pretty_assertions::assert_eq;
+    use std::io::{self, Cursor};
+
+    fn trace(code: &str, format: TraceFormat) -> String {
+        let mut program = parser::parse(code).unwrap();
+        let mut output = Vec::new();
+        {
+            let mut tracer = Tracer::new(&mut output, format);
+            let mut writer = Cursor::new(Vec::new());
+            runner::run_with_hook(&mut program, &mut io::empty(), &mut writer, &mut tracer)
+                .unwrap();
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    const PROGRAM: &str = "Midnight takes your heart
+Give back your heart with 1
+
+My world is 1
+While my world is as low as 2
+Put Midnight taking my world into my world
+
+Say my world
+";
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            trace(PROGRAM, TraceFormat::Text),
+            "line 1: FunctionDeclaration
+line 4: Assignment (my world: unset -> 1)
+line 5: While
+line 5: condition true is true
+  enter Midnight taking 1
+  line 2: Return
+  exit Midnight
+line 6: Assignment (my world: 1 -> 2)
+line 5: condition true is true
+  enter Midnight taking 2
+  line 2: Return
+  exit Midnight
+line 6: Assignment (my world: 2 -> 3)
+line 5: condition false is false
+line 8: Say
+"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let lines: Vec<String> = trace(
+            "My world is 1\nIf my world is 1\nSay it\n",
+            TraceFormat::Json,
+        )
+        .lines()
+        .map(|line| line.to_string())
+        .collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"changes":[{"name":"my world","new":"1","old":null}],"depth":0,"event":"command","kind":"Assignment","line":1}"#,
+                r#"{"changes":[],"depth":0,"event":"command","kind":"If","line":2}"#,
+                r#"{"depth":0,"event":"condition","line":2,"truthy":true,"value":"true"}"#,
+                r#"{"changes":[],"depth":0,"event":"command","kind":"Say","line":3}"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,457 @@
+use super::{alterable, mutation, unsupported, Mutation, Place};
+use crate::common::{Block, Command, CommandLine, Expression, Program, Result};
+use std::collections::HashSet;
+
+// Value handling and the `Env` class the generated code is written against
+const RUNTIME: &str = include_str!("js_runtime.js");
+
+const INDENT: &str = "  ";
+
+// Runs the program with stdio when it's used as a script in node
+const NODE_MAIN: &str = r#"
+if (typeof module !== "undefined") {
+  module.exports = { run, MaidenError };
+  if (typeof require !== "undefined" && require.main === module) {
+    let lines = null;
+    const input = () => {
+      if (lines === null) {
+        lines = require("fs").readFileSync(0, "utf8").split("\n");
+      }
+      return lines.length > 0 ? lines.shift() : null;
+    };
+    try {
+      run((line) => process.stdout.write(line + "\n"), input);
+    } catch (err) {
+      if (!(err instanceof MaidenError)) {
+        throw err;
+      }
+      process.stderr.write(`Error: ${err.message}\n`);
+      process.exitCode = 1;
+    }
+  }
+}
+"#;
+
+fn string(value: &str) -> String {
+    let mut res = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() || c == '\u{2028}' || c == '\u{2029}' => {
+                res.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+fn number(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == std::f64::INFINITY {
+        "Infinity".to_string()
+    } else if value == std::f64::NEG_INFINITY {
+        "-Infinity".to_string()
+    } else {
+        // Rust's Debug output for floats is also valid JavaScript
+        format!("{:?}", value)
+    }
+}
+
+fn nullable(name: Option<&str>) -> String {
+    match name {
+        Some(name) => string(name),
+        None => "null".to_string(),
+    }
+}
+
+// The expression of an If/While/Until, which also points the pronoun at
+// the variable it tests
+fn condition(expression: &Expression, line: usize) -> Result<String> {
+    let value = self::expression(expression, line)?;
+    Ok(match expression.subject() {
+        Some(subject) => format!("env.tested({}, {})", string(subject), value),
+        None => value,
+    })
+}
+
+// Expressions the interpreter doesn't evaluate any further
+// What a split or join into another variable works on
+fn split_source(expression: &Expression, line: usize) -> Result<String> {
+    Ok(match expression {
+        Expression::Pronoun => "variable(null)".to_string(),
+        other => literal(other, line)?,
+    })
+}
+
+fn literal(expression: &Expression, line: usize) -> Result<String> {
+    Ok(match expression {
+        Expression::String(s) => string(s),
+        Expression::Floating(f) => number(*f),
+        Expression::Variable(name) => format!("variable({})", string(name)),
+        Expression::ArrayRef { name, index } => match **name {
+            Expression::Variable(ref name) => {
+                format!("arrayRef({}, {})", string(name), literal(index, line)?)
+            }
+            // The pronoun could move on before the lookup is resolved
+            Expression::Pronoun => {
+                format!("arrayRef(env.currentPronoun(), {})", literal(index, line)?)
+            }
+            ref other => return unsupported(format!("Array named by {:?}", other), line),
+        },
+        Expression::True => "true".to_string(),
+        Expression::False => "false".to_string(),
+        Expression::Null => "null".to_string(),
+        Expression::Mysterious => "MYSTERIOUS".to_string(),
+        other => return unsupported(format!("Literal {:?}", other), line),
+    })
+}
+
+fn expression(expression: &Expression, line: usize) -> Result<String> {
+    let binop = |method: &str, first: &Expression, second: &Expression| -> Result<String> {
+        Ok(format!(
+            "env.{}({}, {})",
+            method,
+            self::expression(first, line)?,
+            self::expression(second, line)?
+        ))
+    };
+    // The logical operators only evaluate their second argument if they have to
+    let lazy = |method: &str, first: &Expression, second: &Expression| -> Result<String> {
+        Ok(format!(
+            "env.{}({}, () => {})",
+            method,
+            self::expression(first, line)?,
+            self::expression(second, line)?
+        ))
+    };
+    match expression {
+        Expression::Variable(name) => Ok(format!("env.get({})", string(name))),
+        Expression::Pronoun => Ok("env.pronoun()".to_string()),
+        Expression::Call(name, args) => {
+            let args = args
+                .iter()
+                .map(|arg| self::expression(arg, line))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(format!(
+                "env.call({}, {}, () => [{}])",
+                string(name),
+                args.len(),
+                args.join(", ")
+            ))
+        }
+        Expression::Not(arg) => Ok(format!("env.not({})", self::expression(arg, line)?)),
+        Expression::Is(first, second) => {
+            if let Expression::Not(not_second) = &**second {
+                binop("aint", first, not_second)
+            } else {
+                binop("is", first, second)
+            }
+        }
+        Expression::Aint(first, second) => binop("aint", first, second),
+        Expression::GreaterThan(first, second) => binop("greater", first, second),
+        Expression::GreaterThanOrEqual(first, second) => binop("greaterOrEqual", first, second),
+        Expression::LessThan(first, second) => binop("less", first, second),
+        Expression::LessThanOrEqual(first, second) => binop("lessOrEqual", first, second),
+        Expression::Add(first, second) => binop("add", first, second),
+        Expression::Subtract(first, second) => binop("subtract", first, second),
+        Expression::Times(first, second) => binop("times", first, second),
+        Expression::Divide(first, second) => binop("divide", first, second),
+        Expression::And(first, second) => lazy("and", first, second),
+        Expression::Or(first, second) => lazy("or", first, second),
+        Expression::Nor(first, second) => lazy("nor", first, second),
+        _ => literal(expression, line),
+    }
+}
+
+fn push(out: &mut String, depth: usize, text: &str) {
+    out.push_str(&INDENT.repeat(depth));
+    out.push_str(text);
+    out.push('\n');
+}
+
+#[derive(Default)]
+struct Generator {
+    // Function and block bodies, each a complete JavaScript function
+    items: Vec<String>,
+    names: HashSet<String>,
+    blocks: usize,
+}
+
+impl Generator {
+    fn name(&mut self, base: &str) -> String {
+        let mut name = base.to_string();
+        let mut count = 1;
+        while self.names.contains(&name) {
+            count += 1;
+            name = format!("{}_{}", base, count);
+        }
+        self.names.insert(name.clone());
+        name
+    }
+
+    fn function(&mut self, name: &str, commands: &[CommandLine]) -> Result<()> {
+        let mut out = format!("function {}(env) {{\n", name);
+        self.commands(commands, 1, &mut out)?;
+        match commands.last().map(|c| &c.cmd) {
+            Some(Command::Return { .. }) | Some(Command::Break) | Some(Command::Continue) => {}
+            _ => push(&mut out, 1, "return NOTHING;"),
+        }
+        out.push_str("}\n");
+        self.items.push(out);
+        Ok(())
+    }
+
+    fn block(&mut self, block: &Block) -> Result<String> {
+        self.blocks += 1;
+        let name = format!("block_{}", self.blocks);
+        self.function(&name, &block.commands)?;
+        Ok(name)
+    }
+
+    fn run_block(&mut self, block: &Block, depth: usize, out: &mut String) -> Result<()> {
+        let name = self.block(block)?;
+        push(out, depth, &format!("const res = env.block({});", name));
+        push(out, depth, "if (res !== NOTHING) {");
+        push(out, depth + 1, "return res;");
+        push(out, depth, "}");
+        Ok(())
+    }
+
+    // Runs a loop's block, stopping the loop on a break and the function on a return
+    fn loop_block(&mut self, block: &Block, depth: usize, out: &mut String) -> Result<()> {
+        let name = self.block(block)?;
+        push(out, depth, &format!("const res = env.block({});", name));
+        push(out, depth, "if (res === BREAK) {");
+        push(out, depth + 1, "break;");
+        push(out, depth, "}");
+        push(out, depth, "if (res !== NOTHING && res !== CONTINUE) {");
+        push(out, depth + 1, "return res;");
+        push(out, depth, "}");
+        Ok(())
+    }
+
+    fn commands(&mut self, commands: &[CommandLine], depth: usize, out: &mut String) -> Result<()> {
+        for command in commands {
+            self.command(command, depth, out)?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    fn command(&mut self, command: &CommandLine, depth: usize, out: &mut String) -> Result<()> {
+        let line = command.line;
+        let statement = match command.cmd {
+            Command::Assignment {
+                ref target,
+                ref value,
+            } => {
+                let value = expression(value, line)?;
+                match **target {
+                    Expression::Variable(ref name) => {
+                        format!("env.assign({}, {});", string(name), value)
+                    }
+                    Expression::Pronoun => format!("env.assignPronoun({});", value),
+                    Expression::ArrayRef {
+                        ref name,
+                        ref index,
+                    } => match **name {
+                        Expression::Variable(ref name) => format!(
+                            "env.assignIndex({}, {}, {});",
+                            string(name),
+                            literal(index, line)?,
+                            value
+                        ),
+                        ref other => {
+                            return unsupported(format!("Array named by {:?}", other), line)
+                        }
+                    },
+                    ref other => return unsupported(format!("Assign to {:?}", other), line),
+                }
+            }
+            Command::Increment { ref target, count } => format!(
+                "env.alter({}, (x) => x + {}, {});",
+                nullable(alterable(target, line)?),
+                number(count),
+                count as usize
+            ),
+            Command::Decrement { ref target, count } => format!(
+                "env.alter({}, (x) => x - {}, {});",
+                nullable(alterable(target, line)?),
+                number(count),
+                count as usize
+            ),
+            Command::Until {
+                ref expression,
+                ref block,
+            } => {
+                push(
+                    out,
+                    depth,
+                    &format!("while (!env.truthy({})) {{", condition(expression, line)?),
+                );
+                self.loop_block(block, depth + 1, out)?;
+                "}".to_string()
+            }
+            Command::While {
+                ref expression,
+                ref block,
+            } => {
+                push(
+                    out,
+                    depth,
+                    &format!("while (env.truthy({})) {{", condition(expression, line)?),
+                );
+                self.loop_block(block, depth + 1, out)?;
+                "}".to_string()
+            }
+            Command::Continue => "return CONTINUE;".to_string(),
+            Command::Break => "return BREAK;".to_string(),
+            Command::Say { ref value } => format!("env.say({});", expression(value, line)?),
+            Command::FunctionDeclaration {
+                ref name,
+                ref args,
+                ref block,
+            } => {
+                let sanitised: String = name
+                    .to_lowercase()
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect();
+                let function = self.name(&format!("fn_{}", sanitised));
+                self.function(&function, &block.commands)?;
+                let args: Vec<String> = args.iter().map(|a| string(a)).collect();
+                format!(
+                    "env.declare({}, [{}], {});",
+                    string(name),
+                    args.join(", "),
+                    function
+                )
+            }
+            Command::Return { ref return_value } => {
+                format!("return {};", expression(return_value, line)?)
+            }
+            Command::If {
+                ref expression,
+                ref then,
+                ref otherwise,
+            } => {
+                push(
+                    out,
+                    depth,
+                    &format!("if (env.truthy({})) {{", condition(expression, line)?),
+                );
+                if let Some(block) = then {
+                    self.run_block(block, depth + 1, out)?;
+                }
+                if let Some(block) = otherwise {
+                    push(out, depth, "} else {");
+                    self.run_block(block, depth + 1, out)?;
+                }
+                "}".to_string()
+            }
+            Command::Call { ref name, ref args } => {
+                let call = Expression::Call(name.clone(), args.clone());
+                format!("{};", expression(&call, line)?)
+            }
+            Command::Listen { ref target } => format!(
+                "env.listen({});",
+                nullable(target.as_ref().map(|t| t.as_str()))
+            ),
+            Command::Round { ref target } => format!(
+                "env.round({}, roundHalfAwayFromZero);",
+                nullable(alterable(target, line)?)
+            ),
+            Command::Ceil { ref target } => format!(
+                "env.round({}, Math.ceil);",
+                nullable(alterable(target, line)?)
+            ),
+            Command::Floor { ref target } => format!(
+                "env.round({}, Math.floor);",
+                nullable(alterable(target, line)?)
+            ),
+            Command::Mutation {
+                ref mutator,
+                ref source,
+                ref target,
+                ref lookup,
+                ref modifier,
+            } => match mutation(mutator, source, target, lookup, modifier, line)? {
+                Mutation::Cast(name) => format!("env.cast({});", nullable(name)),
+                Mutation::Split(Place::InPlace(name), separator) => {
+                    format!("env.split({}, {});", nullable(name), string(separator))
+                }
+                Mutation::Split(Place::Into(source, target), separator) => format!(
+                    "env.splitInto({}, {}, {});",
+                    split_source(source, line)?,
+                    nullable(target),
+                    string(separator)
+                ),
+                Mutation::Join(Place::InPlace(name), separator) => {
+                    format!("env.join({}, {});", nullable(name), string(separator))
+                }
+                Mutation::Join(Place::Into(source, target), separator) => format!(
+                    "env.joinInto({}, {}, {});",
+                    split_source(source, line)?,
+                    nullable(target),
+                    string(separator)
+                ),
+            },
+        };
+        push(out, depth, &statement);
+        Ok(())
+    }
+}
+
+pub fn generate(program: &Program) -> Result<String> {
+    let mut generator = Generator::default();
+    generator.function("program", &program.commands)?;
+    let mut out = String::from("// Generated by maiden from a Rockstar program\n");
+    out.push_str(RUNTIME);
+    for item in &generator.items {
+        out.push('\n');
+        out.push_str(item);
+    }
+    out.push_str(
+        "
+// `output` is called with each line said, and `input` is called for each
+// Listen, returning the next line (without its newline) or null at the end
+function run(output, input) {
+  program(new Env(output, input));
+}
+",
+    );
+    out.push_str(NODE_MAIN);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate;
+    use crate::parser;
+
+    fn transpile(code: &str) -> String {
+        generate(&parser::parse(code).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn loops_and_logic() {
+        let out = transpile("While 1 is 2 and 3 is 4\nSay \"x\"\n\nUntil 1 is 1\nSay \"y\"\n");
+        assert!(
+            out.contains("while (env.truthy(env.and(env.is(1.0, 2.0), () => env.is(3.0, 4.0)))) {")
+        );
+        assert!(out.contains("const res = env.block(block_1);"));
+        assert!(out.contains("while (!env.truthy(env.is(1.0, 1.0))) {"));
+    }
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(super::string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+}
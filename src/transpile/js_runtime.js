@@ -0,0 +1,720 @@
+// Runtime included in everything `maiden transpile --target js` generates.
+// It mirrors the value handling in runner.rs so the generated programs print
+// exactly what the interpreter does. Rockstar values map onto JavaScript ones
+// where they can: strings, numbers, booleans and null, plus a few markers.
+"use strict";
+
+const MYSTERIOUS = { toString: () => "Mysterious" };
+const NOTHING = { toString: () => "Nothing" };
+const BREAK = { toString: () => "Break" };
+const CONTINUE = { toString: () => "Continue" };
+
+class MaidenError extends Error {}
+
+// Unevaluated variable, only used as an array index
+function variable(name) {
+  return { variable: name };
+}
+
+// Array lookup, which like in the interpreter is only resolved when printed
+function arrayRef(name, index) {
+  return { arrayRef: name, index };
+}
+
+function array(numeric, strings) {
+  return { numeric: new Map(numeric), strings: new Map(strings) };
+}
+
+function cloneArray(value) {
+  return { numeric: new Map(value.numeric), strings: new Map(value.strings) };
+}
+
+function isArray(value) {
+  return value !== null && typeof value === "object" && value.numeric instanceof Map;
+}
+
+// Rough equivalent of the interpreter's Debug output, for error messages
+function describe(value) {
+  if (typeof value === "string") {
+    return JSON.stringify(value);
+  }
+  if (isArray(value)) {
+    return "Array";
+  }
+  if (value !== null && typeof value === "object") {
+    for (const kind of ["variable", "arrayRef", "object"]) {
+      if (kind in value) {
+        return `${kind}(${value[kind]})`;
+      }
+    }
+  }
+  return String(value);
+}
+
+// Equivalent of Rust's `x as usize`
+function toIndex(value) {
+  if (Number.isNaN(value) || value < 0) {
+    return 0;
+  }
+  return Math.trunc(value);
+}
+
+// Formats numbers like Rust's `{}` does for f64, i.e. never in exponent form
+function formatNumber(value) {
+  if (Number.isNaN(value)) {
+    return "NaN";
+  }
+  if (value === Infinity) {
+    return "inf";
+  }
+  if (value === -Infinity) {
+    return "-inf";
+  }
+  if (Object.is(value, -0)) {
+    return "-0";
+  }
+  const text = String(value);
+  const match = /^(-?)(\d+)(?:\.(\d+))?e([+-]\d+)$/.exec(text);
+  if (!match) {
+    return text;
+  }
+  const [, sign, whole, fraction = "", exponentText] = match;
+  const digits = whole + fraction;
+  const point = whole.length + Number(exponentText);
+  if (point <= 0) {
+    return sign + "0." + "0".repeat(-point) + digits;
+  }
+  if (point >= digits.length) {
+    return sign + digits + "0".repeat(point - digits.length);
+  }
+  return sign + digits.slice(0, point) + "." + digits.slice(point);
+}
+
+// Same inputs as Rust's `str::parse::<f64>`
+function parseNumber(text) {
+  if (/^[+-]?(inf|infinity)$/i.test(text)) {
+    return text.startsWith("-") ? -Infinity : Infinity;
+  }
+  if (/^[+-]?nan$/i.test(text)) {
+    return NaN;
+  }
+  if (/^[+-]?(\d+\.?\d*|\.\d+)([eE][+-]?\d+)?$/.test(text)) {
+    return Number(text);
+  }
+  return undefined;
+}
+
+function toNumber(value) {
+  if (typeof value === "number") {
+    return value;
+  }
+  if (value === null) {
+    return 0;
+  }
+  if (typeof value === "string") {
+    const number = parseNumber(value);
+    if (number === undefined) {
+      throw new MaidenError(`Unparsable number: '${value}'`);
+    }
+    return number;
+  }
+  throw new MaidenError(`Unimplemented: Can't convert ${describe(value)} to number`);
+}
+
+function toBoolean(value) {
+  if (value === false || value === MYSTERIOUS || value === null) {
+    return false;
+  }
+  if (value === true) {
+    return true;
+  }
+  if (typeof value === "number") {
+    return value !== 0;
+  }
+  if (typeof value === "string") {
+    return value !== "";
+  }
+  if (value !== null && typeof value === "object" && "object" in value) {
+    return true;
+  }
+  throw new MaidenError(`Bad boolean resolve: ${describe(value)}`);
+}
+
+function tryBoolean(value) {
+  try {
+    return toBoolean(value);
+  } catch (err) {
+    if (err instanceof MaidenError) {
+      return undefined;
+    }
+    throw err;
+  }
+}
+
+// Rust only orders two numbers or two strings; anything else compares false
+function ordered(first, second) {
+  return (
+    (typeof first === "number" && typeof second === "number") ||
+    (typeof first === "string" && typeof second === "string")
+  );
+}
+
+// Rust's f64::round, which rounds halves away from zero
+function roundHalfAwayFromZero(value) {
+  return Math.sign(value) * Math.round(Math.abs(value));
+}
+
+function splitArray(text, separator) {
+  const parts = separator === "" ? Array.from(text) : text.split(separator);
+  return array(
+    parts.filter((part) => part !== "").map((part, index) => [index, part]),
+    []
+  );
+}
+
+function joinArray(numeric, separator) {
+  let res = "";
+  for (const key of Array.from(numeric.keys()).sort((a, b) => a - b)) {
+    const value = numeric.get(key);
+    if (typeof value !== "string") {
+      throw new MaidenError(`Unimplemented: Join with non-string: ${describe(value)}`);
+    }
+    // Like the interpreter, leading empty strings don't get a separator
+    res = res === "" ? value : res + separator + value;
+  }
+  return res;
+}
+
+class Env {
+  // `output` is called with each line the program says, and `input` should
+  // return the next line of input (without its newline), or null at the end
+  constructor(output, input) {
+    this.output = output;
+    this.input = input;
+    this.variables = new Map();
+    this.functions = new Map();
+    this.pronounName = null;
+    this.depth = 0;
+    this.frames = [];
+  }
+
+  variableType() {
+    return this.depth === 0 ? "global" : "local";
+  }
+
+  lookup(name) {
+    if (!this.variables.has(name)) {
+      throw new MaidenError(`Missing variable '${name}'`);
+    }
+    return this.variables.get(name);
+  }
+
+  currentPronoun() {
+    if (this.pronounName === null) {
+      throw new MaidenError("Got to a pronoun, but no variable defined");
+    }
+    return this.pronounName.toLowerCase();
+  }
+
+  get(name) {
+    const entry = this.variables.get(name.toLowerCase());
+    if (entry !== undefined) {
+      return entry.value;
+    }
+    const func = this.functions.get(name.toLowerCase());
+    if (func !== undefined) {
+      // A function used as a value keeps the local variables it could see
+      const captured = new Map();
+      for (const [variable, { kind, value }] of this.variables) {
+        if (kind === "local") {
+          captured.set(variable, value);
+        }
+      }
+      return { object: name, func, captured };
+    }
+    throw new MaidenError(`Missing variable '${name}'`);
+  }
+
+  // The pronoun refers to whichever variable was last assigned or tested
+  refer(name) {
+    this.pronounName = name;
+  }
+
+  // Passes through the value of a condition that tests `name`
+  tested(name, value) {
+    this.refer(name);
+    return value;
+  }
+
+  pronoun() {
+    return this.lookup(this.currentPronoun()).value;
+  }
+
+  assign(name, value) {
+    this.refer(name);
+    const existing = this.variables.get(name.toLowerCase());
+    const kind = existing === undefined ? this.variableType() : existing.kind;
+    this.variables.set(name.toLowerCase(), { kind, value });
+  }
+
+  assignPronoun(value) {
+    this.assign(this.currentPronoun(), value);
+  }
+
+  assignIndex(name, index, value) {
+    if (index !== null && typeof index === "object" && "variable" in index) {
+      index = this.lookup(index.variable).value;
+    }
+    const entry = this.variables.get(name) || { kind: this.variableType(), value: array([], []) };
+    if (!isArray(entry.value)) {
+      throw new MaidenError(
+        `Unimplemented: Array ref assignment to non-array ${name} ${describe(index)}`
+      );
+    }
+    // Copy first, as other variables may share the same array
+    const target = cloneArray(entry.value);
+    this.variables.set(name, { kind: entry.kind, value: target });
+    if (typeof index === "number") {
+      target.numeric.set(toIndex(index), value);
+    } else if (typeof index === "string") {
+      target.strings.set(index, value);
+    } else {
+      throw new MaidenError(`Unimplemented: Index assignment with ${describe(index)}`);
+    }
+  }
+
+  printable(value) {
+    if (typeof value === "number") {
+      return formatNumber(value);
+    }
+    if (typeof value === "string") {
+      return value;
+    }
+    if (value === true) {
+      return "true";
+    }
+    if (value === false) {
+      return "false";
+    }
+    if (value === MYSTERIOUS) {
+      return "mysterious";
+    }
+    if (value === null) {
+      return "null";
+    }
+    if (isArray(value)) {
+      return String(Math.max(-1, ...value.numeric.keys()) + 1);
+    }
+    if (value !== null && typeof value === "object" && "variable" in value) {
+      return this.printable(this.lookup(value.variable.toLowerCase()).value);
+    }
+    if (value !== null && typeof value === "object" && "arrayRef" in value) {
+      const target = this.lookup(value.arrayRef.toLowerCase()).value;
+      let index = value.index;
+      if (index !== null && typeof index === "object" && "variable" in index) {
+        index = this.lookup(index.variable).value;
+      }
+      let entry;
+      if (isArray(target) && typeof index === "string") {
+        entry = target.strings.get(index);
+      } else if (isArray(target) && typeof index === "number") {
+        entry = target.numeric.get(toIndex(index));
+      } else if (typeof target === "string" && typeof index === "number") {
+        // By code point rather than UTF-16 unit, as the interpreter indexes by char
+        entry = Array.from(target)[toIndex(index)];
+      } else {
+        throw new MaidenError(
+          `Unimplemented: Lookup of ${describe(target)} with ${describe(index)}`
+        );
+      }
+      return this.printable(entry === undefined ? MYSTERIOUS : entry);
+    }
+    throw new MaidenError(`Unimplemented: Say '${describe(value)}'`);
+  }
+
+  say(value) {
+    this.output(this.printable(value));
+  }
+
+  listen(target) {
+    const line = this.input();
+    if (target !== null) {
+      this.refer(target);
+      this.variables.set(target.toLowerCase(), {
+        kind: this.variableType(),
+        value: line === null || line === undefined ? "" : line,
+      });
+    }
+  }
+
+  compare(first, second, f) {
+    if (first === true || first === false) {
+      if (second === true || second === false) {
+        return f(first, second);
+      }
+      const b = tryBoolean(second);
+      if (b !== undefined) {
+        return f(first, b);
+      }
+    } else if (typeof first === "string") {
+      if (typeof second === "string") {
+        return f(first, second);
+      }
+      if (second === null) {
+        return false;
+      }
+    } else if (first === MYSTERIOUS) {
+      return second === MYSTERIOUS;
+    }
+    if (second === true || second === false) {
+      const b = tryBoolean(first);
+      if (b !== undefined) {
+        return f(b, second);
+      }
+    }
+    let converted;
+    try {
+      converted = toNumber(second);
+    } catch (err) {
+      if (err instanceof MaidenError) {
+        return false;
+      }
+      throw err;
+    }
+    return f(toNumber(first), converted);
+  }
+
+  is(first, second) {
+    return this.compare(first, second, (f, s) => f === s);
+  }
+
+  aint(first, second) {
+    return this.compare(first, second, (f, s) => f !== s);
+  }
+
+  greater(first, second) {
+    return this.compare(first, second, (f, s) => ordered(f, s) && f > s);
+  }
+
+  greaterOrEqual(first, second) {
+    return this.compare(first, second, (f, s) => ordered(f, s) && f >= s);
+  }
+
+  less(first, second) {
+    return this.compare(first, second, (f, s) => ordered(f, s) && f < s);
+  }
+
+  lessOrEqual(first, second) {
+    return this.compare(first, second, (f, s) => ordered(f, s) && f <= s);
+  }
+
+  // The logical operators only evaluate their second argument if they have to
+  and(first, second) {
+    if (first === false) {
+      return false;
+    }
+    return this.compare(first, second(), (f, s) => toBoolean(f) && toBoolean(s));
+  }
+
+  or(first, second) {
+    if (first === true) {
+      return true;
+    }
+    return this.compare(first, second(), (f, s) => toBoolean(f) || toBoolean(s));
+  }
+
+  nor(first, second) {
+    if (first === true) {
+      return false;
+    }
+    return !this.compare(first, second(), (f, s) => toBoolean(f) || toBoolean(s));
+  }
+
+  not(value) {
+    const b = tryBoolean(value);
+    return b === undefined ? false : !b;
+  }
+
+  truthy(value) {
+    return toBoolean(value);
+  }
+
+  math(op, first, second, f) {
+    const printable = (value) => {
+      try {
+        return this.printable(value);
+      } catch (err) {
+        if (err instanceof MaidenError) {
+          return undefined;
+        }
+        throw err;
+      }
+    };
+    if (typeof first === "number") {
+      if (typeof second === "number") {
+        return f(first, second);
+      }
+      if (typeof second === "string" && op === "add") {
+        return formatNumber(first) + second;
+      }
+      if (typeof second === "string" && op === "times") {
+        return second.repeat(toIndex(first));
+      }
+      if (second === null) {
+        return f(first, 0);
+      }
+    } else if (typeof first === "string") {
+      if (op === "add") {
+        const text = printable(second);
+        if (text !== undefined) {
+          return first + text;
+        }
+      } else if (op === "times") {
+        if (typeof second === "number") {
+          return first.repeat(toIndex(second));
+        }
+        if (second === null) {
+          return "";
+        }
+      }
+    } else if (first === null) {
+      if (typeof second === "number") {
+        return f(0, second);
+      }
+      if (typeof second === "string" && op === "add") {
+        return "null" + second;
+      }
+      if (second === null) {
+        return f(0, 0);
+      }
+    } else if (op === "add" && typeof second === "string") {
+      const text = printable(first);
+      if (text !== undefined) {
+        return text + second;
+      }
+    }
+    throw new MaidenError(
+      `Unimplemented: Math op (${op}) on values we can't apply: ${describe(first)} ${describe(second)}`
+    );
+  }
+
+  add(first, second) {
+    return this.math("add", first, second, (f, s) => f + s);
+  }
+
+  subtract(first, second) {
+    return this.math("subtract", first, second, (f, s) => f - s);
+  }
+
+  times(first, second) {
+    return this.math("times", first, second, (f, s) => f * s);
+  }
+
+  divide(first, second) {
+    const res = this.math("divide", first, second, (f, s) => f / s);
+    if (res === Infinity) {
+      throw new MaidenError(
+        `Got infinity on divide between ${describe(first)} and ${describe(second)}`
+      );
+    }
+    return res;
+  }
+
+  targetName(target) {
+    return target === null ? this.currentPronoun() : target.toLowerCase();
+  }
+
+  alter(target, f, count) {
+    if (target !== null) {
+      this.refer(target);
+    }
+    const name = this.targetName(target);
+    const { kind, value } = this.lookup(name);
+    let altered;
+    if (typeof value === "number") {
+      altered = f(value);
+    } else if (value === null) {
+      altered = f(0);
+    } else if (value === true || value === false) {
+      // double-flips do nothing, so just look at the low bit
+      altered = count % 2 === 1 ? !value : value;
+    } else {
+      throw new MaidenError(`Unimplemented: Attempt to alter non-integer '${name}'`);
+    }
+    this.variables.set(name, { kind, value: altered });
+  }
+
+  round(target, f) {
+    if (target !== null) {
+      this.refer(target);
+    }
+    const name = this.targetName(target);
+    const { kind, value } = this.lookup(name);
+    if (typeof value === "number") {
+      this.variables.set(name, { kind, value: f(value) });
+    } else if (value === null) {
+      this.variables.set(name, { kind, value: f(0) });
+    } else {
+      throw new MaidenError(`Unimplemented: Attempt to alter non-integer '${name}'`);
+    }
+  }
+
+  cast(target) {
+    const name = this.targetName(target);
+    this.refer(name);
+    const { kind, value } = this.lookup(name);
+    let cast;
+    if (typeof value === "string") {
+      cast = parseNumber(value);
+      const chars = Array.from(value);
+      if (cast === undefined && chars.length === 1) {
+        cast = chars[0].codePointAt(0);
+      }
+      if (cast === undefined) {
+        throw new MaidenError(`Unparsable number: '${value}'`);
+      }
+    } else if (typeof value === "number") {
+      const code = value >= 4294967295 ? 4294967295 : toIndex(value);
+      if (code > 0x10ffff || (code >= 0xd800 && code <= 0xdfff)) {
+        throw new MaidenError(`Unimplemented: Cast for ${formatNumber(value)}`);
+      }
+      cast = String.fromCodePoint(code);
+    } else {
+      throw new MaidenError(`Unimplemented: Cast for ${describe(value)}`);
+    }
+    this.variables.set(name, { kind, value: cast });
+  }
+
+  split(target, separator) {
+    const name = this.targetName(target);
+    this.refer(name);
+    const { kind, value } = this.lookup(name);
+    if (typeof value !== "string") {
+      throw new MaidenError(`Unimplemented: Split for ${describe(value)}`);
+    }
+    this.variables.set(name, { kind, value: splitArray(value, separator) });
+  }
+
+  splitInto(source, target, separator) {
+    const { kind, value } = this.source(source);
+    if (typeof value !== "string") {
+      throw new MaidenError(`Unimplemented: Split for ${describe(value)}`);
+    }
+    this.mutated(target, kind, splitArray(value, separator));
+  }
+
+  join(target, separator) {
+    const name = this.targetName(target);
+    this.refer(name);
+    const { kind, value } = this.lookup(name);
+    if (!isArray(value)) {
+      throw new MaidenError(`Unimplemented: Join for ${describe(value)}`);
+    }
+    this.variables.set(name, { kind, value: joinArray(value.numeric, separator) });
+  }
+
+  joinInto(source, target, separator) {
+    const { kind, value } = this.source(source);
+    if (!isArray(value)) {
+      throw new MaidenError(`Unimplemented: Join for ${describe(value)}`);
+    }
+    this.mutated(target, kind, joinArray(value.numeric, separator));
+  }
+
+  // What a split or join into another variable works on. Ones of a variable
+  // keep that variable's scope, as in the interpreter.
+  source(source) {
+    if (source !== null && typeof source === "object" && "variable" in source) {
+      return this.lookup(this.targetName(source.variable));
+    }
+    return { kind: this.variableType(), value: source };
+  }
+
+  // Stores the result of a split or join into another variable. Both sides
+  // are resolved before the pronoun moves on to a named target.
+  mutated(target, kind, value) {
+    const name = this.targetName(target);
+    if (target !== null) {
+      this.refer(target);
+    }
+    this.variables.set(name, { kind, value });
+  }
+
+  declare(name, args, body) {
+    this.functions.set(name.toLowerCase(), { args, body });
+  }
+
+  // Runs a nested block. As with the interpreter, functions declared in it
+  // don't outlive it.
+  block(body) {
+    const functions = new Map(this.functions);
+    try {
+      return body(this);
+    } finally {
+      this.functions = functions;
+    }
+  }
+
+  // The arguments are evaluated before the call starts, so the pronoun still
+  // means what it did in the caller, as in the interpreter
+  call(name, count, args) {
+    // Variables holding functions take precedence over declared functions
+    const entry = this.variables.get(name.toLowerCase());
+    const closure =
+      entry !== undefined && entry.value !== null && typeof entry.value === "object" && "object" in entry.value
+        ? entry.value
+        : null;
+    const func = closure !== null ? closure.func : this.functions.get(name.toLowerCase());
+    if (func === undefined) {
+      throw new MaidenError(`Missing function '${name}'`);
+    }
+    if (count !== func.args.length) {
+      throw new MaidenError(
+        `Wrong argument count to function (expected ${func.args.length}, got ${count})`
+      );
+    }
+    const values = args();
+    if (this.depth === 100) {
+      throw new MaidenError(`Exceeded maximum allowed stack depth of ${this.depth}`);
+    }
+    const frame = {
+      variables: this.variables,
+      functions: this.functions,
+      pronounName: this.pronounName,
+    };
+    this.variables = new Map(this.variables);
+    this.functions = new Map(this.functions);
+    this.pronounName = null;
+    this.depth += 1;
+    this.frames.push(frame);
+    if (closure !== null) {
+      for (const [variable, value] of closure.captured) {
+        this.variables.set(variable, { kind: "local", value });
+      }
+      // So it can still call itself from outside where it was declared
+      if (!this.functions.has(closure.object.toLowerCase())) {
+        this.functions.set(closure.object.toLowerCase(), func);
+      }
+    }
+    try {
+      func.args.forEach((arg, i) => {
+        this.variables.set(arg.toLowerCase(), { kind: "local", value: values[i] });
+      });
+      const res = func.body(this);
+      // Running off the end without giving anything back
+      return res === NOTHING || res === BREAK || res === CONTINUE ? MYSTERIOUS : res;
+    } finally {
+      this.frames.pop();
+      const callee = this.variables;
+      this.variables = frame.variables;
+      for (const [name, { kind, value }] of callee) {
+        if (kind === "global") {
+          this.variables.set(name, { kind, value });
+        }
+      }
+      this.functions = frame.functions;
+      this.pronounName = frame.pronounName;
+      this.depth -= 1;
+    }
+  }
+}
@@ -0,0 +1,121 @@
+use crate::common::{Command, CommandLine, Expression, MaidenError, Program, Result, SymbolType};
+
+mod js;
+mod rust;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Rust,
+    Js,
+}
+
+const ALL_TARGETS: [Target; 2] = [Target::Rust, Target::Js];
+
+impl Target {
+    pub fn all() -> &'static [Target] {
+        &ALL_TARGETS
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::Rust => "rust",
+            Target::Js => "js",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Target> {
+        ALL_TARGETS
+            .iter()
+            .cloned()
+            .find(|target| target.name() == name)
+    }
+}
+
+fn unsupported<T>(description: String, line: usize) -> Result<T> {
+    Err(MaidenError::Unimplemented { description, line })
+}
+
+// Target of a increment/rounding, with None meaning the current pronoun
+fn alterable(expression: &Expression, line: usize) -> Result<Option<&str>> {
+    match expression {
+        Expression::Variable(name) => Ok(Some(name)),
+        Expression::Pronoun => Ok(None),
+        other => unsupported(format!("Can't alter {:?}", other), line),
+    }
+}
+
+// Variable names here are `None` for the pronoun, as with `alterable`
+enum Place<'a> {
+    InPlace(Option<&'a str>),
+    Into(&'a Expression, Option<&'a str>),
+}
+
+enum Mutation<'a> {
+    Cast(Option<&'a str>),
+    Split(Place<'a>, &'a str),
+    Join(Place<'a>, &'a str),
+}
+
+// Checks a `Command::Mutation` is one of the forms the interpreter handles
+fn mutation<'a>(
+    mutator: &SymbolType,
+    source: &'a Option<Box<Expression>>,
+    target: &'a Option<Box<Expression>>,
+    lookup: &'a Option<Box<Expression>>,
+    modifier: &'a Option<Box<Expression>>,
+    line: usize,
+) -> Result<Mutation<'a>> {
+    let place = match (
+        lookup.as_ref().map(|l| &**l),
+        source.as_ref().map(|s| &**s),
+        target.as_ref().map(|t| &**t),
+    ) {
+        (Some(lookup), None, None) => alterable(lookup, line).ok().map(Place::InPlace),
+        (None, Some(source), Some(target)) => alterable(target, line)
+            .ok()
+            .map(|target| Place::Into(source, target)),
+        _ => None,
+    };
+    let separator = match modifier.as_ref().map(|m| &**m) {
+        None => Some(""),
+        Some(Expression::String(s)) => Some(s.as_str()),
+        Some(_) => None,
+    };
+    match (mutator, place, separator) {
+        (SymbolType::Cast, Some(Place::InPlace(name)), _) if modifier.is_none() => {
+            Ok(Mutation::Cast(name))
+        }
+        (SymbolType::Split, Some(place), Some(separator)) => Ok(Mutation::Split(place, separator)),
+        (SymbolType::Join, Some(place), Some(separator)) => Ok(Mutation::Join(place, separator)),
+        _ => unsupported(
+            format!(
+                "Mutation: {:?} {:?} {:?} {:?} {:?}",
+                mutator, source, target, lookup, modifier
+            ),
+            line,
+        ),
+    }
+}
+
+/// Generates a standalone program for `target` that behaves the same as
+/// running `program` with `runner::run`
+pub fn transpile(program: &Program, target: Target) -> Result<String> {
+    let program = hoisted(program);
+    match target {
+        Target::Rust => rust::generate(&program),
+        Target::Js => js::generate(&program),
+    }
+}
+
+// The interpreter knows about top-level functions before it starts running,
+// so their declarations get moved to the start
+fn hoisted(program: &Program) -> Program {
+    let (declarations, rest): (Vec<&CommandLine>, Vec<&CommandLine>) = program
+        .commands
+        .iter()
+        .partition(|command| matches!(command.cmd, Command::FunctionDeclaration { .. }));
+    Program {
+        commands: declarations.into_iter().chain(rest).cloned().collect(),
+        functions: program.functions.clone(),
+    }
+}
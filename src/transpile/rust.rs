@@ -0,0 +1,534 @@
+use super::{alterable, mutation, unsupported, Mutation, Place};
+use crate::common::{Block, Command, CommandLine, Expression, Program, Result};
+use std::collections::HashSet;
+
+// Value type and helpers the generated code is written against
+const RUNTIME: &str = include_str!("rust_runtime.rs");
+
+const INDENT: &str = "    ";
+
+fn string(value: &str) -> String {
+    // Debug formatting of a str is also a valid Rust literal
+    format!("{:?}", value)
+}
+
+fn float(value: f64) -> String {
+    if value.is_nan() {
+        "std::f64::NAN".to_string()
+    } else if value == std::f64::INFINITY {
+        "std::f64::INFINITY".to_string()
+    } else if value == std::f64::NEG_INFINITY {
+        "std::f64::NEG_INFINITY".to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+fn option(name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("Some({})", string(name)),
+        None => "None".to_string(),
+    }
+}
+
+// Expressions the interpreter doesn't evaluate any further
+fn literal(expression: &Expression, line: usize) -> Result<String> {
+    Ok(match expression {
+        Expression::String(s) => format!("Value::String({}.to_string())", string(s)),
+        Expression::Floating(f) => format!("Value::Number({})", float(*f)),
+        Expression::True => "Value::Boolean(true)".to_string(),
+        Expression::False => "Value::Boolean(false)".to_string(),
+        Expression::Null => "Value::Null".to_string(),
+        Expression::Mysterious => "Value::Mysterious".to_string(),
+        other => return unsupported(format!("Literal {:?}", other), line),
+    })
+}
+
+// What a split or join into another variable works on
+fn split_source(expression: &Expression, line: usize) -> Result<String> {
+    Ok(match expression {
+        Expression::Variable(name) => format!("Source::Variable(Some({}))", string(name)),
+        Expression::Pronoun => "Source::Variable(None)".to_string(),
+        other => format!("Source::Value({})", literal(other, line)?),
+    })
+}
+
+fn binop(method: &str, first: String, second: String) -> String {
+    format!(
+        "{{ let a = {}; let b = {}; env.{}(a, b)? }}",
+        first, second, method
+    )
+}
+
+fn expression(expression: &Expression, line: usize) -> Result<String> {
+    let pair = |first: &Expression, second: &Expression| -> Result<(String, String)> {
+        Ok((
+            self::expression(first, line)?,
+            self::expression(second, line)?,
+        ))
+    };
+    Ok(match expression {
+        Expression::Variable(name) => format!("env.get({})?", string(name)),
+        Expression::Pronoun => "env.pronoun()?".to_string(),
+        Expression::ArrayRef { name, index } => {
+            let name = match **name {
+                Expression::Variable(ref name) => Some(name.as_str()),
+                Expression::Pronoun => None,
+                ref other => return unsupported(format!("Array named by {:?}", other), line),
+            };
+            format!(
+                "{{ let index = {}; env.index({}, index)? }}",
+                self::expression(index, line)?,
+                option(name)
+            )
+        }
+        Expression::Call(name, args) => {
+            let args = args
+                .iter()
+                .map(|arg| self::expression(arg, line))
+                .collect::<Result<Vec<_>>>()?;
+            format!(
+                "{{ let args = vec![{args}]; env.enter({name}, {count})?; env.call({name}, args)? }}",
+                name = string(name),
+                count = args.len(),
+                args = args.join(", ")
+            )
+        }
+        Expression::Not(arg) => {
+            format!("{{ let a = {}; env.not(a) }}", self::expression(arg, line)?)
+        }
+        Expression::Is(first, second) => {
+            if let Expression::Not(not_second) = &**second {
+                let (a, b) = pair(first, not_second)?;
+                binop("aint", a, b)
+            } else {
+                let (a, b) = pair(first, second)?;
+                binop("is", a, b)
+            }
+        }
+        Expression::Aint(first, second) => {
+            let (a, b) = pair(first, second)?;
+            binop("aint", a, b)
+        }
+        Expression::GreaterThan(first, second) => {
+            let (a, b) = pair(first, second)?;
+            binop("greater", a, b)
+        }
+        Expression::GreaterThanOrEqual(first, second) => {
+            let (a, b) = pair(first, second)?;
+            binop("greater_or_equal", a, b)
+        }
+        Expression::LessThan(first, second) => {
+            let (a, b) = pair(first, second)?;
+            binop("less", a, b)
+        }
+        Expression::LessThanOrEqual(first, second) => {
+            let (a, b) = pair(first, second)?;
+            binop("less_or_equal", a, b)
+        }
+        Expression::Add(first, second) => {
+            let (a, b) = pair(first, second)?;
+            binop("add", a, b)
+        }
+        Expression::Subtract(first, second) => {
+            let (a, b) = pair(first, second)?;
+            binop("subtract", a, b)
+        }
+        Expression::Times(first, second) => {
+            let (a, b) = pair(first, second)?;
+            binop("times", a, b)
+        }
+        Expression::Divide(first, second) => {
+            let (a, b) = pair(first, second)?;
+            binop("divide", a, b)
+        }
+        // The logical operators only evaluate their second argument if they have to
+        Expression::And(first, second) => {
+            let (a, b) = pair(first, second)?;
+            format!(
+                "{{ let a = {}; if a == Value::Boolean(false) {{ Value::Boolean(false) }} else {{ let b = {}; env.and(a, b)? }} }}",
+                a, b
+            )
+        }
+        Expression::Or(first, second) => {
+            let (a, b) = pair(first, second)?;
+            format!(
+                "{{ let a = {}; if a == Value::Boolean(true) {{ Value::Boolean(true) }} else {{ let b = {}; env.or(a, b)? }} }}",
+                a, b
+            )
+        }
+        Expression::Nor(first, second) => {
+            let (a, b) = pair(first, second)?;
+            format!(
+                "{{ let a = {}; if a == Value::Boolean(true) {{ Value::Boolean(false) }} else {{ let b = {}; env.nor(a, b)? }} }}",
+                a, b
+            )
+        }
+        _ => literal(expression, line)?,
+    })
+}
+
+fn push(out: &mut String, depth: usize, text: &str) {
+    out.push_str(&INDENT.repeat(depth));
+    out.push_str(text);
+    out.push('\n');
+}
+
+#[derive(Default)]
+struct Generator {
+    // Function and block bodies, each a complete Rust fn
+    items: Vec<String>,
+    names: HashSet<String>,
+    blocks: usize,
+}
+
+impl Generator {
+    fn name(&mut self, base: &str) -> String {
+        let mut name = base.to_string();
+        let mut count = 1;
+        while self.names.contains(&name) {
+            count += 1;
+            name = format!("{}_{}", base, count);
+        }
+        self.names.insert(name.clone());
+        name
+    }
+
+    fn function(&mut self, name: &str, commands: &[CommandLine]) -> Result<()> {
+        let mut out = format!("fn {}(env: &mut Env) -> Result<Flow> {{\n", name);
+        self.commands(commands, 1, &mut out)?;
+        match commands.last().map(|c| &c.cmd) {
+            Some(Command::Return { .. }) | Some(Command::Break) | Some(Command::Continue) => {}
+            _ => push(&mut out, 1, "Ok(Flow::Next)"),
+        }
+        out.push_str("}\n");
+        self.items.push(out);
+        Ok(())
+    }
+
+    fn block(&mut self, block: &Block) -> Result<String> {
+        self.blocks += 1;
+        let name = format!("block_{}", self.blocks);
+        self.function(&name, &block.commands)?;
+        Ok(name)
+    }
+
+    fn run_block(&mut self, block: &Block, depth: usize, out: &mut String) -> Result<()> {
+        let name = self.block(block)?;
+        push(out, depth, &format!("let res = env.block({})?;", name));
+        push(out, depth, "if res != Flow::Next {");
+        push(out, depth + 1, "return Ok(res);");
+        push(out, depth, "}");
+        Ok(())
+    }
+
+    // Runs a loop's block, stopping the loop on a break and the function on a return
+    fn loop_block(&mut self, block: &Block, depth: usize, out: &mut String) -> Result<()> {
+        let name = self.block(block)?;
+        push(out, depth, &format!("let res = env.block({})?;", name));
+        push(out, depth, "if res == Flow::Break {");
+        push(out, depth + 1, "break;");
+        push(out, depth, "}");
+        push(out, depth, "if let Flow::Return(_) = res {");
+        push(out, depth + 1, "return Ok(res);");
+        push(out, depth, "}");
+        Ok(())
+    }
+
+    fn commands(&mut self, commands: &[CommandLine], depth: usize, out: &mut String) -> Result<()> {
+        for command in commands {
+            self.command(command, depth, out)?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    fn command(&mut self, command: &CommandLine, depth: usize, out: &mut String) -> Result<()> {
+        let line = command.line;
+        match command.cmd {
+            Command::Assignment {
+                ref target,
+                ref value,
+            } => {
+                push(
+                    out,
+                    depth,
+                    &format!("let value = {};", expression(value, line)?),
+                );
+                let assign = match **target {
+                    Expression::Variable(ref name) => {
+                        format!("env.assign({}, value);", string(name))
+                    }
+                    Expression::Pronoun => "env.assign_pronoun(value)?;".to_string(),
+                    Expression::ArrayRef {
+                        ref name,
+                        ref index,
+                    } => match **name {
+                        Expression::Variable(ref name) => format!(
+                            "env.assign_index({}, {}, value)?;",
+                            string(name),
+                            expression(index, line)?
+                        ),
+                        ref other => {
+                            return unsupported(format!("Array named by {:?}", other), line)
+                        }
+                    },
+                    ref other => return unsupported(format!("Assign to {:?}", other), line),
+                };
+                push(out, depth, &assign);
+            }
+            Command::Increment { ref target, count } => {
+                let name = alterable(target, line)?;
+                push(
+                    out,
+                    depth,
+                    &format!(
+                        "env.alter({}, |x| x + {}, {})?;",
+                        option(name),
+                        float(count),
+                        count as usize
+                    ),
+                );
+            }
+            Command::Decrement { ref target, count } => {
+                let name = alterable(target, line)?;
+                push(
+                    out,
+                    depth,
+                    &format!(
+                        "env.alter({}, |x| x - {}, {})?;",
+                        option(name),
+                        float(count),
+                        count as usize
+                    ),
+                );
+            }
+            Command::Until {
+                ref expression,
+                ref block,
+            } => {
+                push(out, depth, "loop {");
+                push(
+                    out,
+                    depth + 1,
+                    &format!("let value = {};", self::expression(expression, line)?),
+                );
+                if let Some(subject) = expression.subject() {
+                    push(out, depth + 1, &format!("env.refer({});", string(subject)));
+                }
+                push(out, depth + 1, "if env.truthy(&value)? {");
+                push(out, depth + 2, "break;");
+                push(out, depth + 1, "}");
+                self.loop_block(block, depth + 1, out)?;
+                push(out, depth, "}");
+            }
+            Command::While {
+                ref expression,
+                ref block,
+            } => {
+                push(out, depth, "loop {");
+                push(
+                    out,
+                    depth + 1,
+                    &format!("let value = {};", self::expression(expression, line)?),
+                );
+                if let Some(subject) = expression.subject() {
+                    push(out, depth + 1, &format!("env.refer({});", string(subject)));
+                }
+                push(out, depth + 1, "if !env.truthy(&value)? {");
+                push(out, depth + 2, "break;");
+                push(out, depth + 1, "}");
+                self.loop_block(block, depth + 1, out)?;
+                push(out, depth, "}");
+            }
+            Command::Continue => push(out, depth, "return Ok(Flow::Continue);"),
+            Command::Break => push(out, depth, "return Ok(Flow::Break);"),
+            Command::Say { ref value } => {
+                push(
+                    out,
+                    depth,
+                    &format!("let value = {};", expression(value, line)?),
+                );
+                push(out, depth, "env.say(value)?;");
+            }
+            Command::FunctionDeclaration {
+                ref name,
+                ref args,
+                ref block,
+            } => {
+                let sanitised: String = name
+                    .to_lowercase()
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect();
+                let function = self.name(&format!("fn_{}", sanitised));
+                self.function(&function, &block.commands)?;
+                let args: Vec<String> = args.iter().map(|a| string(a)).collect();
+                push(
+                    out,
+                    depth,
+                    &format!(
+                        "env.declare({}, &[{}], {});",
+                        string(name),
+                        args.join(", "),
+                        function
+                    ),
+                );
+            }
+            Command::Return { ref return_value } => {
+                push(
+                    out,
+                    depth,
+                    &format!(
+                        "return Ok(Flow::Return({}));",
+                        expression(return_value, line)?
+                    ),
+                );
+            }
+            Command::If {
+                ref expression,
+                ref then,
+                ref otherwise,
+            } => {
+                push(
+                    out,
+                    depth,
+                    &format!("let value = {};", self::expression(expression, line)?),
+                );
+                if let Some(subject) = expression.subject() {
+                    push(out, depth, &format!("env.refer({});", string(subject)));
+                }
+                push(out, depth, "if env.truthy(&value)? {");
+                if let Some(block) = then {
+                    self.run_block(block, depth + 1, out)?;
+                }
+                if let Some(block) = otherwise {
+                    push(out, depth, "} else {");
+                    self.run_block(block, depth + 1, out)?;
+                }
+                push(out, depth, "}");
+            }
+            Command::Call { ref name, ref args } => {
+                let call = Expression::Call(name.clone(), args.clone());
+                push(out, depth, &format!("{};", expression(&call, line)?));
+            }
+            Command::Listen { ref target } => {
+                push(
+                    out,
+                    depth,
+                    &format!(
+                        "env.listen({})?;",
+                        option(target.as_ref().map(|t| t.as_str()))
+                    ),
+                );
+            }
+            Command::Round { ref target } => {
+                let name = alterable(target, line)?;
+                push(
+                    out,
+                    depth,
+                    &format!("env.round({}, f64::round)?;", option(name)),
+                );
+            }
+            Command::Ceil { ref target } => {
+                let name = alterable(target, line)?;
+                push(
+                    out,
+                    depth,
+                    &format!("env.round({}, f64::ceil)?;", option(name)),
+                );
+            }
+            Command::Floor { ref target } => {
+                let name = alterable(target, line)?;
+                push(
+                    out,
+                    depth,
+                    &format!("env.round({}, f64::floor)?;", option(name)),
+                );
+            }
+            Command::Mutation {
+                ref mutator,
+                ref source,
+                ref target,
+                ref lookup,
+                ref modifier,
+            } => {
+                let mutation = match mutation(mutator, source, target, lookup, modifier, line)? {
+                    Mutation::Cast(name) => format!("env.cast({})?;", option(name)),
+                    Mutation::Split(Place::InPlace(name), separator) => {
+                        format!("env.split({}, {})?;", option(name), string(separator))
+                    }
+                    Mutation::Split(Place::Into(source, target), separator) => format!(
+                        "env.split_into({}, {}, {})?;",
+                        split_source(source, line)?,
+                        option(target),
+                        string(separator)
+                    ),
+                    Mutation::Join(Place::InPlace(name), separator) => {
+                        format!("env.join({}, {})?;", option(name), string(separator))
+                    }
+                    Mutation::Join(Place::Into(source, target), separator) => format!(
+                        "env.join_into({}, {}, {})?;",
+                        split_source(source, line)?,
+                        option(target),
+                        string(separator)
+                    ),
+                };
+                push(out, depth, &mutation);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn generate(program: &Program) -> Result<String> {
+    let mut generator = Generator::default();
+    generator.function("program", &program.commands)?;
+    let mut out = String::from("// Generated by maiden from a Rockstar program\n");
+    out.push_str(RUNTIME);
+    for item in &generator.items {
+        out.push('\n');
+        out.push_str(item);
+    }
+    out.push_str(
+        "
+fn main() {
+    let mut env = Env::new();
+    let res = program(&mut env);
+    env.writer.flush().unwrap();
+    if let Err(err) = res {
+        eprintln!(\"Error: {}\", err);
+        std::process::exit(1);
+    }
+}
+",
+    );
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate;
+    use crate::parser;
+
+    fn transpile(code: &str) -> String {
+        generate(&parser::parse(code).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn functions_become_rust_functions() {
+        let out =
+            transpile("Midnight takes your heart\nGive back your heart\n\nSay Midnight taking 3\n");
+        assert!(out.contains("fn fn_midnight(env: &mut Env) -> Result<Flow> {"));
+        assert!(out.contains("env.declare(\"Midnight\", &[\"your heart\"], fn_midnight);"));
+        assert!(out.contains("env.enter(\"Midnight\", 1)?"));
+    }
+
+    #[test]
+    fn blocks_get_their_own_functions() {
+        let out = transpile("While 1 is 2\nSay \"x\"\n\nUntil 1 is 1\nSay \"y\"\n");
+        assert!(out.contains("fn block_1(env: &mut Env) -> Result<Flow> {"));
+        assert!(out.contains("let res = env.block(block_1)?;"));
+        assert!(out.contains("let res = env.block(block_2)?;"));
+    }
+}
@@ -0,0 +1,705 @@
+// Runtime prepended to everything `maiden transpile --target rust` generates.
+// This isn't a module of maiden itself; it mirrors the value handling in
+// runner.rs so the generated programs print exactly what the interpreter does.
+#![allow(warnings)]
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufWriter, Stdout, Write};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    String(String),
+    Boolean(bool),
+    Null,
+    Mysterious,
+    Array {
+        numeric: BTreeMap<usize, Value>,
+        strings: BTreeMap<String, Value>,
+    },
+    Function(Box<Closure>),
+}
+
+// What to do after running a block
+#[derive(Debug, Clone, PartialEq)]
+enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(Value),
+}
+
+// What a split or join into another variable works on. Ones of a variable
+// keep that variable's scope, as in the interpreter.
+enum Source {
+    Value(Value),
+    // `None` for the pronoun
+    Variable(Option<&'static str>),
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Number(s), Value::Number(o)) => s.partial_cmp(o),
+            (Value::String(s), Value::String(o)) => s.partial_cmp(o),
+            _ => None,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VariableType {
+    Global,
+    Local,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Add,
+    Subtract,
+    Times,
+    Divide,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Function {
+    args: Vec<&'static str>,
+    body: fn(&mut Env) -> Result<Flow>,
+}
+
+// A function used as a value, with the local variables it could see
+#[derive(Debug, Clone, PartialEq)]
+struct Closure {
+    name: String,
+    function: Function,
+    captured: HashMap<String, Value>,
+}
+
+struct Frame {
+    variables: HashMap<String, (VariableType, Value)>,
+    functions: HashMap<String, Function>,
+    pronoun: Option<String>,
+    callee: Closure,
+}
+
+struct Env {
+    writer: BufWriter<Stdout>,
+    variables: HashMap<String, (VariableType, Value)>,
+    functions: HashMap<String, Function>,
+    pronoun: Option<String>,
+    depth: u32,
+    frames: Vec<Frame>,
+}
+
+fn to_number(value: Value) -> Result<Value> {
+    match value {
+        Value::Number(_) => Ok(value),
+        Value::Null => Ok(Value::Number(0.0)),
+        Value::String(ref s) => match s.parse::<f64>() {
+            Ok(float) => Ok(Value::Number(float)),
+            Err(_) => Err(format!("Unparsable number: '{}'", s)),
+        },
+        _ => Err(format!(
+            "Unimplemented: Can't convert {:?} to number",
+            value
+        )),
+    }
+}
+
+fn to_boolean(value: &Value) -> Result<bool> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        Value::Mysterious | Value::Null => Ok(false),
+        Value::Function(_) => Ok(true),
+        Value::Number(val) => Ok(*val != 0f64),
+        Value::String(val) => Ok(!val.is_empty()),
+        _ => Err(format!("Bad boolean resolve: {:?}", value)),
+    }
+}
+
+fn split_array(to_split: &str, split_by: &str) -> Value {
+    Value::Array {
+        numeric: to_split
+            .split(split_by)
+            .filter(|x| !x.is_empty())
+            .enumerate()
+            .map(|(k, v)| (k, Value::String(v.to_string())))
+            .collect(),
+        strings: BTreeMap::new(),
+    }
+}
+
+fn join_array(to_join: &BTreeMap<usize, Value>, join_with: &str) -> Result<Value> {
+    let mut res = String::new();
+    for value in to_join.values() {
+        match value {
+            // Like the interpreter, leading empty strings don't get a separator
+            Value::String(s) if res.is_empty() => res = s.clone(),
+            Value::String(s) => res = res + join_with + s,
+            other => return Err(format!("Unimplemented: Join with non-string: {:?}", other)),
+        }
+    }
+    Ok(Value::String(res))
+}
+
+impl Env {
+    fn new() -> Env {
+        Env {
+            writer: BufWriter::new(io::stdout()),
+            variables: HashMap::new(),
+            functions: HashMap::new(),
+            pronoun: None,
+            depth: 0,
+            frames: vec![],
+        }
+    }
+
+    fn variable_type(&self) -> VariableType {
+        if self.depth == 0 {
+            VariableType::Global
+        } else {
+            VariableType::Local
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<&(VariableType, Value)> {
+        self.variables
+            .get(name)
+            .ok_or_else(|| format!("Missing variable '{}'", name))
+    }
+
+    fn pronoun_name(&self) -> Result<String> {
+        self.pronoun
+            .as_ref()
+            .map(|p| p.to_lowercase())
+            .ok_or_else(|| "Got to a pronoun, but no variable defined".to_string())
+    }
+
+    fn get(&self, name: &str) -> Result<Value> {
+        match self.variables.get(&name.to_lowercase()) {
+            Some((_, value)) => Ok(value.clone()),
+            None => match self.functions.get(&name.to_lowercase()) {
+                Some(function) => Ok(Value::Function(Box::new(Closure {
+                    name: name.to_string(),
+                    function: function.clone(),
+                    captured: self
+                        .variables
+                        .iter()
+                        .filter(|(_, (kind, _))| *kind == VariableType::Local)
+                        .map(|(name, (_, value))| (name.clone(), value.clone()))
+                        .collect(),
+                }))),
+                None => Err(format!("Missing variable '{}'", name)),
+            },
+        }
+    }
+
+    // The pronoun refers to whichever variable was last assigned or tested
+    fn refer(&mut self, name: &str) {
+        self.pronoun = Some(name.to_string());
+    }
+
+    fn pronoun(&self) -> Result<Value> {
+        Ok(self.lookup(&self.pronoun_name()?)?.1.clone())
+    }
+
+    fn assign(&mut self, name: &str, value: Value) {
+        self.refer(name);
+        let kind = match self.variables.get(&name.to_lowercase()) {
+            Some((kind, _)) => *kind,
+            None => self.variable_type(),
+        };
+        self.variables.insert(name.to_lowercase(), (kind, value));
+    }
+
+    fn assign_pronoun(&mut self, value: Value) -> Result<()> {
+        let name = self.pronoun_name()?;
+        self.assign(&name, value);
+        Ok(())
+    }
+
+    fn assign_index(&mut self, name: &str, index: Value, value: Value) -> Result<()> {
+        let kind = self.variable_type();
+        let array = self.variables.entry(name.to_string()).or_insert_with(|| {
+            (
+                kind,
+                Value::Array {
+                    numeric: BTreeMap::new(),
+                    strings: BTreeMap::new(),
+                },
+            )
+        });
+        match (&mut array.1, index) {
+            (Value::Array { numeric, .. }, Value::Number(idx)) => {
+                numeric.insert(idx as usize, value);
+            }
+            (Value::Array { strings, .. }, Value::String(idx)) => {
+                strings.insert(idx, value);
+            }
+            (Value::Array { .. }, index) => {
+                return Err(format!("Unimplemented: Index assignment with {:?}", index));
+            }
+            (_, index) => {
+                return Err(format!(
+                    "Unimplemented: Array ref assignment to non-array {} {:?}",
+                    name, index
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn index(&self, target: Option<&str>, index: Value) -> Result<Value> {
+        let array = &self.lookup(&self.target_name(target)?)?.1;
+        let entry = match (array, &index) {
+            (Value::Array { strings, .. }, Value::String(s)) => strings.get(s).cloned(),
+            (Value::Array { numeric, .. }, Value::Number(f)) => numeric.get(&(*f as usize)).cloned(),
+            (Value::String(s), Value::Number(f)) => s
+                .chars()
+                .nth(*f as usize)
+                .map(|c| Value::String(c.to_string())),
+            _ => {
+                return Err(format!(
+                    "Unimplemented: Lookup of {:?} with {:?}",
+                    array, index
+                ))
+            }
+        };
+        Ok(entry.unwrap_or(Value::Mysterious))
+    }
+
+    fn printable(&self, value: &Value) -> Result<String> {
+        match value {
+            Value::Number(x) => Ok(format!("{}", x)),
+            Value::String(s) => Ok(s.to_string()),
+            Value::Boolean(b) => Ok(format!("{}", b)),
+            Value::Array { numeric, .. } => {
+                Ok(format!("{}", numeric.keys().max().map_or(0, |x| x + 1)))
+            }
+            Value::Mysterious => Ok("mysterious".to_string()),
+            Value::Null => Ok("null".to_string()),
+            Value::Function(_) => Err(format!("Unimplemented: Say '{:?}'", value)),
+        }
+    }
+
+    fn say(&mut self, value: Value) -> Result<()> {
+        let text = self.printable(&value)?;
+        writeln!(self.writer, "{}", text).map_err(|e| e.to_string())
+    }
+
+    fn listen(&mut self, target: Option<&str>) -> Result<()> {
+        self.writer.flush().map_err(|e| e.to_string())?;
+        let mut input = String::new();
+        io::stdin()
+            .read_line(&mut input)
+            .map_err(|e| e.to_string())?;
+        if let Some(target) = target {
+            self.refer(target);
+            let kind = self.variable_type();
+            self.variables.insert(
+                target.to_lowercase(),
+                (
+                    kind,
+                    Value::String(input.trim_end_matches('\n').to_string()),
+                ),
+            );
+        }
+        Ok(())
+    }
+
+    fn compare(
+        &self,
+        first: Value,
+        second: Value,
+        f: fn(&Value, &Value) -> Result<bool>,
+    ) -> Result<bool> {
+        match first {
+            Value::Boolean(_) => match second {
+                Value::Boolean(_) => return f(&first, &second),
+                _ => {
+                    if let Ok(b) = to_boolean(&second) {
+                        return f(&first, &Value::Boolean(b));
+                    }
+                }
+            },
+            Value::String(_) => {
+                if let Value::String(_) = second {
+                    return f(&first, &second);
+                }
+                if second == Value::Null {
+                    return Ok(false);
+                }
+            }
+            Value::Mysterious => return Ok(second == Value::Mysterious),
+            _ => {}
+        }
+        if let Value::Boolean(_) = second {
+            if let Ok(b) = to_boolean(&first) {
+                return f(&Value::Boolean(b), &second);
+            }
+        }
+        let second = match to_number(second) {
+            Ok(second) => second,
+            Err(_) => return Ok(false),
+        };
+        f(&to_number(first)?, &second)
+    }
+
+    fn is(&self, first: Value, second: Value) -> Result<Value> {
+        Ok(Value::Boolean(self.compare(
+            first,
+            second,
+            |f, s| Ok(f == s),
+        )?))
+    }
+
+    fn aint(&self, first: Value, second: Value) -> Result<Value> {
+        Ok(Value::Boolean(self.compare(
+            first,
+            second,
+            |f, s| Ok(f != s),
+        )?))
+    }
+
+    fn greater(&self, first: Value, second: Value) -> Result<Value> {
+        Ok(Value::Boolean(self.compare(first, second, |f, s| Ok(f > s))?))
+    }
+
+    fn greater_or_equal(&self, first: Value, second: Value) -> Result<Value> {
+        Ok(Value::Boolean(self.compare(
+            first,
+            second,
+            |f, s| Ok(f >= s),
+        )?))
+    }
+
+    fn less(&self, first: Value, second: Value) -> Result<Value> {
+        Ok(Value::Boolean(self.compare(first, second, |f, s| Ok(f < s))?))
+    }
+
+    fn less_or_equal(&self, first: Value, second: Value) -> Result<Value> {
+        Ok(Value::Boolean(self.compare(
+            first,
+            second,
+            |f, s| Ok(f <= s),
+        )?))
+    }
+
+    // The generated code has already short-circuited on the first value
+    fn and(&self, first: Value, second: Value) -> Result<Value> {
+        let res = self.compare(first, second, |f, s| Ok(to_boolean(f)? && to_boolean(s)?))?;
+        Ok(Value::Boolean(res))
+    }
+
+    fn or(&self, first: Value, second: Value) -> Result<Value> {
+        let res = self.compare(first, second, |f, s| Ok(to_boolean(f)? || to_boolean(s)?))?;
+        Ok(Value::Boolean(res))
+    }
+
+    fn nor(&self, first: Value, second: Value) -> Result<Value> {
+        let res = self.compare(first, second, |f, s| Ok(to_boolean(f)? || to_boolean(s)?))?;
+        Ok(Value::Boolean(!res))
+    }
+
+    fn not(&self, value: Value) -> Value {
+        Value::Boolean(!to_boolean(&value).unwrap_or(true))
+    }
+
+    fn truthy(&self, value: &Value) -> Result<bool> {
+        to_boolean(value)
+    }
+
+    fn math(&self, op: Op, first: Value, second: Value) -> Result<Value> {
+        let f: fn(f64, f64) -> f64 = match op {
+            Op::Add => |f, s| f + s,
+            Op::Subtract => |f, s| f - s,
+            Op::Times => |f, s| f * s,
+            Op::Divide => |f, s| f / s,
+        };
+        match (&first, &second, op) {
+            (Value::Number(x), Value::Number(y), _) => return Ok(Value::Number(f(*x, *y))),
+            (Value::Number(x), Value::String(s), Op::Add) => {
+                return Ok(Value::String(format!("{}{}", x, s)))
+            }
+            (Value::Number(x), Value::String(s), Op::Times) => {
+                return Ok(Value::String(s.repeat(*x as usize)))
+            }
+            (Value::Number(x), Value::Null, _) => return Ok(Value::Number(f(*x, 0f64))),
+            (Value::String(x), Value::String(y), Op::Add) => {
+                return Ok(Value::String(x.clone() + y))
+            }
+            (Value::String(x), _, Op::Add) => {
+                if let Ok(y) = self.printable(&second) {
+                    return Ok(Value::String(format!("{}{}", x, y)));
+                }
+            }
+            (Value::String(x), Value::Number(y), Op::Times) => {
+                return Ok(Value::String(x.repeat(*y as usize)))
+            }
+            (Value::String(_), Value::Null, Op::Times) => return Ok(Value::String(String::new())),
+            (Value::Null, Value::Number(y), _) => return Ok(Value::Number(f(0f64, *y))),
+            (Value::Null, Value::String(s), Op::Add) => {
+                return Ok(Value::String(format!("null{}", s)))
+            }
+            (Value::Null, Value::Null, _) => return Ok(Value::Number(f(0f64, 0f64))),
+            (Value::Number(_), _, _) | (Value::String(_), _, _) | (Value::Null, _, _) => {}
+            (_, Value::String(y), Op::Add) => {
+                if let Ok(x) = self.printable(&first) {
+                    return Ok(Value::String(format!("{}{}", x, y)));
+                }
+            }
+            _ => {}
+        }
+        Err(format!(
+            "Unimplemented: Math op ({:?}) on values we can't apply: {:?} {:?}",
+            op, first, second
+        ))
+    }
+
+    fn add(&self, first: Value, second: Value) -> Result<Value> {
+        self.math(Op::Add, first, second)
+    }
+
+    fn subtract(&self, first: Value, second: Value) -> Result<Value> {
+        self.math(Op::Subtract, first, second)
+    }
+
+    fn times(&self, first: Value, second: Value) -> Result<Value> {
+        self.math(Op::Times, first, second)
+    }
+
+    fn divide(&self, first: Value, second: Value) -> Result<Value> {
+        let res = self.math(Op::Divide, first.clone(), second.clone())?;
+        if res == Value::Number(std::f64::INFINITY) {
+            return Err(format!(
+                "Got infinity on divide between {:?} and {:?}",
+                first, second
+            ));
+        }
+        Ok(res)
+    }
+
+    fn target_name(&self, target: Option<&str>) -> Result<String> {
+        match target {
+            Some(name) => Ok(name.to_lowercase()),
+            None => self.pronoun_name(),
+        }
+    }
+
+    fn alter(&mut self, target: Option<&str>, f: fn(f64) -> f64, count: usize) -> Result<()> {
+        if let Some(name) = target {
+            self.refer(name);
+        }
+        let name = self.target_name(target)?;
+        let (kind, value) = self.lookup(&name)?.clone();
+        let value = match value {
+            Value::Number(x) => Value::Number(f(x)),
+            Value::Null => Value::Number(f(0f64)),
+            // double-flips do nothing, so just look at the low bit
+            Value::Boolean(b) if count & 0x1 == 1 => Value::Boolean(!b),
+            Value::Boolean(_) => return Ok(()),
+            _ => {
+                return Err(format!(
+                    "Unimplemented: Attempt to alter non-integer '{}'",
+                    name
+                ))
+            }
+        };
+        self.variables.insert(name, (kind, value));
+        Ok(())
+    }
+
+    fn round(&mut self, target: Option<&str>, f: fn(f64) -> f64) -> Result<()> {
+        if let Some(name) = target {
+            self.refer(name);
+        }
+        let name = self.target_name(target)?;
+        let (kind, value) = self.lookup(&name)?.clone();
+        let value = match value {
+            Value::Number(x) => Value::Number(f(x)),
+            Value::Null => Value::Number(f(0f64)),
+            _ => {
+                return Err(format!(
+                    "Unimplemented: Attempt to alter non-integer '{}'",
+                    name
+                ))
+            }
+        };
+        self.variables.insert(name, (kind, value));
+        Ok(())
+    }
+
+    fn cast(&mut self, target: Option<&str>) -> Result<()> {
+        let name = self.target_name(target)?;
+        self.refer(&name);
+        let (kind, value) = self.lookup(&name)?.clone();
+        let value = match value {
+            Value::String(s) => {
+                let mut chars = s.chars();
+                match (f64::from_str(&s), chars.next(), chars.next()) {
+                    (Ok(val), _, _) => Value::Number(val),
+                    (Err(_), Some(c), None) => Value::Number(f64::from(c as u32)),
+                    (Err(_), _, _) => return Err(format!("Unparsable number: '{}'", s)),
+                }
+            }
+            Value::Number(f) => match std::char::from_u32(f as u32) {
+                Some(c) => Value::String(c.to_string()),
+                None => return Err(format!("Unimplemented: Cast for {}", f)),
+            },
+            other => return Err(format!("Unimplemented: Cast for {:?}", other)),
+        };
+        self.variables.insert(name, (kind, value));
+        Ok(())
+    }
+
+    fn split(&mut self, target: Option<&str>, split_by: &str) -> Result<()> {
+        let name = self.target_name(target)?;
+        self.refer(&name);
+        match self.lookup(&name)?.clone() {
+            (kind, Value::String(s)) => {
+                self.variables
+                    .insert(name, (kind, split_array(&s, split_by)));
+                Ok(())
+            }
+            var => Err(format!("Unimplemented: Split for {:?}", var)),
+        }
+    }
+
+    fn split_into(&mut self, source: Source, target: Option<&str>, split_by: &str) -> Result<()> {
+        let (kind, value) = match self.source(source)? {
+            (kind, Value::String(s)) => (kind, split_array(&s, split_by)),
+            (_, other) => return Err(format!("Unimplemented: Split for {:?}", other)),
+        };
+        self.mutated(target, kind, value)
+    }
+
+    fn join(&mut self, target: Option<&str>, join_with: &str) -> Result<()> {
+        let name = self.target_name(target)?;
+        self.refer(&name);
+        match self.lookup(&name)?.clone() {
+            (kind, Value::Array { numeric, .. }) => {
+                let value = join_array(&numeric, join_with)?;
+                self.variables.insert(name, (kind, value));
+                Ok(())
+            }
+            var => Err(format!("Unimplemented: Join for {:?}", var)),
+        }
+    }
+
+    fn join_into(&mut self, source: Source, target: Option<&str>, join_with: &str) -> Result<()> {
+        let (kind, value) = match self.source(source)? {
+            (kind, Value::Array { numeric, .. }) => (kind, join_array(&numeric, join_with)?),
+            (_, other) => return Err(format!("Unimplemented: Join for {:?}", other)),
+        };
+        self.mutated(target, kind, value)
+    }
+
+    fn source(&self, source: Source) -> Result<(VariableType, Value)> {
+        match source {
+            Source::Value(value) => Ok((self.variable_type(), value)),
+            Source::Variable(src) => Ok(self.lookup(&self.target_name(src)?)?.clone()),
+        }
+    }
+
+    // Stores the result of a split or join into another variable. Both sides
+    // are resolved before the pronoun moves on to a named target.
+    fn mutated(&mut self, target: Option<&str>, kind: VariableType, value: Value) -> Result<()> {
+        let name = self.target_name(target)?;
+        if let Some(name) = target {
+            self.refer(name);
+        }
+        self.variables.insert(name, (kind, value));
+        Ok(())
+    }
+
+    fn declare(&mut self, name: &str, args: &[&'static str], body: fn(&mut Env) -> Result<Flow>) {
+        let function = Function {
+            args: args.to_vec(),
+            body,
+        };
+        self.functions.insert(name.to_lowercase(), function);
+    }
+
+    // Runs a nested block. As with the interpreter, functions declared in it
+    // don't outlive it.
+    fn block(&mut self, body: fn(&mut Env) -> Result<Flow>) -> Result<Flow> {
+        let functions = self.functions.clone();
+        let res = body(self);
+        self.functions = functions;
+        res
+    }
+
+    // Sets up a function call. The arguments are evaluated before this, so
+    // the pronoun still means what it did in the caller, as in the interpreter.
+    fn enter(&mut self, name: &str, args: usize) -> Result<()> {
+        // Variables holding functions take precedence over declared functions
+        let callee = match self.variables.get(&name.to_lowercase()) {
+            Some((_, Value::Function(closure))) => (**closure).clone(),
+            _ => match self.functions.get(&name.to_lowercase()) {
+                Some(function) => Closure {
+                    name: name.to_string(),
+                    function: function.clone(),
+                    captured: HashMap::new(),
+                },
+                None => return Err(format!("Missing function '{}'", name)),
+            },
+        };
+        let function = &callee.function;
+        if args != function.args.len() {
+            return Err(format!(
+                "Wrong argument count to function (expected {}, got {})",
+                function.args.len(),
+                args
+            ));
+        }
+        if self.depth == 100 {
+            return Err(format!(
+                "Exceeded maximum allowed stack depth of {}",
+                self.depth
+            ));
+        }
+        self.frames.push(Frame {
+            variables: self.variables.clone(),
+            functions: self.functions.clone(),
+            pronoun: self.pronoun.take(),
+            callee: callee.clone(),
+        });
+        for (name, value) in callee.captured {
+            self.variables.insert(name, (VariableType::Local, value));
+        }
+        // So it can still call itself from outside where it was declared
+        self.functions
+            .entry(callee.name.to_lowercase())
+            .or_insert(callee.function);
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value> {
+        let function = self.frames.last().unwrap().callee.function.clone();
+        for (arg, value) in function.args.iter().zip(args) {
+            self.variables
+                .insert(arg.to_lowercase(), (VariableType::Local, value));
+        }
+        let res = (function.body)(self);
+        let frame = self.frames.pop().unwrap();
+        let callee = std::mem::replace(&mut self.variables, frame.variables);
+        for (name, (kind, value)) in callee {
+            if kind == VariableType::Global {
+                self.variables.insert(name, (VariableType::Global, value));
+            }
+        }
+        self.functions = frame.functions;
+        self.pronoun = frame.pronoun;
+        self.depth -= 1;
+        res.map(|flow| match flow {
+            Flow::Return(value) => value,
+            // Running off the end without giving anything back
+            _ => Value::Mysterious,
+        })
+    }
+}
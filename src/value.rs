@@ -0,0 +1,263 @@
+use crate::common::{Expression, Function, MaidenError};
+use crate::parser;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+
+/// What an expression works out to when a program is run
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Number(f64),
+    String(String),
+    Boolean(bool),
+    Null,
+    Mysterious,
+    Array {
+        numeric: BTreeMap<usize, Value>,
+        strings: BTreeMap<String, Value>,
+    },
+    Function(Box<Closure>),
+}
+
+/// A function used as a value, along with the local variables that were in
+/// scope where it was taken, so it can still see them when called elsewhere
+#[derive(Debug, PartialEq, Clone)]
+pub struct Closure {
+    pub name: String,
+    pub function: Function,
+    pub captured: HashMap<String, Value>,
+}
+
+impl Value {
+    /// The value of a literal, or None if `expression` needs running to get one
+    pub fn literal(expression: &Expression) -> Option<Value> {
+        match expression {
+            Expression::Floating(x) => Some(Value::Number(*x)),
+            Expression::String(s) => Some(Value::String(s.clone())),
+            Expression::True => Some(Value::Boolean(true)),
+            Expression::False => Some(Value::Boolean(false)),
+            Expression::Null => Some(Value::Null),
+            Expression::Mysterious => Some(Value::Mysterious),
+            _ => None,
+        }
+    }
+
+    /// Whether the value counts as true in a condition. Arrays don't count as either.
+    pub fn to_boolean(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(b) => Some(*b),
+            Value::Number(x) => Some(*x != 0f64),
+            Value::String(s) => Some(!s.is_empty()),
+            Value::Null | Value::Mysterious => Some(false),
+            Value::Function(_) => Some(true),
+            Value::Array { .. } => None,
+        }
+    }
+
+    /// The value as a number, with null as 0 and strings parsed
+    pub fn to_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(x) => Some(*x),
+            Value::Null => Some(0f64),
+            Value::String(s) => s.parse::<f64>().ok(),
+            _ => None,
+        }
+    }
+
+    /// Name of the kind of value, for error messages
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Boolean(_) => "boolean",
+            Value::Null => "null",
+            Value::Mysterious => "mysterious",
+            Value::Array { .. } => "array",
+            Value::Function(_) => "function",
+        }
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Number(s), Value::Number(o)) => s.partial_cmp(o),
+            (Value::String(s), Value::String(o)) => s.partial_cmp(o),
+            _ => None,
+        }
+    }
+}
+
+/// What `Say` prints. Arrays print their length.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Number(x) => write!(f, "{}", x),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Null => write!(f, "null"),
+            Value::Mysterious => write!(f, "mysterious"),
+            Value::Array { numeric, .. } => {
+                write!(f, "{}", numeric.keys().max().map_or(0, |x| x + 1))
+            }
+            Value::Function(closure) => write!(f, "function {}", closure.name),
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(x: f64) -> Value {
+        Value::Number(x)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Boolean(b)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Value {
+        Value::Array {
+            numeric: values.into_iter().enumerate().collect(),
+            strings: BTreeMap::new(),
+        }
+    }
+}
+
+/// Error for getting `value` where a different kind of value was wanted
+pub fn wrong_type(expected: &str, value: &Value) -> MaidenError {
+    MaidenError::WrongType {
+        expected: expected.to_string(),
+        got: value.kind().to_string(),
+        line: 0,
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = MaidenError;
+
+    fn try_from(value: Value) -> Result<f64, MaidenError> {
+        match value {
+            Value::Number(x) => Ok(x),
+            other => Err(wrong_type("number", &other)),
+        }
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = MaidenError;
+
+    fn try_from(value: Value) -> Result<bool, MaidenError> {
+        match value {
+            Value::Boolean(b) => Ok(b),
+            other => Err(wrong_type("boolean", &other)),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = MaidenError;
+
+    fn try_from(value: Value) -> Result<String, MaidenError> {
+        match value {
+            Value::String(s) => Ok(s),
+            other => Err(wrong_type("string", &other)),
+        }
+    }
+}
+
+/// The numbered entries of an array, in order
+impl TryFrom<Value> for Vec<Value> {
+    type Error = MaidenError;
+
+    fn try_from(value: Value) -> Result<Vec<Value>, MaidenError> {
+        match value {
+            Value::Array { numeric, .. } => Ok(numeric.into_values().collect()),
+            other => Err(wrong_type("array", &other)),
+        }
+    }
+}
+
+/// `value` as it'd be written in a program, with arrays listed out entry by entry
+pub fn describe(value: &Value) -> String {
+    match value {
+        Value::String(s) => parser::escape(s),
+        Value::Array { numeric, strings } => {
+            let entries: Vec<String> =
+                numeric
+                    .iter()
+                    .map(|(index, value)| format!("{}: {}", index, describe(value)))
+                    .chain(strings.iter().map(|(key, value)| {
+                        format!("{}: {}", parser::escape(key), describe(value))
+                    }))
+                    .collect();
+            format!("[{}]", entries.join(", "))
+        }
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Value;
+    use crate::common::MaidenError;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn coercions() {
+        assert_eq!(Value::from("").to_boolean(), Some(false));
+        assert_eq!(Value::from("no").to_boolean(), Some(true));
+        assert_eq!(Value::from(0.0).to_boolean(), Some(false));
+        assert_eq!(Value::Mysterious.to_boolean(), Some(false));
+        assert_eq!(Value::from(vec![]).to_boolean(), None);
+        assert_eq!(Value::from("4.5").to_number(), Some(4.5));
+        assert_eq!(Value::Null.to_number(), Some(0.0));
+        assert_eq!(Value::from("four").to_number(), None);
+        assert_eq!(Value::from(true).to_number(), None);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Value::from(3.0).to_string(), "3");
+        assert_eq!(Value::from(0.5).to_string(), "0.5");
+        assert_eq!(Value::from("hi").to_string(), "hi");
+        assert_eq!(Value::from(false).to_string(), "false");
+        assert_eq!(Value::Null.to_string(), "null");
+        let list = Value::from(vec![Value::Null, Value::from(1.0)]);
+        assert_eq!(list.to_string(), "2");
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(f64::try_from(Value::from(2.0)).unwrap(), 2.0);
+        assert_eq!(String::try_from(Value::from("hi")).unwrap(), "hi");
+        assert!(bool::try_from(Value::from(true)).unwrap());
+        let list = vec![Value::from("a"), Value::from("b")];
+        assert_eq!(
+            Vec::<Value>::try_from(Value::from(list.clone())).unwrap(),
+            list
+        );
+        match f64::try_from(Value::from("2")) {
+            Err(MaidenError::WrongType { expected, got, .. }) => {
+                assert_eq!(expected, "number");
+                assert_eq!(got, "string");
+            }
+            other => panic!("Expected a WrongType error, got {:?}", other),
+        }
+    }
+}
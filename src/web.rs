@@ -3,6 +3,7 @@ use crate::display;
 use crate::parser;
 use crate::runner;
 use std;
+use std::io;
 use stdweb::js;
 use yew::html;
 use yew::prelude::*;
@@ -32,7 +33,7 @@ impl Model {
     }
 
     fn nicer_error(&self, err: &MaidenError) -> String {
-        let line = get_error_line(err);
+        let line = err.line();
         if line == 0 {
             format!("{}", err)
         } else {
@@ -69,7 +70,7 @@ impl Model {
                 self.program = display::print_program(&val);
                 self.parse_error = false;
                 let mut writer = std::io::Cursor::new(Vec::new());
-                let res = runner::run(&mut val, &mut writer);
+                let res = runner::run(&mut val, &mut io::empty(), &mut writer);
                 self.res = "".into();
                 if let Err(err) = res {
                     self.res += &self.nicer_error(&err);
@@ -185,26 +186,3 @@ impl Renderable<Model> for Model {
         }
     }
 }
-
-fn get_error_line(e: &MaidenError) -> usize {
-    match e {
-        MaidenError::MissingVariable { ref line, .. }
-        | MaidenError::MissingFunction { ref line, .. }
-        | MaidenError::WrongArgCount { ref line, .. }
-        | MaidenError::ParseNumberError { ref line, .. }
-        | MaidenError::NoEndOfIf { ref line }
-        | MaidenError::BadBooleanResolve { ref line, .. }
-        | MaidenError::Unimplemented { ref line, .. }
-        | MaidenError::StackOverflow { ref line, .. }
-        | MaidenError::InstructionLimit { ref line }
-        | MaidenError::UndefinedPronoun { ref line }
-        | MaidenError::Infinity { ref line, .. }
-        | MaidenError::Incomplete { ref line, .. }
-        | MaidenError::NotAnExpression { ref line, .. }
-        | MaidenError::NotASymbol { ref line, .. }
-        | MaidenError::NotACommand { ref line, .. }
-        | MaidenError::NotABlock { ref line, .. }
-        | MaidenError::BadString { ref line, .. } => *line,
-        MaidenError::Pest { .. } | MaidenError::Io { .. } => 0,
-    }
-}
@@ -1,70 +1,225 @@
 #![allow(warnings)]
 extern crate assert_cmd;
 
-#[cfg(test)]
-mod integration {
-    use assert_cmd::prelude::*;
-    use log::warn;
-    use pretty_assertions::assert_eq;
-    use std;
-    use std::fs::File;
-    use std::io::{ErrorKind, Read};
-    use std::path::Path;
-    use std::process::Command;
+use assert_cmd::prelude::*;
+use libtest_mimic::{run_tests, Arguments, Outcome, Test};
+use log::warn;
+use maiden::transpile::{self, Target};
+use maiden::{golden, parser};
+use pretty_assertions::assert_eq;
+use std;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-    include!(concat!(env!("OUT_DIR"), "/test.rs"));
+#[derive(Clone, Copy, PartialEq)]
+enum Check {
+    Run,
+    Cli,
+    ParseFail,
+    TranspileRust,
+    TranspileJs,
+}
+
+struct Fixture {
+    name: String,
+    check: Check,
+    // Why it's in tests/skip.toml, if it is
+    skipped: Option<String>,
+}
 
-    fn file_or_empty(fname_raw: String) -> String {
-        let fname = Path::new(&fname_raw);
-        if fname.exists() {
-            let mut f = File::open(&fname).expect(&format!("Trying to open '{}'", fname.display()));
-            let mut expected = String::new();
-            f.read_to_string(&mut expected).unwrap();
-            expected
+fn fixtures() -> Vec<Test<Fixture>> {
+    let root = Path::new("tests");
+    let skips = golden::skip_list(&root.join("skip.toml")).unwrap();
+    let mut tests = Vec::new();
+    for path in golden::find_fixtures(root) {
+        let name = path
+            .strip_prefix(root)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        let skipped = skips.get(&name).cloned();
+        let checks = if name.contains("failures") {
+            vec![(Check::ParseFail, "")]
         } else {
-            String::new()
+            vec![
+                (Check::Run, ""),
+                (Check::Cli, "cli"),
+                (Check::TranspileRust, "rust"),
+                (Check::TranspileJs, "js"),
+            ]
+        };
+        for (check, kind) in checks {
+            tests.push(Test {
+                name: name.clone(),
+                kind: match (&skipped, kind) {
+                    (Some(_), "") => "expected-fail".to_string(),
+                    (Some(_), kind) => format!("{}, expected-fail", kind),
+                    (None, kind) => kind.to_string(),
+                },
+                // Not worth running the binary or transpiling programs that don't run properly yet
+                is_ignored: skipped.is_some() && check != Check::Run && check != Check::ParseFail,
+                is_bench: false,
+                data: Fixture {
+                    name: name.clone(),
+                    check,
+                    skipped: skipped.clone(),
+                },
+            });
         }
     }
+    tests
+}
 
-    fn success_file_test(name: &str) {
-        let expected_out = file_or_empty(format!("./tests/{}.out", name));
-        let expected_err = file_or_empty(format!("./tests/{}.err", name));
-
-        if !expected_err.is_empty() {
-            warn!("Don't yet support non-zero errors, because of differences in implementation");
-            return;
+// Runs `check`, turning any panic into an error with its message
+fn caught(check: impl FnOnce() + panic::UnwindSafe) -> Result<(), String> {
+    panic::catch_unwind(check).map_err(|payload| {
+        if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else {
+            "panicked".to_string()
         }
+    })
+}
+
+fn run_fixture(fixture: &Fixture) -> Outcome {
+    let name = fixture.name.as_str();
+    let path = Path::new("tests").join(name);
+    let result = match fixture.check {
+        Check::Run => golden::run_fixture(&path, false),
+        Check::ParseFail => golden::run_fixture(&path, true),
+        Check::Cli => caught(|| cli_file_test(name)),
+        Check::TranspileRust => caught(|| transpile_rust_file_test(name)),
+        Check::TranspileJs => caught(|| transpile_js_file_test(name)),
+    };
+    match (result.err(), &fixture.skipped) {
+        (None, None) | (Some(_), Some(_)) => Outcome::Passed,
+        (Some(message), None) => Outcome::Failed { msg: Some(message) },
+        (None, Some(reason)) => Outcome::Failed {
+            msg: Some(format!(
+                "passes now, so take it out of tests/skip.toml (skipped as \"{}\")",
+                reason
+            )),
+        },
+    }
+}
 
-        let args = [&format!("./tests/{}", name)];
-        let mut mb = Command::main_binary().unwrap();
-        let input_buffer = file_or_empty(format!("./tests/{}.in", name));
+fn main() {
+    let args = Arguments::from_args();
+    // Failures get reported by run_tests, rather than as they happen
+    panic::set_hook(Box::new(|_| {}));
+    run_tests(&args, fixtures(), |test| run_fixture(&test.data)).exit();
+}
 
-        let output = mb
-            .args(&args)
-            .with_stdin()
-            .buffer(input_buffer)
-            .output()
-            .unwrap();
-        let stderr = std::str::from_utf8(&output.stderr).unwrap();
-        // assert_eq!(stderr, expected_err);
-        let stdout = std::str::from_utf8(&output.stdout).unwrap();
-        assert_eq!(
-            stdout, expected_out,
-            "stdout: {}\n\nstderr: {}",
-            stdout, stderr
-        );
+fn file_or_empty(fname_raw: String) -> String {
+    let fname = Path::new(&fname_raw);
+    if fname.exists() {
+        let mut f = File::open(&fname).expect(&format!("Trying to open '{}'", fname.display()));
+        let mut expected = String::new();
+        f.read_to_string(&mut expected).unwrap();
+        expected
+    } else {
+        String::new()
     }
+}
 
-    fn parse_fail_file_test(name: &str) {
-        let args = [&format!("./tests/{}", name)];
-        let mut mb = Command::main_binary().unwrap();
-        let output = mb.args(&args).output().unwrap();
-        assert!(!output.status.success());
+// Runs the maiden binary itself, which should exit with 1 if the program errors
+fn cli_file_test(name: &str) {
+    let expected_out = file_or_empty(format!("./tests/{}.out", name));
+    let expected_err = file_or_empty(format!("./tests/{}.err", name));
+    let input_buffer = file_or_empty(format!("./tests/{}.in", name));
+    let output = Command::main_binary()
+        .unwrap()
+        .arg(format!("./tests/{}", name))
+        .with_stdin()
+        .buffer(input_buffer)
+        .output()
+        .unwrap();
+    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    if expected_err.is_empty() {
+        assert!(output.status.success(), "stderr: {}", stderr);
+    } else {
+        assert_eq!(output.status.code(), Some(1), "stderr: {}", stderr);
+    }
+    let stdout = std::str::from_utf8(&output.stdout).unwrap();
+    assert_eq!(stdout, expected_out, "stderr: {}", stderr);
+}
+
+// Writes out the transpiled program, returning None if it's a test we skip
+fn transpile_file(name: &str, target: &str, extension: &str) -> Option<PathBuf> {
+    let expected_err = file_or_empty(format!("./tests/{}.err", name));
+    if !expected_err.is_empty() {
+        warn!("Transpiled programs report errors differently, so not checking this one");
+        return None;
+    }
+
+    let source = file_or_empty(format!("./tests/{}", name));
+    let program = parser::parse(&source).unwrap();
+    let output = transpile::transpile(&program, Target::from_name(target).unwrap()).unwrap();
+
+    let dir = std::env::temp_dir().join("maiden-transpile");
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = dir
+        .join(name.replace("/", "_").replace(".", "_"))
+        .with_extension(extension);
+    std::fs::write(&source, output).unwrap();
+    Some(source)
+}
+
+fn run_transpiled(name: &str, mut command: Command) {
+    let expected_out = file_or_empty(format!("./tests/{}.out", name));
+    let input_buffer = file_or_empty(format!("./tests/{}.in", name));
+    let output = command.with_stdin().buffer(input_buffer).output().unwrap();
+    let stdout = std::str::from_utf8(&output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        expected_out,
+        "stdout: {}\n\nstderr: {}",
+        stdout,
+        std::str::from_utf8(&output.stderr).unwrap()
+    );
+}
 
-        let stderr = std::str::from_utf8(&output.stderr).unwrap();
-        assert!(stderr.contains("Error:"), stderr.to_string());
+fn transpile_rust_file_test(name: &str) {
+    let source = match transpile_file(name, "rust", "rs") {
+        Some(source) => source,
+        None => return,
+    };
+    let binary = source.with_extension("");
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let compiled = Command::new(rustc)
+        .arg("--edition=2018")
+        .arg("-o")
+        .arg(&binary)
+        .arg(&source)
+        .output()
+        .unwrap();
+    assert!(
+        compiled.status.success(),
+        "{}",
+        std::str::from_utf8(&compiled.stderr).unwrap()
+    );
+    run_transpiled(name, Command::new(&binary));
+}
 
-        let stdout = std::str::from_utf8(&output.stdout).unwrap();
-        assert_eq!(stdout, "");
+fn transpile_js_file_test(name: &str) {
+    let node = std::env::var("NODE").unwrap_or_else(|_| "node".to_string());
+    match Command::new(&node).arg("--version").output() {
+        Ok(ref output) if output.status.success() => {}
+        _ => {
+            warn!("No JavaScript engine found, so not checking transpiled output");
+            return;
+        }
     }
+    let source = match transpile_file(name, "js", "js") {
+        Some(source) => source,
+        None => return,
+    };
+    let mut command = Command::new(node);
+    command.arg(&source);
+    run_transpiled(name, command);
 }